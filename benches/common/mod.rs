@@ -0,0 +1,102 @@
+//! Grammars and timing shared by the benchmarks.
+use std::fs;
+use std::time::{Duration, Instant};
+use story_gen::grammar::Grammar;
+
+/// The name and text of every bundled example grammar.
+pub fn examples() -> Vec<(String, String)> {
+    let mut paths: Vec<_> = fs::read_dir("examples")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let text = fs::read_to_string(&path).unwrap();
+            (path.display().to_string(), text)
+        })
+        .collect()
+}
+
+/// The synthetic grammars: many rules, a long word list and a deep chain
+/// of non-terminals.
+pub fn synthetic() -> Vec<(String, String)> {
+    vec![
+        (String::from("synthetic: 10k rules"), many_rules(5, 2_000)),
+        (String::from("synthetic: 100k words"), word_list(100_000)),
+        (String::from("synthetic: depth 1000"), deep_recursion(1_000)),
+    ]
+}
+
+/// 'levels' layers of 'width' rules. Every rule has a dozen options mixing
+/// words and rules of the next layer, and the last layer only has words.
+fn many_rules(levels: usize, width: usize) -> String {
+    let mut text = String::from("<s> = ");
+    let starts: Vec<String> = (0..width).map(|i| format!("<n0_{}>", i)).collect();
+    text.push_str(&starts.join(" | "));
+    for level in 0..levels {
+        for i in 0..width {
+            let options: Vec<String> = (0..12)
+                .map(|option| match level + 1 < levels {
+                    true => format!(
+                        "word{} <n{}_{}> and <n{}_{}> again",
+                        option,
+                        level + 1,
+                        (i + option) % width,
+                        level + 1,
+                        (i * 7 + option) % width
+                    ),
+                    false => format!("leaf{} word{}", i, option),
+                })
+                .collect();
+            text.push_str(&format!("\n<n{}_{}> = {}", level, i, options.join(" | ")));
+        }
+    }
+    text
+}
+
+/// A sentence picking from a single rule with 'words' options.
+fn word_list(words: usize) -> String {
+    let options: Vec<String> = (0..words).map(|i| format!("word{}", i)).collect();
+    format!(
+        "<s> = the <adj> <noun> ran\n<adj> = big | small\n<noun> = {}",
+        options.join(" | ")
+    )
+}
+
+/// A chain of 'depth' rules, each expanding the next one.
+fn deep_recursion(depth: usize) -> String {
+    let mut text = String::from("<s> = <n0>");
+    for i in 0..depth {
+        text.push_str(&format!("\n<n{}> = a <n{}> | b <n{}> c", i, i + 1, i + 1));
+    }
+    text.push_str(&format!("\n<n{}> = end", depth));
+    text
+}
+
+/// A grammar with the rules of 'text', starting from its first rule.
+pub fn load(text: &str) -> Grammar {
+    let rules = story_gen::try_parse_file(text).unwrap();
+    let mut grammar = Grammar::new();
+    grammar.change_start_nonterminal(&rules[0].left_hand);
+    for rule in rules {
+        grammar.rule_add_from_file(rule);
+    }
+    grammar.instantiate_templates().unwrap();
+    grammar
+}
+
+/// Runs 'f' 'iterations' times and prints the average time of each of the
+/// 'items' handled by a run, such as the sentences generated.
+pub fn measure<F: FnMut()>(name: &str, task: &str, iterations: u32, items: u32, mut f: F) {
+    // the first run fills caches, such as the compiled rules
+    f();
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let average: Duration = start.elapsed() / iterations / items;
+    println!("{:<28} {:<10} {:>12?}", name, task, average);
+}
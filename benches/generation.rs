@@ -0,0 +1,20 @@
+//! Measures how fast sentences are generated from the bundled examples and
+//! from large synthetic grammars. Run with `cargo bench --bench generation`.
+mod common;
+
+use story_gen::random;
+
+/// Number of sentences generated in every run.
+const SENTENCES: usize = 1_000;
+
+fn main() {
+    for (name, text) in common::examples().into_iter().chain(common::synthetic()) {
+        let grammar = common::load(&text);
+        let key = grammar.start_nonterminal.clone();
+        let mut rng = random::seeded_rng(1);
+        common::measure(&name, "sentence", 20, SENTENCES as u32, || {
+            let sentences = grammar.generate_sentences_with_rng(&key, SENTENCES, &mut rng);
+            assert_eq!(sentences.len(), SENTENCES);
+        });
+    }
+}
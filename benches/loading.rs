@@ -0,0 +1,20 @@
+//! Measures how fast grammars are parsed and validated, for the bundled
+//! examples and for large synthetic grammars. Run with
+//! `cargo bench --bench loading`.
+mod common;
+
+fn main() {
+    for (name, text) in common::examples().into_iter().chain(common::synthetic()) {
+        let iterations = match text.len() > 100_000 {
+            true => 10,
+            false => 2_000,
+        };
+        common::measure(&name, "parse", iterations, 1, || {
+            story_gen::try_parse_file(&text).unwrap();
+        });
+        let grammar = common::load(&text);
+        common::measure(&name, "validate", iterations, 1, || {
+            grammar.validate().unwrap();
+        });
+    }
+}
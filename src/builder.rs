@@ -0,0 +1,546 @@
+//! This module contains a typed builder for grammars, as an alternative to
+//! writing rules in the grammar file syntax. Terminals and non-terminals are
+//! separate types, so a word can't be mistaken for a non-terminal or for
+//! the syntax of a rule, and the grammar is validated when it is built.
+use crate::grammar::{Grammar, MAX_WEIGHT};
+use crate::token::{self, Token};
+use crate::{Combinations, Rule};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// One or more words that are output as they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Terminal(String);
+
+impl Terminal {
+    pub fn new(text: &str) -> Terminal {
+        Terminal(String::from(text))
+    }
+
+    pub fn text(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A non-terminal, which must have a rule in the grammar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonTerminal(String);
+
+impl NonTerminal {
+    /// The non-terminal called 'name', such as `<noun>`.
+    pub fn new(name: &str) -> NonTerminal {
+        NonTerminal(String::from(name))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Symbol {
+    Terminal(Terminal),
+    NonTerminal(NonTerminal),
+}
+
+impl From<Terminal> for Symbol {
+    fn from(terminal: Terminal) -> Self {
+        Symbol::Terminal(terminal)
+    }
+}
+
+impl From<NonTerminal> for Symbol {
+    fn from(non_terminal: NonTerminal) -> Self {
+        Symbol::NonTerminal(non_terminal)
+    }
+}
+
+/// One option of a rule: a sequence of symbols, some of which may be left
+/// out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Production {
+    /// The symbols, and whether each one is optional.
+    items: Vec<(Symbol, bool)>,
+    weight: u32,
+}
+
+impl Default for Production {
+    fn default() -> Self {
+        Production::new()
+    }
+}
+
+impl Production {
+    /// An empty production with a weight of 1.
+    pub fn new() -> Production {
+        Production {
+            items: vec![],
+            weight: 1,
+        }
+    }
+
+    /// Appends 'symbol'.
+    pub fn then<S: Into<Symbol>>(mut self, symbol: S) -> Self {
+        self.items.push((symbol.into(), false));
+        self
+    }
+
+    /// Appends 'symbol', which is left out of half of the options. Like
+    /// `(<adj>)` in a grammar file, every combination of the optional
+    /// symbols becomes an option of the rule.
+    pub fn optional<S: Into<Symbol>>(mut self, symbol: S) -> Self {
+        self.items.push((symbol.into(), true));
+        self
+    }
+
+    /// How likely the production is compared to a production with a weight
+    /// of 1, up to MAX_WEIGHT. The weight is split evenly between the
+    /// options made by the optional symbols of the production, see
+    /// `Grammar::set_weight`, and an option made by several productions gets
+    /// the sum of their shares.
+    pub fn weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+}
+
+/// Why a grammar could not be built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// The name of a non-terminal is empty, contains whitespace or is
+    /// special syntax, such as `$name`.
+    InvalidNonTerminal(String),
+    /// A word of a terminal is special syntax, such as `~`, `$name` or
+    /// `[name:value]`, or contains `|`.
+    InvalidTerminal(String),
+    /// A terminal has the name of a non-terminal, so it would be expanded.
+    TerminalIsNonTerminal(String),
+    /// A non-terminal is used without a rule.
+    UndefinedNonTerminal(String),
+    /// A rule without productions.
+    EmptyRule(String),
+    /// A production with a weight of 0, in the rule of the non-terminal.
+    ZeroWeight(String),
+    /// A production of the non-terminal has a weight above MAX_WEIGHT, or
+    /// an option does once the weights of productions with optional symbols
+    /// are split between their options in whole numbers.
+    WeightTooLarge(String),
+    /// The grammar failed validation, see `Grammar::validate`.
+    Invalid(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::InvalidNonTerminal(name) => {
+                write!(f, "'{}' can't be used as a non-terminal", name)
+            }
+            BuildError::InvalidTerminal(word) => {
+                write!(f, "'{}' can't be used as a terminal", word)
+            }
+            BuildError::TerminalIsNonTerminal(word) => {
+                write!(f, "the terminal '{}' is also a non-terminal", word)
+            }
+            BuildError::UndefinedNonTerminal(name) => write!(f, "'{}' has no rule", name),
+            BuildError::EmptyRule(name) => write!(f, "the rule of '{}' has no productions", name),
+            BuildError::ZeroWeight(name) => {
+                write!(f, "a production of '{}' has a weight of 0", name)
+            }
+            BuildError::WeightTooLarge(name) => write!(
+                f,
+                "an option of '{}' has a weight above {}",
+                name, MAX_WEIGHT
+            ),
+            BuildError::Invalid(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// Builds a validated grammar from typed rules.
+///
+/// # Example
+/// ```
+/// use story_gen::builder::{GrammarBuilder, NonTerminal, Production, Terminal};
+///
+/// let sentence = NonTerminal::new("<sentence>");
+/// let name = NonTerminal::new("<name>");
+/// let grammar = GrammarBuilder::new(sentence.clone())
+///     .rule(
+///         sentence,
+///         vec![Production::new()
+///             .then(name.clone())
+///             .optional(Terminal::new("quickly"))
+///             .then(Terminal::new("ran home"))],
+///     )
+///     .rule(
+///         name,
+///         vec![
+///             Production::new().then(Terminal::new("Mike")).weight(3),
+///             Production::new().then(Terminal::new("Sarah O'Neil")),
+///         ],
+///     )
+///     .build()
+///     .unwrap();
+/// assert_eq!(grammar.rules()["<sentence>"], vec!["<name> ran home", "<name> quickly ran home"]);
+/// assert_eq!(grammar.rules()["<name>"], vec!["Mike", "Sarah O'Neil"]);
+/// assert_eq!(grammar.weight("<name>", 0), Some(3));
+/// ```
+#[derive(Debug, Clone)]
+pub struct GrammarBuilder {
+    start: NonTerminal,
+    rules: Vec<(NonTerminal, Vec<Production>)>,
+}
+
+impl GrammarBuilder {
+    /// A builder for a grammar whose sentences are generated from 'start'.
+    pub fn new(start: NonTerminal) -> GrammarBuilder {
+        GrammarBuilder {
+            start,
+            rules: vec![],
+        }
+    }
+
+    /// Adds the productions of 'non_terminal'. Productions added for the
+    /// same non-terminal in several calls are merged, and each option is
+    /// added once.
+    pub fn rule<I>(mut self, non_terminal: NonTerminal, productions: I) -> Self
+    where
+        I: IntoIterator<Item = Production>,
+    {
+        self.rules
+            .push((non_terminal, productions.into_iter().collect()));
+        self
+    }
+
+    /// Checks the symbols, builds the grammar and validates it.
+    pub fn build(self) -> Result<Grammar, BuildError> {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal(self.start.name());
+        for (non_terminal, _) in &self.rules {
+            check_non_terminal(non_terminal.name())?;
+        }
+        // in the order the non-terminals were first added
+        let mut rules: Vec<(&str, WeightedOptions)> = vec![];
+        for (non_terminal, productions) in &self.rules {
+            let name = non_terminal.name();
+            if productions.is_empty() {
+                return Err(BuildError::EmptyRule(String::from(name)));
+            }
+            let position = match rules.iter().position(|(rule, _)| *rule == name) {
+                Some(position) => position,
+                None => {
+                    rules.push((name, WeightedOptions::default()));
+                    rules.len() - 1
+                }
+            };
+            let options = &mut rules[position].1;
+            for production in productions {
+                if production.weight == 0 {
+                    return Err(BuildError::ZeroWeight(String::from(name)));
+                }
+                if production.weight > MAX_WEIGHT {
+                    return Err(BuildError::WeightTooLarge(String::from(name)));
+                }
+                let mut combinations = Combinations::new();
+                for (symbol, optional) in &production.items {
+                    let text = self.symbol_text(&grammar, symbol)?;
+                    match optional {
+                        true => combinations.add_optional(&text),
+                        false => combinations.add_required(&text),
+                    }
+                }
+                options
+                    .add_production(&combinations.options, production.weight)
+                    .ok_or_else(|| BuildError::WeightTooLarge(String::from(name)))?;
+            }
+        }
+        for (name, options) in rules {
+            let WeightedOptions {
+                options, weights, ..
+            } = options;
+            if weights.iter().any(|&weight| weight > u64::from(MAX_WEIGHT)) {
+                return Err(BuildError::WeightTooLarge(String::from(name)));
+            }
+            let weights: Vec<u32> = weights.into_iter().map(|weight| weight as u32).collect();
+            grammar.rule_add_from_file(Rule {
+                left_hand: String::from(name),
+                right_hand: options,
+            });
+            if weights.iter().any(|&weight| weight != 1) {
+                grammar.weights.insert(String::from(name), weights);
+            }
+        }
+        if !grammar.has_rule(self.start.name()) {
+            return Err(BuildError::UndefinedNonTerminal(String::from(
+                self.start.name(),
+            )));
+        }
+        grammar.validate().map_err(BuildError::Invalid)?;
+        Ok(grammar)
+    }
+
+    /// The text of 'symbol' in an option, after checking that it means the
+    /// same in the grammar.
+    fn symbol_text(&self, grammar: &Grammar, symbol: &Symbol) -> Result<String, BuildError> {
+        let has_rule = |name: &str| self.rules.iter().any(|(rule, _)| rule.name() == name);
+        match symbol {
+            Symbol::NonTerminal(non_terminal) => {
+                let name = non_terminal.name();
+                check_non_terminal(name)?;
+                match has_rule(name) {
+                    true => Ok(String::from(name)),
+                    false => Err(BuildError::UndefinedNonTerminal(String::from(name))),
+                }
+            }
+            Symbol::Terminal(terminal) => {
+                let words: Vec<&str> = terminal.text().split_whitespace().collect();
+                if words.is_empty() {
+                    return Err(BuildError::InvalidTerminal(String::from(terminal.text())));
+                }
+                for word in &words {
+                    // modifiers that don't exist leave the word as it is
+                    let literal = match token::classify(word) {
+                        Token::Symbol(_) => true,
+                        Token::Modified { modifiers, .. } => !modifiers
+                            .split('.')
+                            .all(|name| grammar.modifiers.contains(name)),
+                        _ => false,
+                    };
+                    if !literal || word.contains('|') {
+                        return Err(BuildError::InvalidTerminal(String::from(*word)));
+                    }
+                    if has_rule(word) {
+                        return Err(BuildError::TerminalIsNonTerminal(String::from(*word)));
+                    }
+                }
+                Ok(words.join(" "))
+            }
+        }
+    }
+}
+
+/// The options of a rule, each added once with the sum of its weights.
+#[derive(Debug)]
+struct WeightedOptions {
+    options: Vec<String>,
+    /// The weights, multiplied by 'scale' so every share is a whole number.
+    weights: Vec<u64>,
+    positions: HashMap<String, usize>,
+    /// A multiple of the number of options of every production so far.
+    scale: u64,
+}
+
+impl Default for WeightedOptions {
+    fn default() -> Self {
+        WeightedOptions {
+            options: vec![],
+            weights: vec![],
+            positions: HashMap::new(),
+            scale: 1,
+        }
+    }
+}
+
+impl WeightedOptions {
+    /// Adds the options of a production, which share its 'weight' evenly.
+    /// Returns None if the weights no longer fit.
+    fn add_production(&mut self, options: &[String], weight: u32) -> Option<()> {
+        let count = options.len() as u64;
+        if count == 0 {
+            return Some(());
+        }
+        let scale = (self.scale / gcd(self.scale, count)).checked_mul(count)?;
+        let factor = scale / self.scale;
+        for weight in &mut self.weights {
+            *weight = weight.checked_mul(factor)?;
+        }
+        self.scale = scale;
+        let share = u64::from(weight).checked_mul(scale / count)?;
+        for option in options {
+            let option = option.trim();
+            match self.positions.get(option) {
+                Some(&i) => self.weights[i] = self.weights[i].checked_add(share)?,
+                None => {
+                    self.positions
+                        .insert(String::from(option), self.options.len());
+                    self.options.push(String::from(option));
+                    self.weights.push(share);
+                }
+            }
+        }
+        Some(())
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+/// Errors unless 'name' is read as a plain non-terminal.
+fn check_non_terminal(name: &str) -> Result<(), BuildError> {
+    match token::is_plain_symbol(name) {
+        true => Ok(()),
+        false => Err(BuildError::InvalidNonTerminal(String::from(name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(text: &str) -> Production {
+        Production::new().then(Terminal::new(text))
+    }
+
+    #[test]
+    fn test_syntax_in_terminals_is_rejected() {
+        let s = NonTerminal::new("<s>");
+        let build = |text: &str| {
+            GrammarBuilder::new(s.clone())
+                .rule(s.clone(), vec![word(text)])
+                .build()
+        };
+        assert!(build("Mr.Smith etc. 3.14 (aside)").is_ok());
+        for text in [
+            "~",
+            "$hero",
+            "[hero:<pro>]",
+            "a|b",
+            "dog.plural",
+            "<np[num=pl]>",
+            " ",
+        ] {
+            assert!(
+                matches!(build(text), Err(BuildError::InvalidTerminal(_))),
+                "{}",
+                text
+            );
+        }
+        assert_eq!(
+            build("<s>").unwrap_err(),
+            BuildError::TerminalIsNonTerminal(String::from("<s>"))
+        );
+    }
+    #[test]
+    fn test_rules_are_checked() {
+        let s = NonTerminal::new("<s>");
+        let loop_ = NonTerminal::new("<loop>");
+        let missing = GrammarBuilder::new(s.clone())
+            .rule(s.clone(), vec![Production::new().then(loop_.clone())])
+            .build();
+        assert_eq!(
+            missing.unwrap_err(),
+            BuildError::UndefinedNonTerminal(String::from("<loop>"))
+        );
+        let endless = GrammarBuilder::new(s.clone())
+            .rule(s.clone(), vec![Production::new().then(loop_.clone())])
+            .rule(loop_.clone(), vec![Production::new().then(loop_.clone())])
+            .build()
+            .unwrap_err();
+        assert!(matches!(endless, BuildError::Invalid(_)), "{}", endless);
+        let empty = GrammarBuilder::new(s.clone())
+            .rule(s.clone(), vec![])
+            .build();
+        assert_eq!(
+            empty.unwrap_err(),
+            BuildError::EmptyRule(String::from("<s>"))
+        );
+        let zero = GrammarBuilder::new(s.clone())
+            .rule(s.clone(), vec![word("hi").weight(0)])
+            .build();
+        assert_eq!(
+            zero.unwrap_err(),
+            BuildError::ZeroWeight(String::from("<s>"))
+        );
+        let bad_name = GrammarBuilder::new(s.clone())
+            .rule(s, vec![word("hi")])
+            .rule(NonTerminal::new("$x"), vec![word("hi")])
+            .build();
+        assert_eq!(
+            bad_name.unwrap_err(),
+            BuildError::InvalidNonTerminal(String::from("$x"))
+        );
+    }
+    #[test]
+    fn test_weights_are_not_copies() {
+        let s = NonTerminal::new("<s>");
+        let grammar = GrammarBuilder::new(s.clone())
+            .rule(
+                s.clone(),
+                vec![word("hi").weight(MAX_WEIGHT / 2), word("bye")],
+            )
+            .rule(s.clone(), vec![word("hi").weight(2)])
+            .build()
+            .unwrap();
+        assert_eq!(grammar.rules()["<s>"], vec!["hi", "bye"]);
+        assert_eq!(grammar.weight("<s>", 0), Some(MAX_WEIGHT / 2 + 2));
+        assert_eq!(grammar.language_size("<s>"), Some(2));
+        assert_eq!(grammar.to_string(), "<s> = hi | bye\n");
+        let heavy = GrammarBuilder::new(s.clone())
+            .rule(s.clone(), vec![word("hi").weight(u32::MAX)])
+            .build();
+        assert_eq!(
+            heavy.unwrap_err(),
+            BuildError::WeightTooLarge(String::from("<s>"))
+        );
+        let summed = GrammarBuilder::new(s.clone())
+            .rule(s.clone(), vec![word("hi").weight(MAX_WEIGHT), word("hi")])
+            .build();
+        assert_eq!(
+            summed.unwrap_err(),
+            BuildError::WeightTooLarge(String::from("<s>"))
+        );
+    }
+    #[test]
+    fn test_weights_are_split_between_optional_options() {
+        let s = NonTerminal::new("<s>");
+        let grammar = GrammarBuilder::new(s.clone())
+            .rule(
+                s.clone(),
+                vec![
+                    Production::new()
+                        .then(Terminal::new("a"))
+                        .optional(Terminal::new("b"))
+                        .optional(Terminal::new("c")),
+                    word("z").weight(3),
+                ],
+            )
+            .build()
+            .unwrap();
+        let weights: Vec<Option<u32>> = (0..5).map(|i| grammar.weight("<s>", i)).collect();
+        assert_eq!(grammar.rules()["<s>"][4], "z");
+        assert_eq!(weights, vec![Some(1), Some(1), Some(1), Some(1), Some(12)]);
+        let mut rng = crate::random::seeded_rng(1);
+        let sentences = grammar.generate_sentences_with_rng("<s>", 1000, &mut rng);
+        let z = sentences.iter().filter(|s| *s == "Z.").count();
+        assert!((700..=800).contains(&z), "{}", z);
+    }
+    #[test]
+    fn test_built_grammar_generates() {
+        let s = NonTerminal::new("<s>");
+        let pro = NonTerminal::new("<pro>");
+        let grammar = GrammarBuilder::new(s.clone())
+            .rule(
+                s.clone(),
+                vec![Production::new()
+                    .then(pro.clone())
+                    .optional(Terminal::new("really"))
+                    .optional(Terminal::new("quickly"))
+                    .then(Terminal::new("ran"))],
+            )
+            .rule(pro.clone(), vec![word("Mike")])
+            .rule(pro, vec![word("Sarah")])
+            .build()
+            .unwrap();
+        assert_eq!(grammar.rules()["<s>"].len(), 4);
+        assert_eq!(grammar.rules()["<pro>"], vec!["Mike", "Sarah"]);
+        let sentence = grammar.generate_sentence("<s>");
+        assert!(sentence.ends_with(" ran."), "{}", sentence);
+    }
+}
@@ -0,0 +1,222 @@
+//! This module contains the compiled form of the grammar rules. Symbols are
+//! interned as numbers, and every option is split into its tokens once,
+//! instead of every time it is chosen. The generator only expands it for
+//! non-terminals without features or a cooldown window, while no length or
+//! required items are left to steer towards. Everything else is still
+//! expanded from the text of the rules.
+use crate::features::FeaturedRule;
+use crate::token::{self, Token};
+use rand::distributions::WeightedIndex;
+use std::collections::HashMap;
+
+/// The number of an interned non-terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SymbolId(u32);
+
+/// The number of a string in the string table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StringId(u32);
+
+/// A token of a compiled option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Piece {
+    /// A non-terminal with a rule.
+    Symbol(SymbolId),
+    /// A word without a rule, which is output as it is.
+    Terminal(StringId),
+    /// A standalone `~`, see `Token::Join`.
+    Join,
+    /// Any other token, such as a variable, a binding, a modified token or a
+    /// non-terminal with features, which is expanded from its text.
+    Token(StringId),
+}
+
+/// The compiled options of a non-terminal.
+#[derive(Debug, Clone, Default)]
+pub struct CompiledRule {
+    pub name: String,
+    /// The options without features, in the order of `Grammar::rules`.
+    pub options: Vec<Box<[Piece]>>,
+    /// The distribution of the options, when they have weights. See
+    /// `Grammar::set_weight`.
+    pub weights: Option<WeightedIndex<u64>>,
+    /// Whether the non-terminal also has rules with features, which are only
+    /// expanded from their text.
+    pub featured: bool,
+}
+
+/// The rules of a grammar with interned symbols and tokenized options. It
+/// does not change once built, see `Grammar::compiled`.
+///
+/// # Example
+/// ```
+/// use story_gen::compiled::{CompiledGrammar, Piece};
+/// use std::collections::HashMap;
+///
+/// let mut rules = HashMap::new();
+/// rules.insert(String::from("<s>"), vec![String::from("<pro> ran")]);
+/// rules.insert(String::from("<pro>"), vec![String::from("Mike"), String::from("Sarah")]);
+/// let compiled = CompiledGrammar::new(&rules, &HashMap::new(), &HashMap::new());
+///
+/// let s = compiled.symbol("<s>").unwrap();
+/// let pro = compiled.symbol("<pro>").unwrap();
+/// match &compiled.rule(s).options[0][..] {
+///     [Piece::Symbol(symbol), Piece::Terminal(word)] => {
+///         assert_eq!(*symbol, pro);
+///         assert_eq!(compiled.string(*word), "ran");
+///     }
+///     pieces => panic!("unexpected pieces {:?}", pieces),
+/// }
+/// assert_eq!(compiled.rule(pro).options.len(), 2);
+/// assert_eq!(compiled.symbol("ran"), None);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CompiledGrammar {
+    rules: Vec<CompiledRule>,
+    symbols: HashMap<String, SymbolId>,
+    strings: Vec<String>,
+}
+
+impl CompiledGrammar {
+    /// Compiles the rules with and without features. Only the options
+    /// without features are tokenized. 'weights' are the weights of the
+    /// options by non-terminal, options without one have a weight of 1.
+    pub fn new(
+        rules: &HashMap<String, Vec<String>>,
+        featured_rules: &HashMap<String, Vec<FeaturedRule>>,
+        weights: &HashMap<String, Vec<u32>>,
+    ) -> CompiledGrammar {
+        let mut compiled = CompiledGrammar::default();
+        let mut names: Vec<&String> = rules.keys().chain(featured_rules.keys()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            compiled
+                .symbols
+                .insert(name.clone(), SymbolId(compiled.rules.len() as u32));
+            compiled.rules.push(CompiledRule {
+                name: name.clone(),
+                options: vec![],
+                weights: None,
+                featured: featured_rules.contains_key(name),
+            });
+        }
+        let mut interned: HashMap<&str, StringId> = HashMap::new();
+        for (name, options) in rules {
+            let id = compiled.symbols[name];
+            let options: Vec<Box<[Piece]>> = options
+                .iter()
+                .map(|option| {
+                    option
+                        .split_whitespace()
+                        .map(|token| compiled.piece(token, &mut interned))
+                        .collect()
+                })
+                .collect();
+            let weights = weights.get(name).and_then(|weights| {
+                let weight = |i| weights.get(i).map_or(1, |&weight| u64::from(weight));
+                WeightedIndex::new((0..options.len()).map(weight)).ok()
+            });
+            let rule = &mut compiled.rules[id.0 as usize];
+            rule.options = options;
+            rule.weights = weights;
+        }
+        compiled
+    }
+
+    /// The piece for a single token, adding its text to the string table
+    /// when needed.
+    fn piece<'a>(&mut self, token: &'a str, interned: &mut HashMap<&'a str, StringId>) -> Piece {
+        let classified = token::classify(token);
+        if classified == Token::Join {
+            return Piece::Join;
+        }
+        if let Token::Symbol(symbol) = classified {
+            if let Some(id) = self.symbols.get(symbol) {
+                return Piece::Symbol(*id);
+            }
+        }
+        let strings = &mut self.strings;
+        let id = *interned.entry(token).or_insert_with(|| {
+            strings.push(String::from(token));
+            StringId(strings.len() as u32 - 1)
+        });
+        match classified {
+            Token::Symbol(_) => Piece::Terminal(id),
+            _ => Piece::Token(id),
+        }
+    }
+
+    /// The number of the non-terminal 'name', if it has a rule.
+    pub fn symbol(&self, name: &str) -> Option<SymbolId> {
+        self.symbols.get(name).copied()
+    }
+
+    pub fn rule(&self, id: SymbolId) -> &CompiledRule {
+        &self.rules[id.0 as usize]
+    }
+
+    pub fn string(&self, id: StringId) -> &str {
+        &self.strings[id.0 as usize]
+    }
+
+    /// Number of non-terminals.
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_are_classified_and_interned() {
+        let mut rules = HashMap::new();
+        rules.insert(
+            String::from("<s>"),
+            vec![String::from(
+                "[hero:<pro>] met $hero ~ <np[num=pl]> met <pro>.upper",
+            )],
+        );
+        rules.insert(String::from("<pro>"), vec![String::from("met")]);
+        let featured = crate::features::parse_featured("<np[num=pl]>").unwrap();
+        let mut featured_rules = HashMap::new();
+        featured_rules.insert(
+            String::from("<np>"),
+            vec![FeaturedRule {
+                head: String::from("<np[num=pl]>"),
+                features: featured.features,
+                options: vec![String::from("dogs")],
+            }],
+        );
+        let compiled = CompiledGrammar::new(&rules, &featured_rules, &HashMap::new());
+        assert_eq!(compiled.len(), 3);
+        let np = compiled.rule(compiled.symbol("<np>").unwrap());
+        assert!(np.featured);
+        assert!(np.options.is_empty());
+        let s = compiled.rule(compiled.symbol("<s>").unwrap());
+        let pieces = &s.options[0];
+        let met = match pieces[1] {
+            Piece::Terminal(met) => met,
+            piece => panic!("unexpected piece {:?}", piece),
+        };
+        assert_eq!(pieces[5], Piece::Terminal(met));
+        assert_eq!(pieces[3], Piece::Join);
+        let texts: Vec<&str> = [0, 2, 4, 6]
+            .iter()
+            .map(|&i| match pieces[i] {
+                Piece::Token(id) => compiled.string(id),
+                piece => panic!("unexpected piece {:?}", piece),
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            vec!["[hero:<pro>]", "$hero", "<np[num=pl]>", "<pro>.upper"]
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! This module contains the state that is carried from one expansion to the
+//! next while sentences are generated.
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+
+/// How long a variable bound with `[name:value]` is remembered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum VariableScope {
+    /// Variables are forgotten after every sentence.
+    #[default]
+    Sentence,
+    /// Variables are forgotten at the start of every paragraph.
+    Paragraph,
+    /// Variables are kept for the whole run.
+    Run,
+}
+
+impl FromStr for VariableScope {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "sentence" => Ok(VariableScope::Sentence),
+            "paragraph" => Ok(VariableScope::Paragraph),
+            "run" => Ok(VariableScope::Run),
+            _ => Err("Variable scope must be one of: sentence, paragraph, run."),
+        }
+    }
+}
+
+/// What happens to the recently chosen options of a non-terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum RepetitionMode {
+    /// Recent options are not chosen, unless every option is recent.
+    #[default]
+    Exclude,
+    /// Recent options are chosen less often, see RepetitionPolicy::recent_weight.
+    Downweight,
+}
+
+impl FromStr for RepetitionMode {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "exclude" => Ok(RepetitionMode::Exclude),
+            "downweight" => Ok(RepetitionMode::Downweight),
+            _ => Err("Repetition mode must be one of: exclude, downweight."),
+        }
+    }
+}
+
+/// Avoids choosing the same option of a non-terminal again within a
+/// cooldown window of its most recent choices. Disabled by default.
+///
+/// # Example
+/// ```
+/// use story_gen::context::{RepetitionMode, RepetitionPolicy};
+///
+/// let mut policy = RepetitionPolicy {
+///     window: 2,
+///     ..RepetitionPolicy::default()
+/// };
+/// policy.windows.insert(String::from("<pro>"), 4);
+/// assert_eq!(policy.window("<adj>"), 2);
+/// assert_eq!(policy.window("<pro>"), 4);
+/// assert_eq!(policy.weight(true), 0.0);
+/// policy.mode = RepetitionMode::Downweight;
+/// assert_eq!(policy.weight(true), policy.recent_weight);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RepetitionPolicy {
+    pub mode: RepetitionMode,
+    /// Number of recent choices remembered for every non-terminal. 0 disables
+    /// the policy.
+    pub window: usize,
+    /// Windows for specific non-terminals, replacing 'window'.
+    pub windows: HashMap<String, usize>,
+    /// How likely a recent option is compared to any other option, when
+    /// the mode is Downweight.
+    pub recent_weight: f64,
+}
+
+impl Default for RepetitionPolicy {
+    fn default() -> Self {
+        RepetitionPolicy {
+            mode: RepetitionMode::Exclude,
+            window: 0,
+            windows: HashMap::new(),
+            recent_weight: 0.2,
+        }
+    }
+}
+
+impl RepetitionPolicy {
+    /// The cooldown window of the non-terminal 'key'.
+    pub fn window(&self, key: &str) -> usize {
+        self.windows.get(key).copied().unwrap_or(self.window)
+    }
+
+    /// Whether any non-terminal has a cooldown window.
+    pub fn is_enabled(&self) -> bool {
+        self.window > 0 || self.windows.values().any(|window| *window > 0)
+    }
+
+    /// The weight of an option when choosing, relative to 1.0.
+    pub fn weight(&self, recent: bool) -> f64 {
+        match (recent, self.mode) {
+            (false, _) => 1.0,
+            (true, RepetitionMode::Exclude) => 0.0,
+            (true, RepetitionMode::Downweight) => self.recent_weight,
+        }
+    }
+}
+
+/// State shared by consecutive sentences of a single generation call.
+///
+/// # Example
+/// ```
+/// use story_gen::context::{GenerationContext, VariableScope};
+///
+/// let mut context = GenerationContext::new(VariableScope::Paragraph);
+/// context.bind("hero", "Mike");
+/// context.begin_sentence();
+/// assert_eq!(context.variable("hero"), Some("Mike"));
+/// context.begin_paragraph();
+/// assert_eq!(context.variable("hero"), None);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct GenerationContext {
+    pub variable_scope: VariableScope,
+    variables: HashMap<String, String>,
+    pub repetition: RepetitionPolicy,
+    /// The most recent options chosen for every non-terminal, newest last.
+    recent_choices: HashMap<String, VecDeque<String>>,
+}
+
+impl GenerationContext {
+    pub fn new(variable_scope: VariableScope) -> GenerationContext {
+        GenerationContext {
+            variable_scope,
+            variables: Default::default(),
+            repetition: Default::default(),
+            recent_choices: Default::default(),
+        }
+    }
+
+    /// Called before every sentence. Forgets sentence scoped variables.
+    pub fn begin_sentence(&mut self) {
+        if self.variable_scope == VariableScope::Sentence {
+            self.variables.clear();
+        }
+    }
+
+    /// Called before the first sentence of every paragraph. Forgets sentence
+    /// and paragraph scoped variables.
+    pub fn begin_paragraph(&mut self) {
+        if self.variable_scope != VariableScope::Run {
+            self.variables.clear();
+        }
+    }
+
+    /// Get the value bound to the variable 'name'.
+    pub fn variable(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(|value| value.as_str())
+    }
+
+    /// Remember 'value' as the variable 'name'.
+    pub fn bind(&mut self, name: &str, value: &str) {
+        self.variables
+            .insert(String::from(name), String::from(value));
+    }
+
+    /// Whether 'option' was chosen for 'key' within its cooldown window.
+    pub fn is_recent(&self, key: &str, option: &str) -> bool {
+        match self.recent_choices.get(key) {
+            Some(recent) => recent.iter().any(|choice| choice == option),
+            None => false,
+        }
+    }
+
+    /// Remember that 'option' was chosen for 'key', forgetting choices that
+    /// fall out of the cooldown window.
+    pub fn remember_choice(&mut self, key: &str, option: &str) {
+        let window = self.repetition.window(key);
+        if window == 0 {
+            return;
+        }
+        let recent = self.recent_choices.entry(String::from(key)).or_default();
+        recent.push_back(String::from(option));
+        while recent.len() > window {
+            recent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cooldown_window() {
+        let mut context = GenerationContext::default();
+        context.remember_choice("<adj>", "happy");
+        assert!(!context.is_recent("<adj>", "happy"));
+        context.repetition.window = 2;
+        context.remember_choice("<adj>", "happy");
+        context.remember_choice("<adj>", "sad");
+        assert!(context.is_recent("<adj>", "happy"));
+        context.remember_choice("<adj>", "calm");
+        assert!(!context.is_recent("<adj>", "happy"));
+        assert!(context.is_recent("<adj>", "sad"));
+        context.begin_paragraph();
+        assert!(context.is_recent("<adj>", "calm"));
+    }
+}
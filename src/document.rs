@@ -0,0 +1,372 @@
+//! This module contains the document model used to generate a structured
+//! story: a story has chapters, chapters have paragraphs and paragraphs have
+//! sentences. The story and its chapters can have titles generated from
+//! their own start symbols, and each level is made of a number of parts of
+//! the level below, or generated whole from its own start symbol.
+use crate::output::SentenceRecord;
+use rand::Rng;
+use std::str::FromStr;
+
+/// An inclusive range of counts, such as "3-6" sentences per paragraph.
+///
+/// # Example
+/// ```
+/// use story_gen::document::CountRange;
+/// assert_eq!("3-6".parse(), Ok(CountRange { min: 3, max: 6 }));
+/// assert_eq!("4".parse(), Ok(CountRange { min: 4, max: 4 }));
+/// assert!("6-3".parse::<CountRange>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CountRange {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Default for CountRange {
+    fn default() -> Self {
+        CountRange { min: 1, max: 1 }
+    }
+}
+
+impl FromStr for CountRange {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let error = "Counts must be a positive integer or a range such as 3-6.";
+        let (min, max) = match value.split_once('-') {
+            Some((min, max)) => (min, max),
+            None => (value, value),
+        };
+        let min: usize = min.trim().parse().map_err(|_| error)?;
+        let max: usize = max.trim().parse().map_err(|_| error)?;
+        if min == 0 || min > max {
+            return Err(error);
+        }
+        Ok(CountRange { min, max })
+    }
+}
+
+impl CountRange {
+    /// Picks a count in the range. A fixed count does not use 'rng'.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> usize {
+        match self.min == self.max {
+            true => self.min,
+            false => rng.gen_range(self.min..=self.max),
+        }
+    }
+}
+
+/// A part of the story that needs to be generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Part<'a> {
+    /// A story or chapter title, generated from the symbol.
+    Title(&'a str),
+    /// The next sentence, generated from 'symbol'. 'starts_paragraph' is set
+    /// for the first sentence of every paragraph. A paragraph or chapter
+    /// generated whole from its symbol is a single sentence.
+    Sentence {
+        symbol: &'a str,
+        starts_paragraph: bool,
+    },
+}
+
+/// How a story is laid out. Every level picks its number of children from
+/// a range, unless it has its own symbol, and titles are generated from
+/// their own symbols.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StoryPlan {
+    /// Symbol the story title is generated from.
+    pub title: Option<String>,
+    pub chapters: CountRange,
+    /// Symbol every chapter title is generated from.
+    pub chapter_title: Option<String>,
+    /// Symbol every chapter is generated from as a single paragraph, instead
+    /// of 'paragraphs' paragraphs.
+    pub chapter: Option<String>,
+    /// Paragraphs in every chapter.
+    pub paragraphs: CountRange,
+    /// Symbol every paragraph is generated from as a whole, instead of
+    /// 'sentences' sentences.
+    pub paragraph: Option<String>,
+    /// Sentences in every paragraph.
+    pub sentences: CountRange,
+    /// Symbol every sentence is generated from, instead of the start symbol
+    /// given to `build`.
+    pub sentence: Option<String>,
+}
+
+/// A generated story. Sentence and paragraph indices of the records count
+/// from the start of the story.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Story {
+    pub title: Option<String>,
+    pub chapters: Vec<Chapter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Chapter {
+    pub title: Option<String>,
+    pub paragraphs: Vec<Vec<SentenceRecord>>,
+}
+
+impl Story {
+    /// Every sentence of the story, in order.
+    pub fn records(&self) -> impl Iterator<Item = &SentenceRecord> {
+        self.chapters
+            .iter()
+            .flat_map(|chapter| chapter.paragraphs.iter().flatten())
+    }
+}
+
+impl StoryPlan {
+    /// Every symbol of the plan, for titles and levels.
+    pub fn symbols(&self) -> impl Iterator<Item = &String> {
+        self.title
+            .iter()
+            .chain(self.chapter_title.iter())
+            .chain(self.chapter.iter())
+            .chain(self.paragraph.iter())
+            .chain(self.sentence.iter())
+    }
+
+    /// Builds a story following the plan. 'generate' produces the text and
+    /// optional derivation of every part, and returning None for a part ends
+    /// the story there, e.g. when the grammar has no unique sentences left.
+    /// Sentences are generated from 'start_symbol' unless the plan has a
+    /// symbol for them. The symbol of every sentence and 'seed' are recorded
+    /// with it.
+    ///
+    /// # Example
+    /// ```
+    /// use story_gen::document::{CountRange, Part, StoryPlan};
+    ///
+    /// let plan = StoryPlan {
+    ///     title: Some(String::from("<title>")),
+    ///     chapters: CountRange { min: 2, max: 2 },
+    ///     sentences: CountRange { min: 3, max: 3 },
+    ///     ..StoryPlan::default()
+    /// };
+    /// let mut rng = story_gen::random::seeded_rng(1);
+    /// let story = plan.build(&mut rng, "<s>", 1, |part, _| match part {
+    ///     Part::Title(symbol) => Some((format!("Title from {}", symbol), None)),
+    ///     Part::Sentence { symbol, .. } => Some((format!("Text from {}.", symbol), None)),
+    /// });
+    /// assert_eq!(story.title.as_deref(), Some("Title from <title>"));
+    /// assert_eq!(story.chapters.len(), 2);
+    /// assert_eq!(story.records().count(), 6);
+    /// assert_eq!(story.records().next().unwrap().sentence, "Text from <s>.");
+    ///
+    /// // every paragraph is generated whole from <paragraph>
+    /// let plan = StoryPlan {
+    ///     paragraphs: CountRange { min: 3, max: 3 },
+    ///     paragraph: Some(String::from("<paragraph>")),
+    ///     ..plan
+    /// };
+    /// let story = plan.build(&mut rng, "<s>", 1, |part, _| match part {
+    ///     Part::Title(symbol) => Some((format!("Title from {}", symbol), None)),
+    ///     Part::Sentence { symbol, .. } => Some((format!("Text from {}.", symbol), None)),
+    /// });
+    /// assert_eq!(story.chapters[0].paragraphs.len(), 3);
+    /// assert_eq!(story.records().count(), 6);
+    /// assert_eq!(story.records().next().unwrap().sentence, "Text from <paragraph>.");
+    /// ```
+    pub fn build<R, F>(&self, rng: &mut R, start_symbol: &str, seed: u64, mut generate: F) -> Story
+    where
+        R: Rng + ?Sized,
+        F: FnMut(Part, &mut R) -> Option<(String, Option<String>)>,
+    {
+        let mut story = Story::default();
+        if let Some(symbol) = &self.title {
+            match generate(Part::Title(symbol), rng) {
+                Some((title, _)) => story.title = Some(title),
+                None => return story,
+            }
+        }
+        // the symbol of the level generated whole, and whether it is a chapter
+        let (symbol, whole_chapter) = match (&self.chapter, &self.paragraph) {
+            (Some(chapter), _) => (chapter.as_str(), true),
+            (None, Some(paragraph)) => (paragraph.as_str(), false),
+            (None, None) => (self.sentence.as_deref().unwrap_or(start_symbol), false),
+        };
+        let whole = self.chapter.is_some() || self.paragraph.is_some();
+        let mut sentence_index = 0;
+        let mut paragraph_index = 0;
+        for _ in 0..self.chapters.sample(rng) {
+            let mut chapter = Chapter::default();
+            if let Some(symbol) = &self.chapter_title {
+                match generate(Part::Title(symbol), rng) {
+                    Some((title, _)) => chapter.title = Some(title),
+                    None => return story,
+                }
+            }
+            let mut finished = false;
+            let paragraphs = match whole_chapter {
+                true => 1,
+                false => self.paragraphs.sample(rng),
+            };
+            for _ in 0..paragraphs {
+                let mut paragraph = vec![];
+                let sentences = match whole {
+                    true => 1,
+                    false => self.sentences.sample(rng),
+                };
+                for i in 0..sentences {
+                    let part = Part::Sentence {
+                        symbol,
+                        starts_paragraph: i == 0,
+                    };
+                    let (sentence, derivation) = match generate(part, rng) {
+                        Some(generated) => generated,
+                        None => {
+                            finished = true;
+                            break;
+                        }
+                    };
+                    paragraph.push(SentenceRecord {
+                        sentence_index,
+                        paragraph_index,
+                        chapter_index: Some(story.chapters.len()),
+                        start_symbol: String::from(symbol),
+                        seed,
+                        sentence,
+                        derivation,
+                    });
+                    sentence_index += 1;
+                }
+                if !paragraph.is_empty() {
+                    chapter.paragraphs.push(paragraph);
+                    paragraph_index += 1;
+                }
+                if finished {
+                    break;
+                }
+            }
+            story.chapters.push(chapter);
+            if finished {
+                break;
+            }
+        }
+        story
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_ranges_are_respected() {
+        let plan = StoryPlan {
+            chapters: CountRange { min: 1, max: 3 },
+            paragraphs: CountRange { min: 2, max: 4 },
+            sentences: CountRange { min: 3, max: 6 },
+            ..StoryPlan::default()
+        };
+        let mut rng = crate::random::seeded_rng(9);
+        let mut starts = 0;
+        let story = plan.build(&mut rng, "<s>", 9, |part, _| {
+            if part
+                == (Part::Sentence {
+                    symbol: "<s>",
+                    starts_paragraph: true,
+                })
+            {
+                starts += 1;
+            }
+            Some((String::from("A."), None))
+        });
+        assert!((1..=3).contains(&story.chapters.len()));
+        let mut paragraphs = 0;
+        for chapter in &story.chapters {
+            assert_eq!(chapter.title, None);
+            assert!((2..=4).contains(&chapter.paragraphs.len()));
+            for paragraph in &chapter.paragraphs {
+                assert!((3..=6).contains(&paragraph.len()));
+                assert!(paragraph.iter().all(|r| r.paragraph_index == paragraphs));
+                paragraphs += 1;
+            }
+        }
+        assert_eq!(starts, paragraphs);
+    }
+    #[test]
+    fn test_story_ends_at_a_failed_title() {
+        let plan = StoryPlan {
+            chapters: CountRange { min: 3, max: 3 },
+            chapter_title: Some(String::from("<title>")),
+            ..StoryPlan::default()
+        };
+        let mut rng = crate::random::seeded_rng(9);
+        let mut parts = 0;
+        let story = plan.build(&mut rng, "<s>", 9, |part, _| {
+            parts += 1;
+            match part {
+                Part::Title(_) if parts > 1 => None,
+                _ => Some((String::from("A."), None)),
+            }
+        });
+        // the first title and its sentence, then the second title
+        assert_eq!(parts, 3);
+        assert_eq!(story.chapters.len(), 1);
+    }
+    #[test]
+    fn test_levels_from_symbols() {
+        let plan = StoryPlan {
+            chapters: CountRange { min: 2, max: 2 },
+            paragraphs: CountRange { min: 3, max: 3 },
+            sentences: CountRange { min: 4, max: 4 },
+            sentence: Some(String::from("<line>")),
+            ..StoryPlan::default()
+        };
+        let build = |plan: &StoryPlan| {
+            let mut rng = crate::random::seeded_rng(9);
+            plan.build(&mut rng, "<s>", 9, |part, _| match part {
+                Part::Title(_) => None,
+                Part::Sentence { symbol, .. } => Some((String::from(symbol), None)),
+            })
+        };
+        let story = build(&plan);
+        assert_eq!(story.records().count(), 24);
+        assert!(story.records().all(|record| record.sentence == "<line>"));
+        let paragraphs = StoryPlan {
+            paragraph: Some(String::from("<paragraph>")),
+            ..plan.clone()
+        };
+        let story = build(&paragraphs);
+        assert_eq!(story.chapters[0].paragraphs.len(), 3);
+        assert_eq!(story.records().count(), 6);
+        assert!(story
+            .records()
+            .all(|record| record.start_symbol == "<paragraph>"));
+        let chapters = StoryPlan {
+            chapter: Some(String::from("<chapter>")),
+            ..paragraphs
+        };
+        let story = build(&chapters);
+        assert_eq!(story.records().count(), 2);
+        assert!(story.records().all(|record| record.sentence == "<chapter>"));
+    }
+    #[test]
+    fn test_story_ends_when_sentences_run_out() {
+        let plan = StoryPlan {
+            chapters: CountRange { min: 2, max: 2 },
+            sentences: CountRange { min: 3, max: 3 },
+            ..StoryPlan::default()
+        };
+        let mut rng = crate::random::seeded_rng(9);
+        let mut left = 4;
+        let story = plan.build(&mut rng, "<s>", 9, |_, _| {
+            left -= 1;
+            match left >= 0 {
+                true => Some((String::from("A."), None)),
+                false => None,
+            }
+        });
+        assert_eq!(story.chapters.len(), 2);
+        assert_eq!(story.chapters[1].paragraphs[0].len(), 1);
+        assert_eq!(story.records().last().unwrap().sentence_index, 3);
+    }
+}
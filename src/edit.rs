@@ -0,0 +1,665 @@
+//! This module contains the methods that edit the rules of a grammar after
+//! they are loaded: removing and replacing rules, adding, removing and
+//! moving single options, weighting options, and renaming a non-terminal
+//! everywhere it is used. Every edit returns a `Result` instead of printing, and is undone
+//! when it fails, so the grammar is never left half edited.
+//!
+//! After an edit the compiled rules are rebuilt, and the length constraint
+//! and required items are applied again. An edit that would make a valid
+//! grammar invalid is rejected.
+use crate::features::FeaturedRule;
+use crate::grammar::{Grammar, MAX_WEIGHT};
+use crate::postprocess::PostProcessor;
+use crate::template;
+use crate::token::{self, Token};
+use crate::Rule;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// Why an edit was rejected. The grammar is unchanged after an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditError {
+    /// There is no rule for the non-terminal.
+    NoSuchRule(String),
+    /// The rule of 'key' has no option at 'index'.
+    NoSuchAlternative {
+        key: String,
+        index: usize,
+        len: usize,
+    },
+    /// An option is empty or contains `|`, which separates options.
+    InvalidAlternative(String),
+    /// A weight of 0 or above MAX_WEIGHT.
+    InvalidWeight(u32),
+    /// The edit would leave the rule of the non-terminal without options.
+    /// Use `remove_rule` to remove the rule itself.
+    EmptyRule(String),
+    /// The name can't be used as a non-terminal, see
+    /// `token::is_plain_symbol`.
+    InvalidSymbol(String),
+    /// The name already has a rule or is used by the rules.
+    SymbolExists(String),
+    /// The length constraint or required items can't be met after the edit.
+    Constraint(String),
+    /// The edit would make the grammar fail validation.
+    Invalid(String),
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditError::NoSuchRule(key) => write!(f, "no rule for '{}'", key),
+            EditError::NoSuchAlternative { key, index, len } => write!(
+                f,
+                "'{}' has no option {}, it has {} options",
+                key, index, len
+            ),
+            EditError::InvalidAlternative(option) => {
+                write!(f, "'{}' can't be used as an option", option)
+            }
+            EditError::InvalidWeight(weight) => write!(
+                f,
+                "a weight must be between 1 and {}, not {}",
+                MAX_WEIGHT, weight
+            ),
+            EditError::EmptyRule(key) => write!(f, "the rule of '{}' would have no options", key),
+            EditError::InvalidSymbol(name) => {
+                write!(f, "'{}' can't be used as a non-terminal", name)
+            }
+            EditError::SymbolExists(name) => write!(f, "'{}' is already used", name),
+            EditError::Constraint(error) | EditError::Invalid(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for EditError {}
+
+/// The parts of a grammar an edit can change, kept to undo it.
+struct Snapshot {
+    rules: HashMap<String, Vec<String>>,
+    featured_rules: HashMap<String, Vec<FeaturedRule>>,
+    weights: HashMap<String, Vec<u32>>,
+    start_nonterminal: String,
+    post_processors: HashMap<String, PostProcessor>,
+    required: Vec<String>,
+}
+
+/// Editing rules.
+///
+/// # Example
+/// ```
+/// use story_gen::edit::EditError;
+/// use story_gen::grammar::Grammar;
+///
+/// let mut grammar = Grammar::new();
+/// grammar.rule_add("<s>", "<pro> ran | <pro> [hero:<pro>] saw $hero");
+/// grammar.rule_add("<pro>", "Mike | Sarah");
+///
+/// grammar.add_alternative("<pro>", "Will").unwrap();
+/// grammar.move_alternative("<pro>", 2, 0).unwrap();
+/// assert_eq!(grammar.remove_alternative("<pro>", 1), Ok(String::from("Mike")));
+/// grammar.set_weight("<pro>", 1, 3).unwrap();
+/// grammar.rename_symbol("<pro>", "<name>").unwrap();
+/// assert_eq!(grammar.rules()["<s>"], vec!["<name> ran", "<name> [hero:<name>] saw $hero"]);
+/// assert_eq!(grammar.rules()["<name>"], vec!["Will", "Sarah"]);
+/// assert_eq!(grammar.weight("<name>", 1), Some(3));
+///
+/// assert_eq!(
+///     grammar.remove_rule("<pro>").unwrap_err(),
+///     EditError::NoSuchRule(String::from("<pro>"))
+/// );
+/// ```
+impl Grammar {
+    /// Remove the rule of 'key', including every variant with features.
+    /// Returns the removed rules, plain options first.
+    pub fn remove_rule(&mut self, key: &str) -> Result<Vec<Rule>, EditError> {
+        if !self.has_rule(key) {
+            return Err(EditError::NoSuchRule(String::from(key)));
+        }
+        self.edit(|grammar| {
+            grammar.forget_weights(key);
+            let mut removed = vec![];
+            if let Some(options) = grammar.rules.remove(key) {
+                removed.push(Rule {
+                    left_hand: String::from(key),
+                    right_hand: options,
+                });
+            }
+            for rule in grammar.featured_rules.remove(key).unwrap_or_default() {
+                removed.push(Rule {
+                    left_hand: rule.head,
+                    right_hand: rule.options,
+                });
+            }
+            Ok(removed)
+        })
+    }
+
+    /// Replace the options of the rule of 'key', which may be a head with
+    /// features such as `<np[num=pl]>`. Returns the old options. The new
+    /// options have a weight of 1.
+    pub fn replace_rule<S: AsRef<str>>(
+        &mut self,
+        key: &str,
+        options: &[S],
+    ) -> Result<Vec<String>, EditError> {
+        let options = options
+            .iter()
+            .map(|option| checked_option(option.as_ref()))
+            .collect::<Result<Vec<String>, EditError>>()?;
+        if options.is_empty() {
+            return Err(EditError::EmptyRule(String::from(key)));
+        }
+        self.edit(|grammar| {
+            let current = std::mem::replace(grammar.options_mut(key)?, options);
+            grammar.weights.remove(key);
+            Ok(current)
+        })
+    }
+
+    /// Add 'option' after the options of the rule of 'key'. Use `rule_add`
+    /// to add a rule that does not exist yet.
+    pub fn add_alternative(&mut self, key: &str, option: &str) -> Result<(), EditError> {
+        let option = checked_option(option)?;
+        self.edit(|grammar| {
+            grammar.options_mut(key)?.push(option);
+            Ok(())
+        })
+    }
+
+    /// Remove the option at 'index' of the rule of 'key', and return it.
+    /// The last option can't be removed, remove the rule instead.
+    pub fn remove_alternative(&mut self, key: &str, index: usize) -> Result<String, EditError> {
+        self.edit(|grammar| {
+            let options = grammar.options_mut(key)?;
+            check_index(key, options, index)?;
+            if options.len() == 1 {
+                return Err(EditError::EmptyRule(String::from(key)));
+            }
+            let option = options.remove(index);
+            if let Some(weights) = grammar.weights.get_mut(key) {
+                if index < weights.len() {
+                    weights.remove(index);
+                }
+            }
+            Ok(option)
+        })
+    }
+
+    /// Move the option at 'from' of the rule of 'key' to 'to', shifting the
+    /// options in between.
+    pub fn move_alternative(&mut self, key: &str, from: usize, to: usize) -> Result<(), EditError> {
+        self.edit(|grammar| {
+            let options = grammar.options_mut(key)?;
+            check_index(key, options, from)?;
+            check_index(key, options, to)?;
+            let option = options.remove(from);
+            options.insert(to, option);
+            let len = options.len();
+            if let Some(weights) = grammar.weights.get_mut(key) {
+                weights.resize(len, 1);
+                let weight = weights.remove(from);
+                weights.insert(to, weight);
+            }
+            Ok(())
+        })
+    }
+
+    /// Set the weight of the option at 'index' of the rule of 'key': how
+    /// likely it is to be chosen compared to an option with a weight of 1,
+    /// which every option has until it is set. The weight must be between 1
+    /// and MAX_WEIGHT.
+    pub fn set_weight(&mut self, key: &str, index: usize, weight: u32) -> Result<(), EditError> {
+        if !(1..=MAX_WEIGHT).contains(&weight) {
+            return Err(EditError::InvalidWeight(weight));
+        }
+        let options = self.options_mut(key)?;
+        check_index(key, options, index)?;
+        let len = options.len();
+        let weights = self.weights.entry(String::from(key)).or_default();
+        weights.resize(len, 1);
+        weights[index] = weight;
+        // the weights after the last one above 1 are 1 anyway
+        while weights.last() == Some(&1) {
+            weights.pop();
+        }
+        if weights.is_empty() {
+            self.weights.remove(key);
+        }
+        self.recompile();
+        Ok(())
+    }
+
+    /// Rename the non-terminal 'old' to 'new' in its rules and every use:
+    /// options, bindings, modified tokens, heads with features, template
+    /// instances, the start symbol, post-processors and required items.
+    /// 'new' must not be used by the grammar yet, or terminals of that name
+    /// would start being expanded.
+    pub fn rename_symbol(&mut self, old: &str, new: &str) -> Result<(), EditError> {
+        if !token::is_plain_symbol(new) {
+            return Err(EditError::InvalidSymbol(String::from(new)));
+        }
+        if !self.has_rule(old) {
+            return Err(EditError::NoSuchRule(String::from(old)));
+        }
+        if old == new {
+            return Ok(());
+        }
+        if self.has_rule(new) || self.uses_symbol(new) {
+            return Err(EditError::SymbolExists(String::from(new)));
+        }
+        self.edit(|grammar| {
+            let rename =
+                |text: &str| rename_in_token(text, old, new).unwrap_or_else(|| text.into());
+            grammar.rules = grammar
+                .rules
+                .drain()
+                .map(|(key, options)| {
+                    let options = options
+                        .iter()
+                        .map(|option| rename_in_option(option, old, new))
+                        .collect();
+                    (rename(&key), options)
+                })
+                .collect();
+            grammar.featured_rules = grammar
+                .featured_rules
+                .drain()
+                .map(|(symbol, rules)| {
+                    let rules = rules
+                        .into_iter()
+                        .map(|rule| FeaturedRule {
+                            head: rename(&rule.head),
+                            features: rule.features,
+                            options: rule
+                                .options
+                                .iter()
+                                .map(|option| rename_in_option(option, old, new))
+                                .collect(),
+                        })
+                        .collect();
+                    (rename(&symbol), rules)
+                })
+                .collect();
+            grammar.weights = grammar
+                .weights
+                .drain()
+                .map(|(key, weights)| (rename(&key), weights))
+                .collect();
+            grammar.start_nonterminal = rename(&grammar.start_nonterminal);
+            grammar.post_processors = grammar
+                .post_processors
+                .drain()
+                .map(|(key, processor)| (rename(&key), processor))
+                .collect();
+            grammar.required = grammar.required.iter().map(|item| rename(item)).collect();
+            Ok(())
+        })
+    }
+
+    /// The options of the rule of 'key', which is a plain non-terminal or a
+    /// head with features.
+    fn options_mut(&mut self, key: &str) -> Result<&mut Vec<String>, EditError> {
+        if let Some(featured) = crate::features::parse_featured(key) {
+            let rules = self.featured_rules.get_mut(&featured.symbol);
+            if let Some(rule) =
+                rules.and_then(|rules| rules.iter_mut().find(|rule| rule.head == key))
+            {
+                return Ok(&mut rule.options);
+            }
+        }
+        self.rules
+            .get_mut(key)
+            .ok_or_else(|| EditError::NoSuchRule(String::from(key)))
+    }
+
+    /// Whether an option of any rule refers to 'symbol'.
+    fn uses_symbol(&self, symbol: &str) -> bool {
+        self.rules
+            .values()
+            .chain(
+                self.featured_rules
+                    .values()
+                    .flatten()
+                    .map(|rule| &rule.options),
+            )
+            .flatten()
+            .flat_map(|option| option.split_whitespace())
+            .any(|token| token::referenced_symbol(token).is_some_and(|used| used == symbol))
+    }
+
+    /// Applies 'change' and brings the derived state up to date. If the
+    /// change or the update fails, the grammar is restored.
+    fn edit<T, F>(&mut self, change: F) -> Result<T, EditError>
+    where
+        F: FnOnce(&mut Grammar) -> Result<T, EditError>,
+    {
+        let was_valid = self.validation_report().is_valid();
+        let snapshot = Snapshot {
+            rules: self.rules.clone(),
+            featured_rules: self.featured_rules.clone(),
+            weights: self.weights.clone(),
+            start_nonterminal: self.start_nonterminal.clone(),
+            post_processors: self.post_processors.clone(),
+            required: self.required.clone(),
+        };
+        let result = change(self).and_then(|value| {
+            self.refresh().map_err(EditError::Constraint)?;
+            if was_valid {
+                self.validate().map_err(EditError::Invalid)?;
+            }
+            Ok(value)
+        });
+        if result.is_err() {
+            self.rules = snapshot.rules;
+            self.featured_rules = snapshot.featured_rules;
+            self.weights = snapshot.weights;
+            self.start_nonterminal = snapshot.start_nonterminal;
+            self.post_processors = snapshot.post_processors;
+            self.required = snapshot.required;
+            // they held for these rules before the edit
+            self.refresh().ok();
+        }
+        result
+    }
+
+    /// Rebuilds the compiled rules, and the lengths and reachable items used
+    /// by the length constraint and required items.
+    fn refresh(&mut self) -> Result<(), String> {
+        self.recompile();
+        if let Some(constraint) = self.length_constraint {
+            self.set_length_constraint(constraint)?;
+        }
+        if !self.required.is_empty() {
+            let required = self.required.clone();
+            self.set_required(&required)?;
+        }
+        Ok(())
+    }
+}
+
+/// The trimmed option, if it can be an option of a rule.
+fn checked_option(option: &str) -> Result<String, EditError> {
+    let trimmed = option.trim();
+    match trimmed.is_empty() || trimmed.contains('|') {
+        true => Err(EditError::InvalidAlternative(String::from(option))),
+        false => Ok(String::from(trimmed)),
+    }
+}
+
+fn check_index(key: &str, options: &[String], index: usize) -> Result<(), EditError> {
+    match index < options.len() {
+        true => Ok(()),
+        false => Err(EditError::NoSuchAlternative {
+            key: String::from(key),
+            index,
+            len: options.len(),
+        }),
+    }
+}
+
+/// 'option' with 'old' renamed to 'new' in every token.
+fn rename_in_option(option: &str, old: &str, new: &str) -> String {
+    let tokens: Vec<Option<String>> = option
+        .split_whitespace()
+        .map(|token| rename_in_token(token, old, new))
+        .collect();
+    if tokens.iter().all(Option::is_none) {
+        return String::from(option);
+    }
+    option
+        .split_whitespace()
+        .zip(tokens)
+        .map(|(token, renamed)| renamed.unwrap_or_else(|| String::from(token)))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// 'token' with the non-terminal 'old' renamed to 'new', or None if it does
+/// not refer to 'old'.
+fn rename_in_token(token: &str, old: &str, new: &str) -> Option<String> {
+    match token::classify(token) {
+        Token::Symbol(symbol) if symbol == old => Some(String::from(new)),
+        Token::Symbol(symbol) => rename_in_call(symbol, old, new),
+        Token::Bind { name, value } => {
+            rename_in_token(value, old, new).map(|value| format!("[{}:{}]", name, value))
+        }
+        Token::Featured(featured) => {
+            let symbol = crate::features::parse_featured(featured)?.symbol;
+            if symbol != old {
+                return None;
+            }
+            let open = featured.find('[')?;
+            let close = featured.rfind(']')?;
+            Some(wrap(new, &featured[open..=close], &featured[close + 1..]))
+        }
+        Token::Modified { inner, modifiers } => {
+            rename_in_token(inner, old, new).map(|inner| format!("{}.{}", inner, modifiers))
+        }
+        Token::Variable(_) | Token::Join => None,
+    }
+}
+
+/// Renames the template and the arguments of a template instance such as
+/// `<list(<noun>)>`.
+fn rename_in_call(call: &str, old: &str, new: &str) -> Option<String> {
+    let (name, args) = template::parse_call(call)?;
+    let open = call.find('(')?;
+    let close = open + args.iter().map(|arg| arg.len() + 1).sum::<usize>();
+    let renamed: Vec<Option<String>> = args
+        .iter()
+        .map(|arg| rename_in_token(arg, old, new))
+        .collect();
+    if name != old && renamed.iter().all(Option::is_none) {
+        return None;
+    }
+    let args: Vec<String> = args
+        .iter()
+        .zip(renamed)
+        .map(|(arg, renamed)| renamed.unwrap_or_else(|| String::from(*arg)))
+        .collect();
+    let inner = format!("({})", args.join(","));
+    let suffix = &call[close + 1..];
+    match name == old {
+        true => Some(wrap(new, &inner, suffix)),
+        false => Some(format!("{}{}{}", &call[..open], inner, suffix)),
+    }
+}
+
+/// 'symbol' with 'inner' inserted before 'suffix', e.g. `<np[num=pl]>` for
+/// `<np>`, `[num=pl]` and `>`.
+fn wrap(symbol: &str, inner: &str, suffix: &str) -> String {
+    match symbol.strip_suffix(suffix) {
+        Some(prefix) if !suffix.is_empty() => format!("{}{}{}", prefix, inner, suffix),
+        _ => format!("{}{}", symbol, inner),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::length::{LengthConstraint, LengthUnit};
+
+    fn grammar() -> Grammar {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<s>");
+        grammar.rule_add(
+            "<s>",
+            "<pro> saw <np[num=$n]> | [hero:<pro>].upper met $hero",
+        );
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        grammar.rule_add("<np[num=sg]>", "a <noun>");
+        grammar.rule_add("<np[num=pl]>", "two <noun>.plural");
+        grammar.rule_add("<noun>", "dog");
+        grammar
+    }
+
+    #[test]
+    fn test_alternatives() {
+        let mut grammar = grammar();
+        grammar.add_alternative("<pro>", " Will ").unwrap();
+        grammar.move_alternative("<pro>", 0, 2).unwrap();
+        assert_eq!(grammar.rules()["<pro>"], vec!["Sarah", "Will", "Mike"]);
+        assert_eq!(
+            grammar.move_alternative("<pro>", 0, 3),
+            Err(EditError::NoSuchAlternative {
+                key: String::from("<pro>"),
+                index: 3,
+                len: 3
+            })
+        );
+        assert_eq!(
+            grammar.add_alternative("<pro>", "a | b"),
+            Err(EditError::InvalidAlternative(String::from("a | b")))
+        );
+        assert_eq!(
+            grammar.add_alternative("<verb>", "ran"),
+            Err(EditError::NoSuchRule(String::from("<verb>")))
+        );
+        grammar
+            .add_alternative("<np[num=pl]>", "the <noun>.plural")
+            .unwrap();
+        assert_eq!(
+            grammar.remove_alternative("<np[num=pl]>", 0),
+            Ok(String::from("two <noun>.plural"))
+        );
+        assert_eq!(
+            grammar.remove_alternative("<noun>", 0),
+            Err(EditError::EmptyRule(String::from("<noun>")))
+        );
+        // the compiled rules follow the edits
+        grammar.replace_rule("<pro>", &["Rachel"]).unwrap();
+        grammar.replace_rule("<s>", &["<pro> ran"]).unwrap();
+        assert_eq!(grammar.generate_sentence("<s>"), "Rachel ran.");
+    }
+    #[test]
+    fn test_weights_follow_the_options() {
+        let mut grammar = grammar();
+        assert_eq!(
+            grammar.set_weight("<pro>", 0, 0),
+            Err(EditError::InvalidWeight(0))
+        );
+        assert_eq!(
+            grammar.set_weight("<pro>", 0, MAX_WEIGHT + 1),
+            Err(EditError::InvalidWeight(MAX_WEIGHT + 1))
+        );
+        assert!(matches!(
+            grammar.set_weight("<pro>", 2, 2),
+            Err(EditError::NoSuchAlternative { .. })
+        ));
+        grammar.set_weight("<pro>", 0, MAX_WEIGHT).unwrap();
+        grammar.add_alternative("<pro>", "Will").unwrap();
+        grammar.move_alternative("<pro>", 0, 2).unwrap();
+        assert_eq!(grammar.weight("<pro>", 2), Some(MAX_WEIGHT));
+        grammar.remove_alternative("<pro>", 0).unwrap();
+        assert_eq!(grammar.weight("<pro>", 1), Some(MAX_WEIGHT));
+        grammar.rename_symbol("<pro>", "<name>").unwrap();
+        assert_eq!(grammar.weight("<name>", 1), Some(MAX_WEIGHT));
+        grammar.replace_rule("<name>", &["Mike", "Sarah"]).unwrap();
+        assert_eq!(grammar.weight("<name>", 1), Some(1));
+
+        grammar.set_weight("<np[num=pl]>", 0, 5).unwrap();
+        grammar.remove_rule("<np>").unwrap();
+        grammar.rule_add("<np[num=pl]>", "two <noun>.plural");
+        assert_eq!(grammar.weight("<np[num=pl]>", 0), Some(1));
+    }
+    #[test]
+    fn test_rules() {
+        let mut grammar = grammar();
+        let removed = grammar.remove_rule("<np>").unwrap();
+        let heads: Vec<&str> = removed.iter().map(|rule| rule.left_hand.as_str()).collect();
+        assert_eq!(heads, vec!["<np[num=sg]>", "<np[num=pl]>"]);
+        assert!(!grammar.has_rule("<np>"));
+        assert_eq!(
+            grammar.replace_rule("<pro>", &[] as &[&str]),
+            Err(EditError::EmptyRule(String::from("<pro>")))
+        );
+        assert_eq!(
+            grammar.replace_rule("<pro>", &["Will", "Jacob"]),
+            Ok(vec![String::from("Mike"), String::from("Sarah")])
+        );
+        // the only option that ends would loop forever
+        let err = grammar.replace_rule("<noun>", &["big <noun>"]).unwrap_err();
+        assert!(matches!(err, EditError::Invalid(_)), "{}", err);
+        assert_eq!(grammar.rules()["<noun>"], vec!["dog"]);
+    }
+    #[test]
+    fn test_constraints_are_kept() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<s>");
+        grammar.rule_add("<s>", "<pro> saw <noun>");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        grammar.rule_add("<noun>", "a dog | a wolf");
+        grammar.set_required(&["wolf"]).unwrap();
+        let constraint = LengthConstraint {
+            min: 3,
+            max: 5,
+            unit: LengthUnit::Words,
+        };
+        grammar.set_length_constraint(constraint).unwrap();
+        let err = grammar.remove_alternative("<noun>", 1).unwrap_err();
+        assert!(matches!(err, EditError::Constraint(_)), "{}", err);
+        let err = grammar.replace_rule("<pro>", &["the old man"]).unwrap_err();
+        assert!(matches!(err, EditError::Constraint(_)), "{}", err);
+        assert_eq!(grammar.rules()["<pro>"], vec!["Mike", "Sarah"]);
+        // the new option is within the length, and the lengths are updated
+        grammar.replace_rule("<pro>", &["Mike Smith"]).unwrap();
+        assert_eq!(grammar.generate_sentence("<s>"), "Mike Smith saw a wolf.");
+    }
+    #[test]
+    fn test_rename_symbol() {
+        let mut grammar = grammar();
+        grammar.rule_add("<list(<noun>)>", "<noun> | <noun> and <list(<noun>)>");
+        grammar.set_required(&["<noun>"]).unwrap();
+        grammar.set_post_processor("<s>", PostProcessor::default());
+        assert_eq!(
+            grammar.rename_symbol("<noun>", "dog"),
+            Err(EditError::SymbolExists(String::from("dog")))
+        );
+        assert_eq!(
+            grammar.rename_symbol("<noun>", "$x"),
+            Err(EditError::InvalidSymbol(String::from("$x")))
+        );
+        grammar.rename_symbol("<noun>", "<animal>").unwrap();
+        grammar.rename_symbol("<np>", "<phrase>").unwrap();
+        grammar.rename_symbol("<pro>", "<name>").unwrap();
+        grammar.rename_symbol("<s>", "<start>").unwrap();
+        assert_eq!(
+            grammar.rules()["<start>"],
+            vec![
+                "<name> saw <phrase[num=$n]>",
+                "[hero:<name>].upper met $hero"
+            ]
+        );
+        assert_eq!(
+            grammar.rules()["<list(<animal>)>"],
+            vec!["<animal>", "<animal> and <list(<animal>)>"]
+        );
+        let heads: Vec<&str> = grammar.featured_rules()["<phrase>"]
+            .iter()
+            .map(|rule| rule.head.as_str())
+            .collect();
+        assert_eq!(heads, vec!["<phrase[num=sg]>", "<phrase[num=pl]>"]);
+        assert_eq!(
+            grammar.featured_rules()["<phrase>"][1].options,
+            vec!["two <animal>.plural"]
+        );
+        assert_eq!(grammar.start_nonterminal, "<start>");
+        assert!(grammar.post_processors.contains_key("<start>"));
+        assert_eq!(grammar.required, vec!["<animal>"]);
+        assert!(grammar.validate().is_ok());
+    }
+    #[test]
+    fn test_rename_in_call() {
+        assert_eq!(
+            rename_in_token("<pair(<a>,<list(<a>)>)>.upper", "<a>", "<b>"),
+            Some(String::from("<pair(<b>,<list(<b>)>)>.upper"))
+        );
+        assert_eq!(
+            rename_in_token("<list(x)>", "<list>", "<items>"),
+            Some(String::from("<items(x)>"))
+        );
+        assert_eq!(rename_in_token("<list(x)>", "<a>", "<b>"), None);
+    }
+}
@@ -0,0 +1,575 @@
+//! This module contains grammatical features, which let non-terminals carry
+//! properties such as number or gender that must agree across a sentence.
+//! A rule declares its features on the left hand side, e.g.
+//! `<noun[num=pl]> = dogs | cats`, and a reference constrains them, e.g.
+//! `<noun[num=$n]>`. Feature variables such as `$n` are local to a single
+//! option and are unified during generation.
+use std::collections::{HashMap, HashSet};
+
+/// Combinations of feature values beyond this are not checked by the
+/// validator, to keep validation fast.
+const MAX_COMBINATIONS: usize = 10_000;
+
+/// The value of a feature, either a fixed value or a variable shared with
+/// the other features of the same option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum FeatureValue {
+    Value(String),
+    Variable(String),
+}
+
+/// A non-terminal with features, such as `<np[num=$n,person=3]>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Featured {
+    /// The non-terminal without its features, e.g. `<np>`.
+    pub symbol: String,
+    pub features: Vec<(String, FeatureValue)>,
+}
+
+/// The options of a rule whose left hand side has features.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FeaturedRule {
+    /// The left hand side as written, e.g. `<np[num=pl]>`.
+    pub head: String,
+    pub features: Vec<(String, FeatureValue)>,
+    pub options: Vec<String>,
+}
+
+/// Parses a non-terminal with features. Returns None if the token has no
+/// features.
+///
+/// # Example
+/// ```
+/// use story_gen::features::{parse_featured, FeatureValue};
+/// let featured = parse_featured("<verb[num=$n,tense=past]>").unwrap();
+/// assert_eq!(featured.symbol, "<verb>");
+/// assert_eq!(
+///     featured.features,
+///     vec![
+///         (String::from("num"), FeatureValue::Variable(String::from("n"))),
+///         (String::from("tense"), FeatureValue::Value(String::from("past"))),
+///     ]
+/// );
+/// ```
+pub fn parse_featured(token: &str) -> Option<Featured> {
+    let open = token.find('[')?;
+    let close = token.rfind(']')?;
+    if open == 0 || close < open {
+        return None;
+    }
+    let suffix = &token[close + 1..];
+    if suffix.contains(['[', ']']) {
+        return None;
+    }
+    let mut features = vec![];
+    for feature in token[open + 1..close].split(',') {
+        let (name, value) = feature.split_once('=')?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.is_empty() || value.is_empty() || value == "$" {
+            return None;
+        }
+        let value = match value.strip_prefix('$') {
+            Some(variable) => FeatureValue::Variable(String::from(variable)),
+            None => FeatureValue::Value(String::from(value)),
+        };
+        features.push((String::from(name), value));
+    }
+    Some(Featured {
+        symbol: format!("{}{}", &token[..open], suffix),
+        features,
+    })
+}
+
+/// A variable bound during unification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Binding {
+    Unbound,
+    Value(String),
+    /// Unified with another variable.
+    Same(usize),
+}
+
+/// Storage for the feature variables of a single sentence. Each variable is
+/// identified by its index.
+#[derive(Debug, Clone, Default)]
+pub struct FeatureStore {
+    bindings: Vec<Binding>,
+}
+
+impl FeatureStore {
+    /// Creates a new unbound variable.
+    pub fn fresh(&mut self) -> usize {
+        self.bindings.push(Binding::Unbound);
+        self.bindings.len() - 1
+    }
+
+    /// Creates a new variable bound to 'value'.
+    pub fn constant(&mut self, value: &str) -> usize {
+        self.bindings.push(Binding::Value(String::from(value)));
+        self.bindings.len() - 1
+    }
+
+    /// Follows unified variables to the variable that holds the value.
+    fn root(&self, mut id: usize) -> usize {
+        while let Binding::Same(next) = self.bindings[id] {
+            id = next;
+        }
+        id
+    }
+
+    /// Get the value of the variable, if it is bound.
+    pub fn value(&self, id: usize) -> Option<&str> {
+        match &self.bindings[self.root(id)] {
+            Binding::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Makes both variables hold the same value. Returns false, and leaves
+    /// the variables unchanged, when they are bound to different values.
+    pub fn unify(&mut self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.root(a), self.root(b));
+        if a == b {
+            return true;
+        }
+        match (&self.bindings[a], &self.bindings[b]) {
+            (Binding::Value(x), Binding::Value(y)) => x == y,
+            (Binding::Unbound, _) => {
+                self.bindings[a] = Binding::Same(b);
+                true
+            }
+            _ => {
+                self.bindings[b] = Binding::Same(a);
+                true
+            }
+        }
+    }
+
+    /// Binds the variable to 'value', unless it is bound to another value.
+    pub fn unify_value(&mut self, id: usize, value: &str) -> bool {
+        let root = self.root(id);
+        match &self.bindings[root] {
+            Binding::Value(existing) => existing == value,
+            _ => {
+                self.bindings[root] = Binding::Value(String::from(value));
+                true
+            }
+        }
+    }
+
+    /// Unifies the left hand side features of a rule with the constraints
+    /// of a reference, where each constraint is a feature name and variable.
+    /// Returns the rule's feature variables, or None if they do not unify.
+    pub fn unify_head(
+        &mut self,
+        head: &[(String, FeatureValue)],
+        constraints: &[(String, usize)],
+    ) -> Option<HashMap<String, usize>> {
+        let mut locals: HashMap<String, usize> = HashMap::new();
+        for (name, value) in head {
+            let constraint = constraints
+                .iter()
+                .find(|(constrained, _)| constrained == name)
+                .map(|(_, id)| *id);
+            let unified = match (value, constraint) {
+                (FeatureValue::Value(value), Some(id)) => self.unify_value(id, value),
+                (FeatureValue::Value(_), None) => true,
+                (FeatureValue::Variable(variable), constraint) => {
+                    let id = match constraint {
+                        Some(id) => id,
+                        None => self.fresh(),
+                    };
+                    match locals.get(variable) {
+                        Some(&existing) => self.unify(existing, id),
+                        None => {
+                            locals.insert(variable.clone(), id);
+                            true
+                        }
+                    }
+                }
+            };
+            if !unified {
+                return None;
+            }
+        }
+        Some(locals)
+    }
+}
+
+/// Finds the feature combinations that can not produce a sentence. Every
+/// non-terminal with features must be productive for every combination of
+/// the values its features take anywhere in the grammar. 'rules' are the
+/// rules without features, which accept any feature value.
+pub fn unproductive_combinations(
+    rules: &HashMap<String, Vec<String>>,
+    featured_rules: &HashMap<String, Vec<FeaturedRule>>,
+) -> Vec<String> {
+    if featured_rules.is_empty() {
+        return vec![];
+    }
+    let domains = feature_domains(rules, featured_rules);
+    // Every non-terminal with the combinations of its features to check.
+    let mut nodes: Vec<(String, Vec<(String, String)>)> = vec![];
+    for key in rules.keys() {
+        if !featured_rules.contains_key(key) {
+            nodes.push((key.clone(), vec![]));
+        }
+    }
+    for (symbol, heads) in featured_rules {
+        let mut names: Vec<&str> = heads
+            .iter()
+            .flat_map(|head| head.features.iter().map(|(name, _)| name.as_str()))
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        let mut combinations: Vec<Vec<(String, String)>> = vec![vec![]];
+        for name in names {
+            let values = &domains[name];
+            combinations = combinations
+                .iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut extended = combination.clone();
+                        extended.push((String::from(name), value.clone()));
+                        extended
+                    })
+                })
+                .take(MAX_COMBINATIONS)
+                .collect();
+        }
+        for combination in combinations {
+            nodes.push((symbol.clone(), combination));
+        }
+    }
+    let mut productive: HashSet<usize> = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, (symbol, combination)) in nodes.iter().enumerate() {
+            if productive.contains(&i) {
+                continue;
+            }
+            let is_productive =
+                candidates(symbol, rules, featured_rules)
+                    .into_iter()
+                    .any(|(head, option)| {
+                        option_is_productive(
+                            head,
+                            option,
+                            combination,
+                            &domains,
+                            &nodes,
+                            &productive,
+                            rules,
+                            featured_rules,
+                        )
+                    });
+            if is_productive {
+                productive.insert(i);
+                changed = true;
+            }
+        }
+    }
+    let mut unproductive: Vec<String> = nodes
+        .iter()
+        .enumerate()
+        .filter(|(i, (symbol, _))| !productive.contains(i) && featured_rules.contains_key(symbol))
+        .map(|(_, (symbol, combination))| format_combination(symbol, combination))
+        .collect();
+    unproductive.sort();
+    unproductive
+}
+
+/// Writes the non-terminal with the features of the combination, e.g.
+/// `<np[num=pl]>`.
+fn format_combination(symbol: &str, combination: &[(String, String)]) -> String {
+    let features: Vec<String> = combination
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    match symbol.strip_suffix('>') {
+        Some(stripped) => format!("{}[{}]>", stripped, features.join(",")),
+        None => format!("{}[{}]", symbol, features.join(",")),
+    }
+}
+
+/// All the options that can be chosen for 'symbol', with the left hand side
+/// features of the rule they belong to.
+pub fn candidates<'a>(
+    symbol: &str,
+    rules: &'a HashMap<String, Vec<String>>,
+    featured_rules: &'a HashMap<String, Vec<FeaturedRule>>,
+) -> Vec<(&'a [(String, FeatureValue)], &'a str)> {
+    let mut found: Vec<(&[(String, FeatureValue)], &str)> = vec![];
+    if let Some(options) = rules.get(symbol) {
+        found.extend(options.iter().map(|option| (&[][..], option.as_str())));
+    }
+    if let Some(heads) = featured_rules.get(symbol) {
+        for head in heads {
+            found.extend(
+                head.options
+                    .iter()
+                    .map(|option| (head.features.as_slice(), option.as_str())),
+            );
+        }
+    }
+    found
+}
+
+/// Every value each feature is given anywhere in the grammar.
+fn feature_domains(
+    rules: &HashMap<String, Vec<String>>,
+    featured_rules: &HashMap<String, Vec<FeaturedRule>>,
+) -> HashMap<String, Vec<String>> {
+    let mut domains: HashMap<String, Vec<String>> = HashMap::new();
+    let mut add = |features: &[(String, FeatureValue)]| {
+        for (name, value) in features {
+            let values = domains.entry(name.clone()).or_default();
+            if let FeatureValue::Value(value) = value {
+                if !values.contains(value) {
+                    values.push(value.clone());
+                }
+            }
+        }
+    };
+    let options = rules.values().flatten().chain(
+        featured_rules
+            .values()
+            .flatten()
+            .flat_map(|head| head.options.iter()),
+    );
+    for option in options {
+        for sub_option in option.split_whitespace() {
+            if let Some(featured) = parse_featured(referenced(sub_option)) {
+                add(&featured.features);
+            }
+        }
+    }
+    for head in featured_rules.values().flatten() {
+        add(&head.features);
+    }
+    for values in domains.values_mut() {
+        values.sort();
+    }
+    domains
+}
+
+/// The part of a token that references a non-terminal, looking inside
+/// variable bindings such as `[hero:<np[num=sg]>]`.
+fn referenced(token: &str) -> &str {
+    let token = crate::token::unmodified(token);
+    match crate::token::classify(token) {
+        crate::token::Token::Bind { value, .. } => crate::token::unmodified(value),
+        _ => token,
+    }
+}
+
+/// Whether the option can produce a sentence when the left hand side has
+/// the feature values of 'combination'. Variables that are not fixed by the
+/// left hand side may take any value of their feature.
+#[allow(clippy::too_many_arguments)]
+fn option_is_productive(
+    head: &[(String, FeatureValue)],
+    option: &str,
+    combination: &[(String, String)],
+    domains: &HashMap<String, Vec<String>>,
+    nodes: &[(String, Vec<(String, String)>)],
+    productive: &HashSet<usize>,
+    rules: &HashMap<String, Vec<String>>,
+    featured_rules: &HashMap<String, Vec<FeaturedRule>>,
+) -> bool {
+    let mut assignment: HashMap<&str, &str> = HashMap::new();
+    for (name, value) in head {
+        let fixed = combination
+            .iter()
+            .find(|(feature, _)| feature == name)
+            .map(|(_, value)| value.as_str());
+        match (value, fixed) {
+            (FeatureValue::Value(value), Some(fixed)) if value != fixed => return false,
+            (FeatureValue::Variable(variable), Some(fixed)) => {
+                match assignment.insert(variable, fixed) {
+                    Some(previous) if previous != fixed => return false,
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+    let references: Vec<Featured> = option
+        .split_whitespace()
+        .map(|sub_option| {
+            let sub_option = referenced(sub_option);
+            parse_featured(sub_option).unwrap_or_else(|| Featured {
+                symbol: String::from(sub_option),
+                features: vec![],
+            })
+        })
+        .collect();
+    // Variables of the option which are not fixed yet, with their values.
+    let mut free: Vec<(&str, &Vec<String>)> = vec![];
+    for reference in &references {
+        for (name, value) in &reference.features {
+            if let FeatureValue::Variable(variable) = value {
+                if !assignment.contains_key(variable.as_str())
+                    && !free.iter().any(|(free, _)| free == variable)
+                {
+                    free.push((variable, &domains[name]));
+                }
+            }
+        }
+    }
+    let mut choice = vec![0; free.len()];
+    loop {
+        let mut assigned = assignment.clone();
+        for ((variable, values), &i) in free.iter().zip(&choice) {
+            if let Some(value) = values.get(i) {
+                assigned.insert(variable, value);
+            }
+        }
+        let all_productive = references.iter().all(|reference| {
+            reference_is_productive(
+                reference,
+                &assigned,
+                nodes,
+                productive,
+                rules,
+                featured_rules,
+            )
+        });
+        if all_productive {
+            return true;
+        }
+        // move on to the next assignment of the free variables
+        let mut position = 0;
+        loop {
+            if position == choice.len() {
+                return false;
+            }
+            choice[position] += 1;
+            if choice[position] < free[position].1.len() {
+                break;
+            }
+            choice[position] = 0;
+            position += 1;
+        }
+    }
+}
+
+/// Whether a single component of an option can produce text.
+fn reference_is_productive(
+    reference: &Featured,
+    assigned: &HashMap<&str, &str>,
+    nodes: &[(String, Vec<(String, String)>)],
+    productive: &HashSet<usize>,
+    rules: &HashMap<String, Vec<String>>,
+    featured_rules: &HashMap<String, Vec<FeaturedRule>>,
+) -> bool {
+    if !rules.contains_key(&reference.symbol) && !featured_rules.contains_key(&reference.symbol) {
+        return true; // terminal
+    }
+    let required: Vec<(&str, Option<&str>)> = reference
+        .features
+        .iter()
+        .map(|(name, value)| match value {
+            FeatureValue::Value(value) => (name.as_str(), Some(value.as_str())),
+            FeatureValue::Variable(variable) => {
+                (name.as_str(), assigned.get(variable.as_str()).copied())
+            }
+        })
+        .collect();
+    nodes.iter().enumerate().any(|(i, (symbol, combination))| {
+        symbol == &reference.symbol
+            && productive.contains(&i)
+            && required.iter().all(|(name, value)| match value {
+                None => true,
+                Some(value) => combination
+                    .iter()
+                    .all(|(feature, fixed)| feature != name || fixed == value),
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn featured(head: &str, options: &[&str]) -> FeaturedRule {
+        FeaturedRule {
+            head: String::from(head),
+            features: parse_featured(head).unwrap().features,
+            options: options.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+
+    fn featured_rules(rules: Vec<FeaturedRule>) -> HashMap<String, Vec<FeaturedRule>> {
+        let mut map: HashMap<String, Vec<FeaturedRule>> = HashMap::new();
+        for rule in rules {
+            let symbol = parse_featured(&rule.head).unwrap().symbol;
+            map.entry(symbol).or_default().push(rule);
+        }
+        map
+    }
+
+    #[test]
+    fn test_parse_featured_rejects_plain_tokens() {
+        assert_eq!(parse_featured("<np>"), None);
+        assert_eq!(parse_featured("[hero:<pro>]"), None);
+        assert_eq!(parse_featured("<np[num]>"), None);
+    }
+    #[test]
+    fn test_unify() {
+        let mut store = FeatureStore::default();
+        let a = store.fresh();
+        let b = store.fresh();
+        assert!(store.unify(a, b));
+        assert!(store.unify_value(b, "pl"));
+        assert_eq!(store.value(a), Some("pl"));
+        assert!(!store.unify_value(a, "sg"));
+        let c = store.constant("sg");
+        assert!(!store.unify(a, c));
+    }
+    #[test]
+    fn test_unify_head() {
+        let mut store = FeatureStore::default();
+        let n = store.fresh();
+        let head = parse_featured("<np[num=$m,person=3]>").unwrap().features;
+        let locals = store
+            .unify_head(&head, &[(String::from("num"), n)])
+            .unwrap();
+        assert!(store.unify_value(locals["m"], "pl"));
+        assert_eq!(store.value(n), Some("pl"));
+        let first = store.constant("1");
+        assert_eq!(
+            store.unify_head(&head, &[(String::from("person"), first)]),
+            None
+        );
+    }
+    #[test]
+    fn test_productive_combinations() {
+        let rules: HashMap<String, Vec<String>> = vec![(
+            String::from("<s>"),
+            vec![String::from("<np[num=$n]> <verb[num=$n]>")],
+        )]
+        .into_iter()
+        .collect();
+        let mut heads = featured_rules(vec![
+            featured("<np[num=sg]>", &["Mike"]),
+            featured("<np[num=pl]>", &["the <noun[num=pl]>"]),
+            featured("<noun[num=pl]>", &["dogs"]),
+            featured("<verb[num=sg]>", &["runs"]),
+            featured("<verb[num=pl]>", &["run"]),
+        ]);
+        assert_eq!(
+            unproductive_combinations(&rules, &heads),
+            vec![String::from("<noun[num=sg]>")]
+        );
+        heads
+            .get_mut("<noun>")
+            .unwrap()
+            .push(featured("<noun[num=sg]>", &["dog"]));
+        assert!(unproductive_combinations(&rules, &heads).is_empty());
+    }
+}
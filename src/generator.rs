@@ -0,0 +1,137 @@
+//! This module contains the generator, which holds the state of a sequence
+//! of generated sentences: the random number generator, the variables and
+//! the recently chosen options. The grammar itself is never changed by
+//! generating, so one grammar can be shared, e.g. behind an `Arc`, by any
+//! number of generators on different threads.
+use crate::context::GenerationContext;
+use crate::grammar::Grammar;
+use rand::Rng;
+
+/// Generates sentences from a shared grammar. Every generator has its own
+/// random number generator and generation context.
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use story_gen::grammar::Grammar;
+/// use story_gen::random;
+///
+/// let mut grammar = Grammar::new();
+/// grammar.rule_add("<sentence>", "[hero:<pro>] saw $hero");
+/// grammar.rule_add("<pro>", "Mike | Sarah");
+/// let grammar = Arc::new(grammar);
+///
+/// let workers: Vec<_> = (0..4)
+///     .map(|seed| {
+///         let grammar = Arc::clone(&grammar);
+///         thread::spawn(move || {
+///             let mut generator = grammar.generator(random::seeded_rng(seed));
+///             generator.sentence("<sentence>")
+///         })
+///     })
+///     .collect();
+/// for worker in workers {
+///     let sentence = worker.join().unwrap();
+///     assert!(sentence == "Mike saw Mike." || sentence == "Sarah saw Sarah.");
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Generator<'a, R: Rng> {
+    grammar: &'a Grammar,
+    rng: R,
+    context: GenerationContext,
+}
+
+impl<'a, R: Rng> Generator<'a, R> {
+    /// A generator with a new generation context, see `Grammar::generator`.
+    pub fn new(grammar: &'a Grammar, rng: R) -> Self {
+        Generator {
+            grammar,
+            rng,
+            context: GenerationContext::default(),
+        }
+    }
+
+    /// Generate with 'context' instead of a new one, e.g. to change the
+    /// variable scope or repetition policy.
+    pub fn with_context(mut self, context: GenerationContext) -> Self {
+        self.context = context;
+        self
+    }
+
+    pub fn grammar(&self) -> &'a Grammar {
+        self.grammar
+    }
+
+    pub fn context(&self) -> &GenerationContext {
+        &self.context
+    }
+
+    /// The context, e.g. to start a new paragraph with
+    /// `GenerationContext::begin_paragraph`.
+    pub fn context_mut(&mut self) -> &mut GenerationContext {
+        &mut self.context
+    }
+
+    /// Generate the next sentence from the non-terminal 'key'. When no
+    /// attempt meets the grammar's constraints the last attempt is returned,
+    /// use `try_sentence` to get an error instead.
+    pub fn sentence(&mut self, key: &str) -> String {
+        self.grammar
+            .generate_sentence_with_context(key, &mut self.rng, &mut self.context)
+    }
+
+    /// Generate the next sentence from 'key', or an error when no attempt
+    /// meets the grammar's constraints, see
+    /// `Grammar::try_generate_sentence_with_context`.
+    pub fn try_sentence(&mut self, key: &str) -> Result<String, String> {
+        self.grammar
+            .try_generate_sentence_with_context(key, &mut self.rng, &mut self.context)
+    }
+
+    /// Generate the next sentence from 'key' with its derivation, see
+    /// `Grammar::generate_derivation_with_rng`.
+    pub fn derivation(&mut self, key: &str) -> (String, String) {
+        self.grammar
+            .generate_derivation_with_context(key, &mut self.rng, &mut self.context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::VariableScope;
+    use crate::random;
+
+    #[test]
+    fn test_grammar_is_shareable() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Grammar>();
+    }
+    #[test]
+    fn test_generators_keep_their_own_state() {
+        let mut grammar = Grammar::new();
+        grammar.rule_add("<sentence>", "[hero:<pro>]");
+        grammar.rule_add("<pro>", "Mike | Sarah | Will | Jacob | Rachel");
+        let run = GenerationContext::new(VariableScope::Run);
+        let mut first = grammar
+            .generator(random::seeded_rng(1))
+            .with_context(run.clone());
+        let mut second = grammar.generator(random::seeded_rng(2)).with_context(run);
+        let hero = first.sentence("<sentence>");
+        for _ in 0..5 {
+            second.sentence("<sentence>");
+            assert_eq!(first.sentence("<sentence>"), hero);
+        }
+        assert!(first.context().variable("hero").is_some());
+        // the same seed gives the same sentences as generating from the grammar
+        let mut rng = random::seeded_rng(1);
+        assert_eq!(
+            grammar.generate_sentence_with_rng("<sentence>", &mut rng),
+            grammar
+                .generator(random::seeded_rng(1))
+                .sentence("<sentence>")
+        );
+    }
+}
@@ -1,26 +1,76 @@
 //! This module contains all the grammar rule storage and generating
 //! random sentences.
 //! intiates the application.
-use crate::random;
+use crate::compiled::{CompiledGrammar, CompiledRule, Piece};
+use crate::context::GenerationContext;
+use crate::features::{self, FeatureStore, FeatureValue, FeaturedRule};
+use crate::generator::Generator;
+use crate::length::{self, LengthConstraint, LengthRange, LengthTable};
+use crate::modifiers::Modifiers;
+use crate::postprocess::PostProcessor;
+use crate::template;
+use crate::token::{self, Token};
+use crate::unique::UniqueFilter;
 use crate::*;
-use std::collections::HashMap;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::OnceLock;
 
 /// This struct is used to manage and store the grammar rules.
 /// This is the main structure the client will interface with.
 #[derive(Debug)]
 pub struct Grammar {
-    pub rules: HashMap<String, Vec<String>>,
-    pub validation: HashMap<String, i32>,
+    /// The rules without features. Read them with `rules`; they are only
+    /// changed through methods such as `rule_add` or `replace_rule`, which
+    /// keep the compiled rules up to date.
+    pub(crate) rules: HashMap<String, Vec<String>>,
     pub start_nonterminal: String,
-    pub validator: Validator,
+    /// Post-processing used for start symbols without their own entry in
+    /// 'post_processors'.
+    pub default_post_processor: PostProcessor,
+    pub post_processors: HashMap<String, PostProcessor>,
+    /// Rules whose LHS has features, e.g. `<np[num=pl]>`, stored under the
+    /// non-terminal without its features. See the features module.
+    pub(crate) featured_rules: HashMap<String, Vec<FeaturedRule>>,
+    /// The weights of the options of a rule, by plain non-terminal or head
+    /// with features. Options past the end of the list, and rules without
+    /// an entry, have a weight of 1. See `set_weight`.
+    pub(crate) weights: HashMap<String, Vec<u32>>,
+    /// Named transformations usable as `<noun>.plural`. See the modifiers
+    /// module.
+    pub modifiers: Modifiers,
+    /// Sentences are steered towards, and retried until they fit, this
+    /// length. See `set_length_constraint`.
+    pub length_constraint: Option<LengthConstraint>,
+    lengths: Option<LengthTable>,
+    /// Terminals or non-terminals every sentence must contain. See
+    /// `set_required`.
+    pub required: Vec<String>,
+    /// Every symbol that can appear in the expansion of each non-terminal.
+    reachable: HashMap<String, HashSet<String>>,
+    /// The rules in the form the generator expands, built when the first
+    /// sentence is generated. See `compiled`.
+    compiled: OnceLock<CompiledGrammar>,
 }
 impl Grammar {
     pub fn new() -> Grammar {
         Grammar {
             rules: Default::default(),
-            validation: Default::default(),
             start_nonterminal: Default::default(),
-            validator: Validator::new(),
+            default_post_processor: PostProcessor::default(),
+            post_processors: Default::default(),
+            featured_rules: Default::default(),
+            weights: HashMap::new(),
+            modifiers: Modifiers::new(),
+            length_constraint: None,
+            lengths: None,
+            required: vec![],
+            reachable: HashMap::new(),
+            compiled: OnceLock::new(),
         }
     }
     /// Add the rule with LHS non-terminal 'key' and the RHS non-terminals
@@ -34,16 +84,265 @@ impl Grammar {
             .collect();
         // get mutable access to value associated with key, guarding against the key
         // possibly not being set
-        let right_hand_side = self.rules.entry(key.to_string()).or_insert(vec![]);
+        self.recompile();
+        let right_hand_side = self.right_hand_side_mut(key);
         for option in parsed {
             right_hand_side.push(option);
         }
     }
 
+    /// Get the options of the rule with LHS 'key', creating the rule if it
+    /// does not exist. Rules with features are stored separately.
+    fn right_hand_side_mut(&mut self, key: &str) -> &mut Vec<String> {
+        match features::parse_featured(key) {
+            Some(featured) => {
+                let heads = self.featured_rules.entry(featured.symbol).or_default();
+                let position = match heads.iter().position(|head| head.head == key) {
+                    Some(position) => position,
+                    None => {
+                        heads.push(FeaturedRule {
+                            head: String::from(key),
+                            features: featured.features,
+                            options: vec![],
+                        });
+                        heads.len() - 1
+                    }
+                };
+                &mut heads[position].options
+            }
+            None => self.rules.entry(key.to_string()).or_default(),
+        }
+    }
+
+    /// The options of every rule without features, by non-terminal.
+    ///
+    /// ```
+    /// use story_gen::grammar::Grammar;
+    ///
+    /// let mut grammar = Grammar::new();
+    /// grammar.rule_add("<pro>", "Mike");
+    /// assert_eq!(grammar.rules()["<pro>"], vec!["Mike"]);
+    /// ```
+    pub fn rules(&self) -> &HashMap<String, Vec<String>> {
+        &self.rules
+    }
+
+    /// The rules with features, stored under the non-terminal without its
+    /// features.
+    pub fn featured_rules(&self) -> &HashMap<String, Vec<FeaturedRule>> {
+        &self.featured_rules
+    }
+
+    /// The weight of the option at 'index' of the rule of 'key', which is a
+    /// plain non-terminal or a head with features, or None if there is no
+    /// such option. See `set_weight`.
+    pub fn weight(&self, key: &str, index: usize) -> Option<u32> {
+        let options = match crate::features::parse_featured(key) {
+            Some(featured) => self
+                .featured_rules
+                .get(&featured.symbol)?
+                .iter()
+                .find(|rule| rule.head == key)
+                .map(|rule| &rule.options),
+            None => self.rules.get(key),
+        }?;
+        match index < options.len() {
+            true => Some(option_weight(self.weights.get(key), index)),
+            false => None,
+        }
+    }
+
+    /// The weights of the options 'features::candidates' returns for
+    /// 'symbol', or None when no rule of 'symbol' has weights.
+    fn candidate_weights(&self, symbol: &str) -> Option<Vec<u32>> {
+        let heads = self.featured_rules.get(symbol).into_iter().flatten();
+        let weighted = self.weights.contains_key(symbol)
+            || heads
+                .clone()
+                .any(|rule| self.weights.contains_key(&rule.head));
+        if !weighted {
+            return None;
+        }
+        let mut weights = vec![];
+        if let Some(options) = self.rules.get(symbol) {
+            let rule_weights = self.weights.get(symbol);
+            weights.extend((0..options.len()).map(|i| option_weight(rule_weights, i)));
+        }
+        for rule in heads {
+            let rule_weights = self.weights.get(&rule.head);
+            weights.extend((0..rule.options.len()).map(|i| option_weight(rule_weights, i)));
+        }
+        Some(weights)
+    }
+
+    /// Forgets the weights of the rule of 'key' and of its heads with
+    /// features, before the rule is removed.
+    pub(crate) fn forget_weights(&mut self, key: &str) {
+        self.weights.remove(key);
+        for rule in self.featured_rules.get(key).into_iter().flatten() {
+            self.weights.remove(&rule.head);
+        }
+    }
+
+    /// Whether there is a rule for the non-terminal 'key', with or without
+    /// features.
+    pub fn has_rule(&self, key: &str) -> bool {
+        self.rules.contains_key(key) || self.featured_rules.contains_key(key)
+    }
+
+    /// The rules in a compact form with interned symbols and tokenized
+    /// options. It is built once and kept until the rules change. Sentences
+    /// are only expanded from it where no features, length constraint,
+    /// required items or cooldown apply, see the compiled module.
+    pub fn compiled(&self) -> &CompiledGrammar {
+        self.compiled
+            .get_or_init(|| CompiledGrammar::new(&self.rules, &self.featured_rules, &self.weights))
+    }
+
+    /// Forgets the compiled rules, so they are built again from the current
+    /// rules. Every change to 'rules' or 'featured_rules' must be followed
+    /// by this.
+    pub(crate) fn recompile(&mut self) {
+        self.compiled = OnceLock::new();
+    }
+
     pub fn change_start_nonterminal(&mut self, new_value: &str) {
         self.start_nonterminal = String::from(new_value);
     }
 
+    /// Make the modifier 'name' available to the grammar, replacing the
+    /// built-in modifier with the same name if there is one.
+    pub fn register_modifier<F>(&mut self, name: &str, modifier: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.modifiers.register(name, modifier);
+    }
+
+    /// Only generate sentences whose length is within 'constraint'. The
+    /// possible lengths of every non-terminal are computed now, so this must
+    /// be called after all rules are added, and only sentences generated from
+    /// the start non-terminal are constrained. Errors when the start
+    /// non-terminal can't produce a sentence of that length, including when
+    /// the length falls between the lengths of its options. Lengths are
+    /// measured before post-processing, see `LengthConstraint::distance`.
+    ///
+    /// # Example
+    /// ```
+    /// use story_gen::grammar::Grammar;
+    /// use story_gen::length::{LengthConstraint, LengthUnit};
+    ///
+    /// let mut grammar = Grammar::new();
+    /// grammar.change_start_nonterminal("<sentence>");
+    /// grammar.rule_add("<sentence>", "<pro> ran | <pro> ran and <sentence>");
+    /// grammar.rule_add("<pro>", "Mike | Sarah");
+    /// let constraint = LengthConstraint { min: 4, max: 6, unit: LengthUnit::Words };
+    /// grammar.set_length_constraint(constraint).unwrap();
+    /// // "Mike ran and Sarah ran."
+    /// assert_eq!(grammar.generate_sentence("<sentence>").split(' ').count(), 5);
+    ///
+    /// let constraint = LengthConstraint { min: 0, max: 1, unit: LengthUnit::Words };
+    /// assert!(grammar.set_length_constraint(constraint).is_err());
+    /// // sentences have 2, 5, 8... words
+    /// let constraint = LengthConstraint { min: 3, max: 4, unit: LengthUnit::Words };
+    /// assert!(grammar.set_length_constraint(constraint).is_err());
+    /// ```
+    pub fn set_length_constraint(&mut self, constraint: LengthConstraint) -> Result<(), String> {
+        let rules = flatten_rules(&self.rules, &self.featured_rules);
+        let lengths = LengthTable::new(&rules, constraint.unit);
+        constraint.check(
+            &self.start_nonterminal,
+            lengths.range(&self.start_nonterminal),
+        )?;
+        if constraint.max <= length::MAX_EXACT_LENGTH {
+            let possible = length::possible_lengths(
+                &rules,
+                constraint.unit,
+                &self.start_nonterminal,
+                constraint.max,
+            );
+            constraint.check_possible(&self.start_nonterminal, &possible)?;
+        }
+        self.length_constraint = Some(constraint);
+        self.lengths = Some(lengths);
+        Ok(())
+    }
+
+    /// Only generate sentences that contain every item, where an item is a
+    /// terminal (e.g. "wolf") or a non-terminal the sentence must pass
+    /// through. Only options from which the remaining items can still be
+    /// reached are chosen. Must be called after all rules are added, and only
+    /// applies to sentences generated from the start non-terminal. Errors
+    /// when the start non-terminal can't reach an item.
+    ///
+    /// # Example
+    /// ```
+    /// use story_gen::grammar::Grammar;
+    ///
+    /// let mut grammar = Grammar::new();
+    /// grammar.change_start_nonterminal("<sentence>");
+    /// grammar.rule_add("<sentence>", "<pro> saw <noun>");
+    /// grammar.rule_add("<pro>", "Mike | Sarah");
+    /// grammar.rule_add("<noun>", "a dog | a cat | a wolf");
+    /// grammar.set_required(&["wolf", "Sarah"]).unwrap();
+    /// assert_eq!(grammar.generate_sentence("<sentence>"), "Sarah saw a wolf.");
+    /// assert!(grammar.set_required(&["owl"]).is_err());
+    /// ```
+    pub fn set_required<S: AsRef<str>>(&mut self, items: &[S]) -> Result<(), String> {
+        let rules = flatten_rules(&self.rules, &self.featured_rules);
+        let reachable = Validator::new().reachable_items(&rules);
+        let start = &self.start_nonterminal;
+        let missing: Vec<&str> = items
+            .iter()
+            .map(|item| item.as_ref())
+            .filter(|item| {
+                !reachable
+                    .get(start)
+                    .is_some_and(|items| items.contains(*item))
+            })
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "'{}' can't reach the required items: {}",
+                start,
+                missing.join(", ")
+            ));
+        }
+        self.required = items
+            .iter()
+            .map(|item| String::from(item.as_ref()))
+            .collect();
+        self.reachable = reachable;
+        Ok(())
+    }
+
+    /// Whether the expansion of 'token' can contain 'item'.
+    fn token_reaches(&self, token: &str, item: &str) -> bool {
+        match token::referenced_symbol(token) {
+            Some(symbol) => {
+                symbol == item
+                    || self
+                        .reachable
+                        .get(symbol.as_ref())
+                        .is_some_and(|items| items.contains(item))
+            }
+            None => false,
+        }
+    }
+
+    /// Use 'processor' to finish the sentences generated from the start
+    /// symbol 'key'.
+    pub fn set_post_processor(&mut self, key: &str, processor: PostProcessor) {
+        self.post_processors.insert(String::from(key), processor);
+    }
+
+    /// Get the post-processing pipeline used for sentences generated from 'key'.
+    pub fn post_processor(&self, key: &str) -> &PostProcessor {
+        self.post_processors
+            .get(key)
+            .unwrap_or(&self.default_post_processor)
+    }
+
     /// Add a rule struct into the existing grammar rules. If no matching LHS
     /// currently matches, it will create a new grammar rule. If a matching LHS
     /// already exists, it will extend the options of the existing respective RHS
@@ -53,15 +352,47 @@ impl Grammar {
     //   key.extend(rule.right_hand);
     // }
     pub fn rule_add_from_file(&mut self, rule: Rule) {
-        let key = self.rules.entry(rule.left_hand).or_insert(vec![]);
+        self.recompile();
+        let key = self.right_hand_side_mut(&rule.left_hand);
         key.extend(rule.right_hand);
     }
 
+    /// Replaces the parameterized rules, e.g. `<list(X)> = X | X , <list(X)>`,
+    /// with a regular rule for every use such as `<list(<noun>)>`. Must be
+    /// called after all rules are added. See the template module.
+    pub fn instantiate_templates(&mut self) -> Result<(), String> {
+        self.recompile();
+        template::instantiate(&mut self.rules, &self.featured_rules)?;
+        let (rules, featured_rules) = (&self.rules, &self.featured_rules);
+        self.weights.retain(|key, _| {
+            rules.contains_key(key)
+                || featured_rules
+                    .values()
+                    .flatten()
+                    .any(|rule| rule.head == *key)
+        });
+        Ok(())
+    }
+
+    /// Remove the rule with LHS non-terminal 'key', including every variant
+    /// with features. Returns whether the rule existed. Unlike `remove_rule`,
+    /// the grammar is not checked after the rule is removed.
+    pub fn rule_remove(&mut self, key: &str) -> bool {
+        self.recompile();
+        self.forget_weights(key);
+        let featured = self.featured_rules.remove(key).is_some();
+        self.rules.remove(key).is_some() || featured
+    }
+
     /// Delete the rule with LHS non-terminal 'key'. Prints a success or error
     /// message based on whether the rule existed before the deletion.
-    /// Could be used in future for interactive console deletion.
+    #[deprecated(note = "use remove_rule")]
     pub fn rule_delete(&mut self, key: &str) {
-        if let Some(value_removed) = self.rules.remove(key) {
+        self.recompile();
+        self.forget_weights(key);
+        if let Some(value_removed) = self.featured_rules.remove(key) {
+            println!("Removed key: {} , value: {:#?}", key, value_removed)
+        } else if let Some(value_removed) = self.rules.remove(key) {
             println!("Removed key: {} , value: {:#?}", key, value_removed)
         } else {
             println!("Error. Could not find rule with key: '{}'", key)
@@ -69,57 +400,1106 @@ impl Grammar {
     }
 
     /// Generate random sentences starting from LHS non-termianal 'key'
-    pub fn generate_sentences(&self, key: &str, count: i32) -> Vec<String> {
+    pub fn generate_sentences(&self, key: &str, count: usize) -> Vec<String> {
+        let mut rng = rand::thread_rng();
+        self.generate_sentences_with_rng(key, count, &mut rng)
+    }
+
+    /// Generate random sentences using the provided random number generator.
+    /// Using a seeded generator makes the output reproducible.
+    pub fn generate_sentences_with_rng<R: Rng + ?Sized>(
+        &self,
+        key: &str,
+        count: usize,
+        rng: &mut R,
+    ) -> Vec<String> {
+        self.generate_sentences_with_context(key, count, rng, &mut GenerationContext::default())
+    }
+
+    /// Generate random sentences that share 'context', so its repetition
+    /// policy keeps recently chosen options out of the following sentences,
+    /// and its variables are shared according to its variable scope.
+    ///
+    /// # Example
+    /// ```
+    /// use story_gen::context::GenerationContext;
+    /// use story_gen::grammar::Grammar;
+    /// use story_gen::random;
+    ///
+    /// let mut grammar = Grammar::new();
+    /// grammar.rule_add("<sentence>", "<pro> ran");
+    /// grammar.rule_add("<pro>", "Mike | Sarah");
+    /// let mut context = GenerationContext::default();
+    /// context.repetition.window = 1;
+    /// let mut rng = random::seeded_rng(3);
+    /// let sentences = grammar.generate_sentences_with_context("<sentence>", 4, &mut rng, &mut context);
+    /// assert_ne!(sentences[0], sentences[1]);
+    /// assert_eq!(sentences[0], sentences[2]);
+    /// ```
+    pub fn generate_sentences_with_context<R: Rng + ?Sized>(
+        &self,
+        key: &str,
+        count: usize,
+        rng: &mut R,
+        context: &mut GenerationContext,
+    ) -> Vec<String> {
+        (0..count)
+            .map(|_| self.generate_sentence_with_context(key, rng, context))
+            .collect()
+    }
+
+    /// An endless iterator of sentences generated from 'key', which share a
+    /// generation context. Nothing is generated until a sentence is asked
+    /// for, so any number of sentences can be processed one at a time. A
+    /// sentence for which no attempt met the constraints is returned like
+    /// the others, use `try_sentences` to get an error instead.
+    ///
+    /// # Example
+    /// ```
+    /// use story_gen::grammar::Grammar;
+    /// use story_gen::random;
+    ///
+    /// let mut grammar = Grammar::new();
+    /// grammar.rule_add("<sentence>", "<pro> ran");
+    /// grammar.rule_add("<pro>", "Mike | Sarah");
+    /// let mut rng = random::seeded_rng(3);
+    /// let long = grammar
+    ///     .sentences("<sentence>", &mut rng)
+    ///     .take(100_000)
+    ///     .filter(|sentence| sentence.starts_with("Mike"))
+    ///     .count();
+    /// assert!(long > 40_000);
+    /// ```
+    pub fn sentences<'a, R: Rng + ?Sized>(
+        &'a self,
+        key: &'a str,
+        rng: &'a mut R,
+    ) -> Sentences<'a, R> {
+        Sentences {
+            generator: self.generator(rng),
+            key,
+        }
+    }
+
+    /// Like `sentences`, but every item is an error when no attempt agreed
+    /// on features, contained the required items and fit the length, see
+    /// `try_generate_sentence_with_context`.
+    ///
+    /// # Example
+    /// ```
+    /// use story_gen::grammar::Grammar;
+    /// use story_gen::random;
+    ///
+    /// let mut grammar = Grammar::new();
+    /// grammar.rule_add("<sentence>", "<pro> ran");
+    /// grammar.rule_add("<pro>", "Mike | Sarah");
+    /// // there is no plural <np>, so its features are never agreed on
+    /// grammar.rule_add("<plural>", "<np[num=pl]> ran");
+    /// grammar.rule_add("<np[num=sg]>", "a dog");
+    /// let mut rng = random::seeded_rng(3);
+    /// let sentences: Result<Vec<String>, String> =
+    ///     grammar.try_sentences("<sentence>", &mut rng).take(10).collect();
+    /// assert_eq!(sentences.unwrap().len(), 10);
+    /// let mut plural = grammar.try_sentences("<plural>", &mut rng);
+    /// assert!(plural.next().unwrap().is_err());
+    /// ```
+    pub fn try_sentences<'a, R: Rng + ?Sized>(
+        &'a self,
+        key: &'a str,
+        rng: &'a mut R,
+    ) -> TrySentences<'a, R> {
+        TrySentences {
+            generator: self.generator(rng),
+            key,
+        }
+    }
+
+    /// A generator that owns 'rng' and its own generation context. Any
+    /// number of generators can share the grammar.
+    pub fn generator<R: Rng>(&self, rng: R) -> Generator<'_, R> {
+        Generator::new(self, rng)
+    }
+
+    /// Generate up to 'count' distinct sentences. Fewer sentences are
+    /// returned when the grammar runs out of new sentences, see UniqueFilter.
+    pub fn generate_unique_sentences_with_rng<R: Rng + ?Sized>(
+        &self,
+        key: &str,
+        count: usize,
+        rng: &mut R,
+    ) -> Vec<String> {
+        let mut context = GenerationContext::default();
+        let mut filter = UniqueFilter::new(self.language_size(key));
         let mut sentences = vec![];
-        for _ in 0..count {
-            sentences.push(self.generate_sentence(key));
+        while sentences.len() < count && !filter.is_exhausted() {
+            let sentence = self.generate_sentence_with_context(key, rng, &mut context);
+            if filter.accept(&sentence) {
+                sentences.push(sentence);
+            }
         }
         sentences
     }
 
+    /// An upper bound on the number of distinct sentences of 'key', found by
+    /// counting the ways to expand it. Returns None for recursive grammars,
+    /// whose language may be infinite, or when the count overflows.
+    ///
+    /// # Example
+    /// ```
+    /// use story_gen::grammar::Grammar;
+    ///
+    /// let mut grammar = Grammar::new();
+    /// grammar.rule_add("<sentence>", "<pro> <verb> | the end");
+    /// grammar.rule_add("<pro>", "Mike | Sarah");
+    /// grammar.rule_add("<verb>", "ran | sat | slept");
+    /// assert_eq!(grammar.language_size("<sentence>"), Some(7));
+    /// grammar.rule_add("<verb>", "<verb> and <verb>");
+    /// assert_eq!(grammar.language_size("<sentence>"), None);
+    /// ```
+    pub fn language_size(&self, key: &str) -> Option<u64> {
+        let rules = flatten_rules(&self.rules, &self.featured_rules);
+        count_expansions(key, &rules, &mut HashMap::new(), &mut HashSet::new())
+    }
+
     /// Generate a single random sentence from provided non-terminal.
     pub fn generate_sentence(&self, key: &str) -> String {
-        let unformatted_sentence = self.build_random(key);
-        let mut trimmed_sentence = String::from(unformatted_sentence.trim());
-        trimmed_sentence.push_str(".");
-        // capitalize first letter in the sentence.
-        let mut c = trimmed_sentence.chars();
-        match c.next() {
-            None => String::new(),
-            Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
+        let mut rng = rand::thread_rng();
+        self.generate_sentence_with_rng(key, &mut rng)
+    }
+
+    /// Generate a single random sentence using the provided random number
+    /// generator. The sentence is finished by the post-processor of 'key'.
+    pub fn generate_sentence_with_rng<R: Rng + ?Sized>(&self, key: &str, rng: &mut R) -> String {
+        self.generate_sentence_with_context(key, rng, &mut GenerationContext::default())
+    }
+
+    /// Generate a single random sentence that shares variables with the
+    /// other sentences generated with the same 'context'. If no attempt
+    /// agrees on features and contains the required items, the last attempt
+    /// is returned, and if none fits the length constraint, the closest one,
+    /// see `try_generate_sentence_with_context`.
+    pub fn generate_sentence_with_context<R: Rng + ?Sized>(
+        &self,
+        key: &str,
+        rng: &mut R,
+        context: &mut GenerationContext,
+    ) -> String {
+        let raw_sentence = self
+            .expand_sentence(key, rng, context, None)
+            .unwrap_or_else(|failed| failed.sentence);
+        self.post_processor(key).apply(&raw_sentence)
+    }
+
+    /// Same as `generate_sentence_with_context`, but errors when no attempt
+    /// agrees on features, contains the required items and fits the length
+    /// constraint, instead of returning a sentence that doesn't.
+    ///
+    /// # Example
+    /// ```
+    /// use story_gen::context::GenerationContext;
+    /// use story_gen::grammar::Grammar;
+    /// use story_gen::length::{LengthConstraint, LengthUnit};
+    /// use story_gen::random;
+    ///
+    /// let mut grammar = Grammar::new();
+    /// grammar.change_start_nonterminal("<s>");
+    /// grammar.rule_add("<s>", "<pro> ran | <pro> saw the big wolf");
+    /// grammar.rule_add("<pro>", "Mike | Sarah");
+    /// grammar.set_required(&["wolf"]).unwrap();
+    /// let constraint = LengthConstraint { min: 0, max: 3, unit: LengthUnit::Words };
+    /// grammar.set_length_constraint(constraint).unwrap();
+    /// let mut rng = random::seeded_rng(1);
+    /// let mut context = GenerationContext::default();
+    /// assert!(grammar
+    ///     .try_generate_sentence_with_context("<s>", &mut rng, &mut context)
+    ///     .is_err());
+    /// ```
+    pub fn try_generate_sentence_with_context<R: Rng + ?Sized>(
+        &self,
+        key: &str,
+        rng: &mut R,
+        context: &mut GenerationContext,
+    ) -> Result<String, String> {
+        match self.expand_sentence(key, rng, context, None) {
+            Ok(raw_sentence) => Ok(self.post_processor(key).apply(&raw_sentence)),
+            Err(failed) => Err(failed.error),
         }
     }
+
+    /// Generate a single random sentence along with its derivation, which is
+    /// written in bracket notation, e.g. `[<np> [<pro> Mike]]`. The same
+    /// random number generator state produces the same sentence as
+    /// `generate_sentence_with_rng`.
+    pub fn generate_derivation_with_rng<R: Rng + ?Sized>(
+        &self,
+        key: &str,
+        rng: &mut R,
+    ) -> (String, String) {
+        self.generate_derivation_with_context(key, rng, &mut GenerationContext::default())
+    }
+
+    /// Same as `generate_derivation_with_rng`, but shares variables with the
+    /// other sentences generated with the same 'context'.
+    pub fn generate_derivation_with_context<R: Rng + ?Sized>(
+        &self,
+        key: &str,
+        rng: &mut R,
+        context: &mut GenerationContext,
+    ) -> (String, String) {
+        let mut derivation = String::new();
+        let raw_sentence = self
+            .expand_sentence(key, rng, context, Some(&mut derivation))
+            .unwrap_or_else(|failed| failed.sentence);
+        let sentence = self.post_processor(key).apply(&raw_sentence);
+        (sentence, derivation)
+    }
+
+    /// Same as `generate_derivation_with_context`, but errors like
+    /// `try_generate_sentence_with_context`.
+    pub fn try_generate_derivation_with_context<R: Rng + ?Sized>(
+        &self,
+        key: &str,
+        rng: &mut R,
+        context: &mut GenerationContext,
+    ) -> Result<(String, String), String> {
+        let mut derivation = String::new();
+        match self.expand_sentence(key, rng, context, Some(&mut derivation)) {
+            Ok(raw_sentence) => Ok((self.post_processor(key).apply(&raw_sentence), derivation)),
+            Err(failed) => Err(failed.error),
+        }
+    }
+
     /// recursive call, if key doesn't exist, it must be a token, so return
     /// that string, otherwise evaulate RHS
     pub fn build_random(&self, key: &str) -> String {
-        if let Some(options) = self.rules.get(key) {
-            let random_choice = random::get_random_from_vector(options);
-            let sub_choices = parse_subunits(&random_choice);
-            let mut built_sentence = String::new();
-            for token in sub_choices {
-                built_sentence.push_str(&self.build_random(&token));
-            }
-            built_sentence
-        } else {
-            format!(" {}", key)
+        let mut rng = rand::thread_rng();
+        self.expand_sentence(key, &mut rng, &mut GenerationContext::default(), None)
+            .unwrap_or_else(|failed| failed.sentence)
+    }
+
+    /// Expands 'key' into an unformatted sentence. When features can not be
+    /// agreed on, or the sentence does not fit the length constraint, the
+    /// sentence is started over, up to MAX_ATTEMPTS times. If no attempt
+    /// fits the length, the closest one, with its derivation and variables,
+    /// is returned as a failure. If no attempt agrees on features and
+    /// contains the required items, the last attempt is.
+    fn expand_sentence<R: Rng + ?Sized>(
+        &self,
+        key: &str,
+        rng: &mut R,
+        context: &mut GenerationContext,
+        mut trace: Option<&mut String>,
+    ) -> Result<String, Failed> {
+        context.begin_sentence();
+        let trace_start = trace.as_ref().map_or(0, |trace| trace.len());
+        // other symbols, such as titles, are generated without constraints
+        let constrained = key == self.start_nonterminal;
+        let constraint = self.length_constraint.as_ref().filter(|_| constrained);
+        let required: &[String] = match constrained {
+            true => &self.required,
+            false => &[],
+        };
+        let variables =
+            match self.featured_rules.is_empty() && constraint.is_none() && required.is_empty() {
+                true => None,
+                false => Some(context.clone()),
+            };
+        // the closest attempt to the length: (distance, sentence, derivation, context)
+        let mut closest: Option<(usize, String, String, GenerationContext)> = None;
+        let mut built_sentence = String::new();
+        let mut error = String::new();
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut expansion = Expansion {
+                grammar: self,
+                compiled: self.compiled(),
+                rng: &mut *rng,
+                context: &mut *context,
+                trace: trace.as_deref_mut(),
+                features: FeatureStore::default(),
+                failed: false,
+                lengths: self.lengths.as_ref(),
+                reached: HashSet::new(),
+            };
+            let goal = Goal {
+                budget: constraint.map(|constraint| (constraint.min, constraint.max)),
+                required: required.iter().map(|item| item.as_str()).collect(),
+            };
+            built_sentence = expansion.expand(key, &[], goal);
+            let missing: Vec<&str> = required
+                .iter()
+                .filter(|item| !expansion.reached.contains(*item))
+                .map(|item| item.as_str())
+                .collect();
+            if expansion.failed {
+                error = format!(
+                    "the features of '{}' could not be agreed on in {} attempts",
+                    key, MAX_ATTEMPTS
+                );
+            } else if !missing.is_empty() {
+                error = format!(
+                    "no sentence of '{}' contained the required items in {} attempts, missing: {}",
+                    key,
+                    MAX_ATTEMPTS,
+                    missing.join(", ")
+                );
+            } else {
+                // measured like the length table, before post-processing
+                let distance = match constraint {
+                    Some(constraint) => constraint.distance(&built_sentence),
+                    None => 0,
+                };
+                if distance == 0 {
+                    return Ok(built_sentence);
+                }
+                if closest.as_ref().is_none_or(|closest| distance < closest.0) {
+                    let derivation = match trace.as_deref() {
+                        Some(trace) => String::from(&trace[trace_start..]),
+                        None => String::new(),
+                    };
+                    closest = Some((
+                        distance,
+                        built_sentence.clone(),
+                        derivation,
+                        context.clone(),
+                    ));
+                }
+            }
+            // a failed last attempt is kept to be reported
+            if attempt == MAX_ATTEMPTS && closest.is_none() {
+                break;
+            }
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.truncate(trace_start);
+            }
+            if let Some(variables) = &variables {
+                *context = variables.clone();
+            }
+        }
+        match closest {
+            Some((_, sentence, derivation, closest_context)) => {
+                if let Some(trace) = trace {
+                    trace.push_str(&derivation);
+                }
+                *context = closest_context;
+                Err(Failed {
+                    error: format!(
+                        "no sentence of '{}' fit the length constraint in {} attempts",
+                        key, MAX_ATTEMPTS
+                    ),
+                    sentence,
+                })
+            }
+            None => Err(Failed {
+                error,
+                sentence: built_sentence,
+            }),
         }
     }
+
     /// Interface function to get LHS tokens which are not reachable.
-    pub fn get_unreachable_nonterminals(&mut self) -> Vec<String> {
-        self.validator
-            .get_unreachable_nonterminals(&self.rules, &self.start_nonterminal)
+    pub fn get_unreachable_nonterminals(&self) -> Vec<String> {
+        self.get_unreachable_from(&self.start_nonterminal)
+    }
+
+    /// Get the LHS tokens which are not reachable from 'key'.
+    pub fn get_unreachable_from(&self, key: &str) -> Vec<String> {
+        let rules = flatten_rules(&self.rules, &self.featured_rules);
+        Validator::new().unreachable_from(&rules, key)
     }
 
-    /// Validates the grammer rules have at least 1 valid path. See Validator
-    /// struct for more detailed info on validation procedure.
-    pub fn validate(&mut self) -> Result<(), String> {
-        self.validator.validate(&self.rules)
+    /// Validates the grammer rules have at least 1 valid path, and that every
+    /// variable is bound before it is used. Returns the first problem of the
+    /// report, see `validation_report`.
+    pub fn validate(&self) -> Result<(), String> {
+        self.validation_report().into_result()
     }
+
+    /// Runs every check of `validate` and reports all of their results. See
+    /// Validator struct for more detailed info on validation procedure.
+    ///
+    /// # Example
+    /// ```
+    /// use story_gen::grammar::{Grammar, Status};
+    ///
+    /// let mut grammar = Grammar::new();
+    /// grammar.change_start_nonterminal("<s>");
+    /// grammar.rule_add("<s>", "<loop> | $hero ran");
+    /// grammar.rule_add("<loop>", "<loop> again");
+    /// let report = grammar.validation_report();
+    /// assert_eq!(report.unsafe_nonterminals, vec!["<loop>"]);
+    /// assert_eq!(report.validation["<s>"], Status::SAFE);
+    /// assert_eq!(report.errors.len(), 2);
+    /// assert_eq!(grammar.validate(), Err(report.errors[0].clone()));
+    /// ```
+    pub fn validation_report(&self) -> ValidationReport {
+        let rules = flatten_rules(&self.rules, &self.featured_rules);
+        let validator = Validator::new();
+        let validation = validator.statuses(&rules);
+        let mut unsafe_nonterminals = get_unsafe_keys(&validation);
+        unsafe_nonterminals.sort();
+        let mut errors = vec![];
+        if !unsafe_nonterminals.is_empty() {
+            errors.push(format!(
+                "unsafe non-terminals: {}",
+                unsafe_nonterminals.join(" ")
+            ));
+        }
+        let checks = vec![
+            validator.check_variables(&rules, &self.start_nonterminal),
+            self.check_modifiers(&rules),
+        ];
+        errors.extend(checks.into_iter().filter_map(Result::err));
+        let unproductive = features::unproductive_combinations(&self.rules, &self.featured_rules);
+        if !unproductive.is_empty() {
+            errors.push(format!(
+                "feature combinations without a valid path: {}",
+                unproductive.join(" ")
+            ));
+        }
+        ValidationReport {
+            validation,
+            unsafe_nonterminals,
+            errors,
+        }
+    }
+}
+
+impl Grammar {
+    /// Reports modifiers that are not registered, when they follow a token
+    /// that expands a rule. Other tokens with dots, e.g. "Mr.Smith", are
+    /// terminals.
+    fn check_modifiers(&self, rules: &HashMap<String, Vec<String>>) -> Result<(), String> {
+        let mut errors: Vec<String> = vec![];
+        for (key, options) in rules {
+            for sub_option in options.iter().flat_map(|option| option.split_whitespace()) {
+                let (inner, modifiers) = match token::classify(sub_option) {
+                    Token::Modified { inner, modifiers } => (inner, modifiers),
+                    _ => continue,
+                };
+                let expands_rule = match token::classify(inner) {
+                    Token::Variable(_) => true,
+                    _ => token::referenced_symbol(inner)
+                        .is_some_and(|symbol| rules.contains_key(symbol.as_ref())),
+                };
+                for modifier in modifiers.split('.') {
+                    if expands_rule && !self.modifiers.contains(modifier) {
+                        errors.push(format!(".{} in '{}'", modifier, key));
+                    }
+                }
+            }
+        }
+        errors.sort();
+        errors.dedup();
+        match errors.len() {
+            0 => Ok(()),
+            _ => Err(format!("unknown modifiers: {}", errors.join(", "))),
+        }
+    }
+}
+
+/// The largest weight an option can have, see `Grammar::set_weight`.
+pub const MAX_WEIGHT: u32 = 1_000_000;
+
+/// The weight of the option at 'index', given the weights of its rule.
+fn option_weight(weights: Option<&Vec<u32>>, index: usize) -> u32 {
+    weights
+        .and_then(|weights| weights.get(index))
+        .copied()
+        .unwrap_or(1)
 }
 
-/// Executes all grammar validation logic and stores results in
-/// the HashMap 'validation' attribute.
+/// Number of times a sentence is started over when its features can not
+/// be agreed on, it misses a required item or it does not fit the length
+/// constraint. See `Grammar::expand_sentence`.
+const MAX_ATTEMPTS: usize = 100;
+
+/// A sentence for which no attempt met the goal, see `expand_sentence`.
+struct Failed {
+    error: String,
+    /// The last attempt, or the closest to the length constraint.
+    sentence: String,
+}
+
+/// The state of a single sentence while it is expanded.
+struct Expansion<'a, R: Rng + ?Sized> {
+    grammar: &'a Grammar,
+    compiled: &'a CompiledGrammar,
+    rng: &'a mut R,
+    context: &'a mut GenerationContext,
+    /// When provided, the derivation tree is appended to it.
+    trace: Option<&'a mut String>,
+    features: FeatureStore,
+    /// Set when an option with agreeing features could not be found.
+    failed: bool,
+    /// The possible lengths of every non-terminal, used to steer choices
+    /// towards the budget passed to 'expand'.
+    lengths: Option<&'a LengthTable>,
+    /// Every symbol expanded so far, when the grammar has required items.
+    reached: HashSet<String>,
+}
+
+/// What the expansion of a token should achieve, besides agreeing features.
+#[derive(Debug, Clone, Default)]
+struct Goal<'a> {
+    /// The range of lengths the expansion should have.
+    budget: Option<LengthRange>,
+    /// Items the expansion must contain, see `Grammar::set_required`.
+    required: Vec<&'a str>,
+}
+
+impl Goal<'_> {
+    fn is_none(&self) -> bool {
+        self.budget.is_none() && self.required.is_empty()
+    }
+}
+
+impl<'a, R: Rng + ?Sized> Expansion<'a, R> {
+    /// Recursive expansion shared by every generation method. If 'key' has
+    /// no rule, it must be a terminal, so return that string. Otherwise
+    /// evaluate a random option whose features agree with 'constraints'.
+    /// Options that can fit the length budget of the 'goal', and that can
+    /// reach its required items, are preferred. Only a plain rule without
+    /// constraints or goal is expanded from the compiled rules, the others
+    /// are chosen from the text of the options.
+    fn expand(&mut self, key: &str, constraints: &[(String, usize)], mut goal: Goal<'a>) -> String {
+        let grammar = self.grammar;
+        if !grammar.required.is_empty() {
+            self.reached.insert(String::from(key));
+            let reached = &self.reached;
+            goal.required.retain(|item| !reached.contains(*item));
+        }
+        if constraints.is_empty() && goal.is_none() {
+            let compiled = self.compiled;
+            if let Some(rule) = compiled.symbol(key).map(|id| compiled.rule(id)) {
+                if self.is_plain(rule) {
+                    return self.expand_compiled(rule);
+                }
+            }
+        }
+        let candidates = features::candidates(key, &grammar.rules, &grammar.featured_rules);
+        if candidates.is_empty() {
+            if let Some(trace) = self.trace.as_deref_mut() {
+                trace.push_str(key);
+            }
+            return format!(" {}", key);
+        }
+        // try every option on a copy, to only choose between agreeing options
+        let agreeing: Vec<usize> = (0..candidates.len())
+            .filter(|&i| {
+                let mut trial = self.features.clone();
+                trial.unify_head(candidates[i].0, constraints).is_some()
+            })
+            .collect();
+        if agreeing.is_empty() {
+            self.failed = true;
+            return String::new();
+        }
+        let agreeing = match (goal.budget, self.lengths) {
+            (Some(budget), Some(lengths)) => {
+                let fitting: Vec<usize> = agreeing
+                    .iter()
+                    .copied()
+                    .filter(|&i| {
+                        let range = length::sum(&lengths.option_ranges(candidates[i].1));
+                        length::overlaps(range, budget)
+                    })
+                    .collect();
+                match fitting.is_empty() {
+                    true => agreeing,
+                    false => fitting,
+                }
+            }
+            _ => agreeing,
+        };
+        let reaching: Vec<usize> = agreeing
+            .iter()
+            .copied()
+            .filter(|&i| {
+                goal.required.iter().all(|item| {
+                    candidates[i]
+                        .1
+                        .split_whitespace()
+                        .any(|token| grammar.token_reaches(token, item))
+                })
+            })
+            .collect();
+        let agreeing = match reaching.is_empty() {
+            true => agreeing,
+            false => reaching,
+        };
+        let (head, option) = candidates[self.choose(key, &candidates, &agreeing)];
+        let locals = self
+            .features
+            .unify_head(head, constraints)
+            .unwrap_or_default();
+        let label = match grammar.featured_rules.get(key) {
+            Some(heads) => heads
+                .iter()
+                .find(|rule| rule.features.as_slice() == head)
+                .map_or(key, |rule| rule.head.as_str()),
+            None => key,
+        };
+        self.expand_option(label, option, locals, goal)
+    }
+
+    /// Whether any option of 'rule' can be chosen, so the compiled options
+    /// can be expanded without looking at their text.
+    fn is_plain(&self, rule: &CompiledRule) -> bool {
+        !rule.featured && self.context.repetition.window(&rule.name) == 0
+    }
+
+    /// Expands a random compiled option of 'rule', see `is_plain`. This is
+    /// the same as `expand_option` for the text of the option, and it
+    /// chooses the same option from the same random numbers as choosing
+    /// from 'rules'.
+    fn expand_compiled(&mut self, rule: &'a CompiledRule) -> String {
+        let compiled = self.compiled;
+        let pieces: &[Piece] = match &rule.weights {
+            Some(weights) => &rule.options[weights.sample(self.rng)],
+            None => rule.options.choose(self.rng).map_or(&[], |pieces| pieces),
+        };
+        if let Some(trace) = self.trace.as_deref_mut() {
+            trace.push('[');
+            trace.push_str(&rule.name);
+        }
+        let mut built_sentence = String::new();
+        let mut locals = HashMap::new();
+        let mut join = false;
+        for piece in pieces {
+            if let Some(trace) = self.trace.as_deref_mut() {
+                trace.push(' ');
+            }
+            let expansion = match *piece {
+                Piece::Join => {
+                    if let Some(trace) = self.trace.as_deref_mut() {
+                        trace.push('~');
+                    }
+                    join = true;
+                    continue;
+                }
+                Piece::Terminal(word) => {
+                    let word = compiled.string(word);
+                    if !self.grammar.required.is_empty() {
+                        self.reached.insert(String::from(word));
+                    }
+                    if let Some(trace) = self.trace.as_deref_mut() {
+                        trace.push_str(word);
+                    }
+                    if !join {
+                        built_sentence.push(' ');
+                    }
+                    built_sentence.push_str(word);
+                    join = false;
+                    continue;
+                }
+                Piece::Symbol(symbol) => {
+                    let rule = compiled.rule(symbol);
+                    match self.is_plain(rule) {
+                        true => {
+                            if !self.grammar.required.is_empty() {
+                                self.reached.insert(rule.name.clone());
+                            }
+                            self.expand_compiled(rule)
+                        }
+                        false => self.expand(&rule.name, &[], Goal::default()),
+                    }
+                }
+                Piece::Token(token) => {
+                    self.expand_token(compiled.string(token), &mut locals, Goal::default())
+                }
+            };
+            match join {
+                // the expansion starts with the space that separates it
+                true => built_sentence.push_str(expansion.trim_start()),
+                false => built_sentence.push_str(&expansion),
+            }
+            join = false;
+        }
+        if let Some(trace) = self.trace.as_deref_mut() {
+            trace.push(']');
+        }
+        built_sentence
+    }
+
+    /// Picks one of the 'agreeing' candidates by the weights of the options,
+    /// following the repetition policy of the context, and returns its
+    /// index in 'candidates'.
+    fn choose(
+        &mut self,
+        key: &str,
+        candidates: &[(&[(String, FeatureValue)], &str)],
+        agreeing: &[usize],
+    ) -> usize {
+        let policy = &self.context.repetition;
+        let option_weights = self.grammar.candidate_weights(key);
+        if policy.window(key) == 0 {
+            // the same draw as 'expand_compiled' for the same options
+            let weights = option_weights
+                .map(|weights| WeightedIndex::new(agreeing.iter().map(|&i| u64::from(weights[i]))));
+            return match weights {
+                Some(Ok(distribution)) => agreeing[distribution.sample(self.rng)],
+                _ => agreeing[self.rng.gen_range(0..agreeing.len())],
+            };
+        }
+        let weights: Vec<f64> = agreeing
+            .iter()
+            .map(|&i| {
+                let weight = option_weights.as_ref().map_or(1, |weights| weights[i]);
+                f64::from(weight) * policy.weight(self.context.is_recent(key, candidates[i].1))
+            })
+            .collect();
+        // when every option is excluded, any of them may be repeated
+        let chosen = match WeightedIndex::new(&weights) {
+            Ok(distribution) => agreeing[distribution.sample(self.rng)],
+            Err(_) => agreeing[self.rng.gen_range(0..agreeing.len())],
+        };
+        self.context.remember_choice(key, candidates[chosen].1);
+        chosen
+    }
+
+    /// Expands every token of an option. 'locals' are the feature variables
+    /// of the option. The length budget of the 'goal' is split between the
+    /// tokens, so each token leaves room for the shortest and longest
+    /// expansions of the tokens after it. Every required item is given to a
+    /// random token that can reach it.
+    fn expand_option(
+        &mut self,
+        label: &str,
+        option: &str,
+        mut locals: HashMap<String, usize>,
+        goal: Goal<'a>,
+    ) -> String {
+        let sub_choices = parse_subunits(option);
+        let ranges = match (goal.budget, self.lengths) {
+            (Some(_), Some(lengths)) => lengths.option_ranges(option),
+            _ => vec![],
+        };
+        let mut assigned: Vec<Vec<&'a str>> = vec![vec![]; sub_choices.len()];
+        for item in goal.required {
+            let reaching: Vec<usize> = (0..sub_choices.len())
+                .filter(|&i| self.grammar.token_reaches(&sub_choices[i], item))
+                .collect();
+            if !reaching.is_empty() {
+                assigned[reaching[self.rng.gen_range(0..reaching.len())]].push(item);
+            }
+        }
+        if let Some(trace) = self.trace.as_deref_mut() {
+            trace.push('[');
+            trace.push_str(label);
+        }
+        let mut built_sentence = String::new();
+        let mut join = false;
+        for (i, token) in sub_choices.iter().enumerate() {
+            if let Some(trace) = self.trace.as_deref_mut() {
+                trace.push(' ');
+            }
+            if token::classify(token) == Token::Join {
+                if let Some(trace) = self.trace.as_deref_mut() {
+                    trace.push('~');
+                }
+                join = true;
+                continue;
+            }
+            let token_budget = match (goal.budget, self.lengths) {
+                (Some((min, max)), Some(lengths)) => {
+                    let used = lengths.unit.measure(&built_sentence);
+                    let (rest_min, rest_max) = length::sum(&ranges[i + 1..]);
+                    Some((
+                        min.saturating_sub(used.saturating_add(rest_max)),
+                        max.saturating_sub(used.saturating_add(rest_min)),
+                    ))
+                }
+                _ => None,
+            };
+            let token_goal = Goal {
+                budget: token_budget,
+                required: std::mem::take(&mut assigned[i]),
+            };
+            let expansion = self.expand_token(token, &mut locals, token_goal);
+            match join {
+                // the expansion starts with the space that separates it
+                true => built_sentence.push_str(expansion.trim_start()),
+                false => built_sentence.push_str(&expansion),
+            }
+            join = false;
+        }
+        if let Some(trace) = self.trace.as_deref_mut() {
+            trace.push(']');
+        }
+        built_sentence
+    }
+
+    /// Expands a single token of an option, handling variable bindings,
+    /// references and features. See the token module for the syntax.
+    fn expand_token(
+        &mut self,
+        token: &str,
+        locals: &mut HashMap<String, usize>,
+        goal: Goal<'a>,
+    ) -> String {
+        match token::classify(token) {
+            Token::Symbol(symbol) => self.expand(symbol, &[], goal),
+            Token::Featured(featured) => {
+                let featured = match features::parse_featured(featured) {
+                    Some(featured) => featured,
+                    None => return self.expand(token, &[], goal),
+                };
+                let mut constraints = vec![];
+                for (name, value) in featured.features {
+                    let id = match value {
+                        FeatureValue::Value(value) => self.features.constant(&value),
+                        FeatureValue::Variable(variable) => {
+                            let features = &mut self.features;
+                            *locals.entry(variable).or_insert_with(|| features.fresh())
+                        }
+                    };
+                    constraints.push((name, id));
+                }
+                self.expand(&featured.symbol, &constraints, goal)
+            }
+            Token::Bind { name, value } => {
+                if let Some(bound) = self.context.variable(name) {
+                    if let Some(trace) = self.trace.as_deref_mut() {
+                        trace.push('$');
+                        trace.push_str(name);
+                    }
+                    return format!(" {}", bound);
+                }
+                if let Some(trace) = self.trace.as_deref_mut() {
+                    trace.push('[');
+                    trace.push_str(name);
+                    trace.push(':');
+                }
+                let expansion = self.expand_token(value, locals, goal);
+                if let Some(trace) = self.trace.as_deref_mut() {
+                    trace.push(']');
+                }
+                self.context.bind(name, expansion.trim());
+                expansion
+            }
+            Token::Modified { inner, modifiers } => {
+                let grammar = self.grammar;
+                if !modifiers
+                    .split('.')
+                    .all(|name| grammar.modifiers.contains(name))
+                {
+                    return self.expand(token, &[], goal);
+                }
+                let expansion = self.expand_token(inner, locals, goal);
+                if let Some(trace) = self.trace.as_deref_mut() {
+                    trace.push('.');
+                    trace.push_str(modifiers);
+                }
+                let mut modified = String::from(expansion.trim());
+                for name in modifiers.split('.') {
+                    modified = grammar.modifiers.apply(name, &modified).unwrap_or(modified);
+                }
+                format!(" {}", modified)
+            }
+            Token::Join => String::new(),
+            Token::Variable(name) => {
+                if let Some(trace) = self.trace.as_deref_mut() {
+                    trace.push_str(token);
+                }
+                match self.context.variable(name) {
+                    Some(bound) => format!(" {}", bound),
+                    None => format!(" {}", token),
+                }
+            }
+        }
+    }
+}
+
+/// Counts the ways 'key' can be expanded. 'counts' remembers finished
+/// non-terminals and 'visiting' holds the ones being counted, to detect
+/// recursion.
+fn count_expansions<'a>(
+    key: &'a str,
+    rules: &'a HashMap<String, Vec<String>>,
+    counts: &mut HashMap<&'a str, Option<u64>>,
+    visiting: &mut HashSet<&'a str>,
+) -> Option<u64> {
+    let (key, options) = match rules.get_key_value(key) {
+        Some((key, options)) => (key.as_str(), options),
+        None => return Some(1), // terminal
+    };
+    if let Some(count) = counts.get(key) {
+        return *count;
+    }
+    if !visiting.insert(key) {
+        return None;
+    }
+    let mut total: Option<u64> = Some(0);
+    for option in options {
+        let mut product: Option<u64> = Some(1);
+        for sub_option in option.split_whitespace() {
+            let token = match token::classify(token::unmodified(sub_option)) {
+                Token::Bind { value, .. } => value,
+                _ => sub_option,
+            };
+            // variables repeat an earlier choice, so they add no choices
+            let count = match token::referenced_symbol(token) {
+                Some(symbol) => match rules.get_key_value(symbol.as_ref()) {
+                    Some((symbol, _)) => count_expansions(symbol, rules, counts, visiting),
+                    None => Some(1),
+                },
+                None => Some(1),
+            };
+            product = product
+                .zip(count)
+                .and_then(|(product, count)| product.checked_mul(count));
+        }
+        total = total
+            .zip(product)
+            .and_then(|(total, product)| total.checked_add(product));
+    }
+    visiting.remove(key);
+    counts.insert(key, total);
+    total
+}
+
+/// Combines the rules with and without features into a single map from
+/// non-terminal to options, which is what the validation traversals use.
+/// Features are ignored, so the map is only borrowed when there are none.
+fn flatten_rules<'a>(
+    rules: &'a HashMap<String, Vec<String>>,
+    featured_rules: &HashMap<String, Vec<FeaturedRule>>,
+) -> Cow<'a, HashMap<String, Vec<String>>> {
+    if featured_rules.is_empty() {
+        return Cow::Borrowed(rules);
+    }
+    let mut flattened = rules.clone();
+    for (symbol, heads) in featured_rules {
+        let options = flattened.entry(symbol.clone()).or_default();
+        for head in heads {
+            options.extend(head.options.iter().cloned());
+        }
+    }
+    Cow::Owned(flattened)
+}
+
+/// Iterator of generated sentences, see `Grammar::sentences`.
+#[derive(Debug)]
+pub struct Sentences<'a, R: Rng + ?Sized> {
+    generator: Generator<'a, &'a mut R>,
+    key: &'a str,
+}
+
+impl<'a, R: Rng + ?Sized> Sentences<'a, R> {
+    /// Generate with 'context' instead of a new one, e.g. to change the
+    /// variable scope or repetition policy.
+    pub fn with_context(mut self, context: GenerationContext) -> Self {
+        self.generator = self.generator.with_context(context);
+        self
+    }
+}
+
+impl<R: Rng + ?Sized> Iterator for Sentences<'_, R> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        Some(self.generator.sentence(self.key))
+    }
+}
+
+/// Iterator of generated sentences or the reason they could not be
+/// generated, see `Grammar::try_sentences`.
 #[derive(Debug)]
+pub struct TrySentences<'a, R: Rng + ?Sized> {
+    generator: Generator<'a, &'a mut R>,
+    key: &'a str,
+}
+
+impl<'a, R: Rng + ?Sized> TrySentences<'a, R> {
+    /// Generate with 'context' instead of a new one, e.g. to change the
+    /// variable scope or repetition policy.
+    pub fn with_context(mut self, context: GenerationContext) -> Self {
+        self.generator = self.generator.with_context(context);
+        self
+    }
+}
+
+impl<R: Rng + ?Sized> Iterator for TrySentences<'_, R> {
+    type Item = Result<String, String>;
+
+    fn next(&mut self) -> Option<Result<String, String>> {
+        Some(self.generator.try_sentence(self.key))
+    }
+}
+
+impl Default for Grammar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes the rules in the syntax of a grammar file, one rule per line,
+/// starting with the start non-terminal so the file can be loaded again.
+/// The file syntax has no weights, so the weights of options are not
+/// written.
+///
+/// # Example
+/// ```
+/// use story_gen::grammar::Grammar;
+///
+/// let mut grammar = Grammar::new();
+/// grammar.change_start_nonterminal("<s>");
+/// grammar.rule_add("<pro>", "Mike | Sarah");
+/// grammar.rule_add("<s>", "<pro> ran");
+/// assert_eq!(grammar.to_string(), "<s> = <pro> ran\n<pro> = Mike | Sarah\n");
+/// ```
+impl fmt::Display for Grammar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut keys: Vec<&String> = self
+            .rules
+            .keys()
+            .chain(self.featured_rules.keys())
+            .collect();
+        keys.sort_unstable_by_key(|key| (**key != self.start_nonterminal, *key));
+        keys.dedup();
+        for key in keys {
+            if let Some(options) = self.rules.get(key) {
+                writeln!(f, "{} = {}", key, options.join(" | "))?;
+            }
+            for featured in self.featured_rules.get(key).into_iter().flatten() {
+                writeln!(f, "{} = {}", featured.head, featured.options.join(" | "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The results of validating a grammar, see `Grammar::validation_report`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ValidationReport {
+    /// The Status of every non-terminal, SAFE when it has a path to
+    /// terminals.
+    pub validation: HashMap<String, i32>,
+    /// The non-terminals without a path to terminals, sorted.
+    pub unsafe_nonterminals: Vec<String>,
+    /// Every problem found, starting with the unsafe non-terminals.
+    pub errors: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// The first problem, if there is one.
+    pub fn into_result(self) -> Result<(), String> {
+        match self.errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Executes all grammar validation logic. `validate` stores its results in
+/// the HashMap 'validation' attribute, the other checks only return them.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Validator {
     pub validation: HashMap<String, i32>,
 }
@@ -150,32 +1530,54 @@ impl Validator {
     /// `<noun> = <verb>`
     /// `<verb> = <noun>`
     pub fn validate(&mut self, rules: &HashMap<String, Vec<String>>) -> Result<(), String> {
-        // self.reset_validation(rules);
-        let mut validation_map = self.reset_validation(rules);
-        for key in rules.keys() {
-            match dfs(key, rules, &mut validation_map) {
-                true => validation_map.insert(String::from(key), Status::SAFE),
-                false => validation_map.insert(String::from(key), Status::UNSAFE),
-            };
-        }
-        self.validation = validation_map;
+        self.validation = self.statuses(rules);
         let unsafe_keys = get_unsafe_keys(&self.validation);
         // if no unsafe keys, then validation is successful
         match unsafe_keys.len() {
             0 => Ok(()),
             _ => {
                 let error_str = format!("unsafe non-terminals: {}", unsafe_keys.join(" "));
-                return Err(error_str);
+                Err(error_str)
             }
         }
     }
 
-    fn reset_validation(&mut self, rules: &HashMap<String, Vec<String>>) -> HashMap<String, i32> {
-        let validation = rules
-            .keys()
-            .map(|key| (String::from(key), Status::UNVISITED))
-            .collect();
-        validation
+    /// The Status of every non-terminal found by `validate`, either SAFE or
+    /// UNSAFE.
+    pub fn statuses(&self, rules: &HashMap<String, Vec<String>>) -> HashMap<String, i32> {
+        let mut validation_map = unvisited(rules);
+        for key in rules.keys() {
+            match dfs(key, rules, &mut validation_map) {
+                true => validation_map.insert(String::from(key), Status::SAFE),
+                false => validation_map.insert(String::from(key), Status::UNSAFE),
+            };
+        }
+        validation_map
+    }
+
+    /// Finds every symbol, non-terminal or terminal, that can appear in an
+    /// expansion of each non-terminal, including the non-terminal itself.
+    pub fn reachable_items(
+        &self,
+        rules: &HashMap<String, Vec<String>>,
+    ) -> HashMap<String, HashSet<String>> {
+        let mut reachable = HashMap::new();
+        for key in rules.keys() {
+            let mut status = unvisited(rules);
+            find_reachable(key, rules, &mut status);
+            let mut items: HashSet<String> = HashSet::new();
+            for (node, _) in status.iter().filter(|(_, &val)| val == Status::SAFE) {
+                items.insert(node.clone());
+                for option in &rules[node] {
+                    let symbols = option
+                        .split_whitespace()
+                        .filter_map(token::referenced_symbol);
+                    items.extend(symbols.map(|symbol| symbol.into_owned()));
+                }
+            }
+            reachable.insert(key.clone(), items);
+        }
+        reachable
     }
 
     /// Traverses the grammar rules from the starting non-terminal
@@ -187,16 +1589,196 @@ impl Validator {
         rules: &HashMap<String, Vec<String>>,
         key: &str,
     ) -> Vec<String> {
-        self.validation = self.reset_validation(rules);
+        self.validation = unvisited(rules);
         find_reachable(key, rules, &mut self.validation);
-        let unreachable_keys: Vec<String> = self
-            .validation
-            .iter()
-            .filter(|(_, &val)| val != Status::SAFE)
-            .map(|(key, _)| String::from(key))
-            .collect();
+        unreachable_keys(&self.validation)
+    }
+
+    /// Same as `get_unreachable_nonterminals`, without storing the results.
+    pub fn unreachable_from(&self, rules: &HashMap<String, Vec<String>>, key: &str) -> Vec<String> {
+        let mut status = unvisited(rules);
+        find_reachable(key, rules, &mut status);
+        unreachable_keys(&status)
+    }
+
+    /// Checks that every `$name` reference is preceded by a `[name:value]`
+    /// binding on every path from the starting non-terminal that reaches it.
+    /// The check assumes variables are forgotten between sentences, which is
+    /// the strictest variable scope.
+    ///
+    /// #### Valid Example
+    /// `<sentence> = [hero:<pro>] <vp>`
+    /// `<vp> = hugged $hero`
+    ///
+    /// #### Invalid Example
+    /// `<sentence> = <vp> | [hero:<pro>] <vp>`
+    /// `<vp> = hugged $hero`
+    pub fn check_variables(
+        &self,
+        rules: &HashMap<String, Vec<String>>,
+        key: &str,
+    ) -> Result<(), String> {
+        let bound_after = variables_bound_after(rules);
+        // Variables that are bound on every path into each non-terminal.
+        let mut bound_on_entry: HashMap<&str, HashSet<&str>> = HashMap::new();
+        if rules.contains_key(key) {
+            bound_on_entry.insert(key, HashSet::new());
+        }
+        let mut pending: Vec<&str> = bound_on_entry.keys().copied().collect();
+        while let Some(node) = pending.pop() {
+            let entry = bound_on_entry[node].clone();
+            for option in &rules[node] {
+                let mut bound = entry.clone();
+                for sub_option in option.split_whitespace() {
+                    let (child, binds) = match token::classify(token::unmodified(sub_option)) {
+                        Token::Bind { name, value } => {
+                            (token::referenced_symbol(value), Some(name))
+                        }
+                        _ => (token::referenced_symbol(sub_option), None),
+                    };
+                    let child = match child {
+                        Some(child) => rules.get_key_value(child.as_ref()),
+                        None => None,
+                    };
+                    if let Some((child, _)) = child {
+                        let changed = match bound_on_entry.get_mut(child.as_str()) {
+                            Some(existing) => {
+                                let before = existing.len();
+                                existing.retain(|name| bound.contains(name));
+                                existing.len() != before
+                            }
+                            None => {
+                                bound_on_entry.insert(child, bound.clone());
+                                true
+                            }
+                        };
+                        if changed {
+                            pending.push(child);
+                        }
+                        // a binding that is already set does not expand its value
+                        if binds.is_none() {
+                            bound.extend(bound_after[child.as_str()].iter().copied());
+                        }
+                    }
+                    if let Some(name) = binds {
+                        bound.insert(name);
+                    }
+                }
+            }
+        }
+        let mut errors: Vec<String> = vec![];
+        for (node, entry) in &bound_on_entry {
+            for option in &rules[*node] {
+                let mut bound = entry.clone();
+                for sub_option in option.split_whitespace() {
+                    match token::classify(token::unmodified(sub_option)) {
+                        Token::Bind { name, .. } => {
+                            bound.insert(name);
+                        }
+                        Token::Variable(name) => {
+                            if !bound.contains(name) {
+                                errors.push(format!("${} in '{}'", name, node));
+                            }
+                        }
+                        _ => {
+                            let after = token::referenced_symbol(sub_option)
+                                .and_then(|symbol| bound_after.get(symbol.as_ref()));
+                            if let Some(after) = after {
+                                bound.extend(after.iter().copied());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        errors.sort();
+        errors.dedup();
+        match errors.len() {
+            0 => Ok(()),
+            _ => Err(format!(
+                "variables used before they are bound: {}",
+                errors.join(", ")
+            )),
+        }
+    }
+}
+
+/// Every non-terminal with the status UNVISITED.
+fn unvisited(rules: &HashMap<String, Vec<String>>) -> HashMap<String, i32> {
+    rules
+        .keys()
+        .map(|key| (String::from(key), Status::UNVISITED))
+        .collect()
+}
 
-        unreachable_keys
+/// The non-terminals that a search with `find_reachable` did not reach.
+fn unreachable_keys(status: &HashMap<String, i32>) -> Vec<String> {
+    status
+        .iter()
+        .filter(|(_, &val)| val != Status::SAFE)
+        .map(|(key, _)| String::from(key))
+        .collect()
+}
+
+/// For every non-terminal, finds the variables that are bound on every
+/// possible expansion of it. Starts by assuming every variable is bound and
+/// removes variables until nothing changes.
+fn variables_bound_after(rules: &HashMap<String, Vec<String>>) -> HashMap<&str, HashSet<&str>> {
+    let all_variables: HashSet<&str> = rules
+        .values()
+        .flatten()
+        .flat_map(|option| option.split_whitespace())
+        .filter_map(
+            |sub_option| match token::classify(token::unmodified(sub_option)) {
+                Token::Bind { name, .. } => Some(name),
+                _ => None,
+            },
+        )
+        .collect();
+    let mut bound_after: HashMap<&str, HashSet<&str>> = rules
+        .keys()
+        .map(|key| (key.as_str(), all_variables.clone()))
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (key, options) in rules {
+            let mut guaranteed: Option<HashSet<&str>> = None;
+            for option in options {
+                let mut bound = HashSet::new();
+                for sub_option in option.split_whitespace() {
+                    match token::classify(token::unmodified(sub_option)) {
+                        Token::Bind { name, .. } => {
+                            bound.insert(name);
+                        }
+                        Token::Variable(_) => (),
+                        _ => {
+                            let after = token::referenced_symbol(sub_option)
+                                .and_then(|symbol| bound_after.get(symbol.as_ref()));
+                            if let Some(after) = after {
+                                bound.extend(after.iter().copied());
+                            }
+                        }
+                    }
+                }
+                guaranteed = Some(match guaranteed {
+                    None => bound,
+                    Some(previous) => previous.intersection(&bound).copied().collect(),
+                });
+            }
+            let guaranteed = guaranteed.unwrap_or_default();
+            if guaranteed.len() != bound_after[key.as_str()].len() {
+                bound_after.insert(key, guaranteed);
+                changed = true;
+            }
+        }
+    }
+    bound_after
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -230,6 +1812,7 @@ pub fn dfs(
                     let parsed = parse_subunits(option);
                     let valid_options: Vec<bool> = parsed
                         .iter()
+                        .filter_map(|sub_option| token::referenced_symbol(sub_option))
                         .map(|sub_option| dfs(&sub_option, graph, status))
                         .collect();
                     let is_valid_option = valid_options.iter().all(sub_option_is_safe);
@@ -268,7 +1851,7 @@ fn find_reachable(
             if let Some(options) = graph.get(node) {
                 for option in options {
                     let parsed = parse_subunits(option);
-                    for sub_option in parsed {
+                    for sub_option in parsed.iter().filter_map(|s| token::referenced_symbol(s)) {
                         find_reachable(&sub_option, graph, status);
                     }
                 }
@@ -311,6 +1894,8 @@ fn get_unsafe_keys(status: &HashMap<String, i32>) -> Vec<String> {
 }
 
 #[cfg(test)]
+// the original tests are kept as they were written
+#[allow(clippy::for_kv_map)]
 mod tests {
     use super::*;
     use std::collections::HashMap;
@@ -333,7 +1918,7 @@ mod tests {
         expected.insert(String::from("noun"), 2);
         assert!(unsafe_keys.contains("<vp>"));
         assert!(unsafe_keys.contains("noun"));
-        assert_eq!(grammar.validator.validation, expected);
+        assert_eq!(grammar.validation_report().validation, expected);
     }
     #[test]
     fn test_validation_2() {
@@ -344,7 +1929,7 @@ mod tests {
         let mut expected = HashMap::new();
         expected.insert(String::from("1"), 3);
         expected.insert(String::from("2"), 3);
-        assert_eq!(grammar.validator.validation, expected);
+        assert_eq!(grammar.validation_report().validation, expected);
     }
     #[test]
     fn test_validation_3() {
@@ -355,7 +1940,7 @@ mod tests {
         let mut expected = HashMap::new();
         expected.insert(String::from("1"), 3);
         expected.insert(String::from("2"), 3);
-        assert_eq!(grammar.validator.validation, expected);
+        assert_eq!(grammar.validation_report().validation, expected);
     }
 
     #[test]
@@ -394,4 +1979,411 @@ mod tests {
         expected.insert("b".to_string(), Status::UNVISITED);
         assert_eq!(status, expected);
     }
+    #[test]
+    fn test_variable_reuses_binding() {
+        let mut grammar = Grammar::new();
+        grammar.rule_add("<sentence>", "[hero:<pro>] saw $hero");
+        grammar.rule_add("<pro>", "Mike | Sarah | Will");
+        let mut rng = random::seeded_rng(1);
+        for sentence in grammar.generate_sentences_with_rng("<sentence>", 20, &mut rng) {
+            let words: Vec<&str> = sentence.trim_end_matches('.').split(' ').collect();
+            assert_eq!(words[0], words[2]);
+        }
+    }
+    #[test]
+    fn test_compiled_rules_follow_changes() {
+        let mut grammar = Grammar::new();
+        grammar.rule_add("<sentence>", "<pro> ~ , <pro> ran");
+        grammar.rule_add("<pro>", "Mike");
+        assert_eq!(grammar.generate_sentence("<sentence>"), "Mike, Mike ran.");
+        assert_eq!(grammar.compiled().len(), 2);
+        grammar.rule_remove("<pro>");
+        grammar.rule_add("<pro>", "Sarah");
+        assert_eq!(grammar.generate_sentence("<sentence>"), "Sarah, Sarah ran.");
+        grammar.replace_rule("<pro>", &["Will"]).unwrap();
+        assert_eq!(grammar.generate_sentence("<sentence>"), "Will, Will ran.");
+    }
+    #[test]
+    fn test_templates_in_featured_rules() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<s>");
+        grammar.rule_add("<s>", "<np[num=pl]> ran");
+        grammar.rule_add("<np[num=pl]>", "<list(<noun>)>");
+        grammar.rule_add("<list(X)>", "X");
+        grammar.rule_add("<noun>", "dogs");
+        grammar.instantiate_templates().unwrap();
+        assert_eq!(grammar.generate_sentence("<s>"), "Dogs ran.");
+        assert!(grammar.get_unreachable_nonterminals().is_empty());
+    }
+    #[test]
+    fn test_weighted_options() {
+        let mut grammar = Grammar::new();
+        grammar.rule_add("<s>", "<pro> saw <np[num=sg]>");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        grammar.rule_add("<np[num=sg]>", "a dog | a cat");
+        grammar.set_weight("<pro>", 1, 9).unwrap();
+        grammar.set_weight("<np[num=sg]>", 0, 9).unwrap();
+        let mut rng = random::seeded_rng(1);
+        let sentences = grammar.generate_sentences_with_rng("<s>", 1000, &mut rng);
+        // the compiled rule of <pro>, and the rule with features
+        let sarah = sentences.iter().filter(|s| s.starts_with("Sarah")).count();
+        let dog = sentences.iter().filter(|s| s.ends_with("dog.")).count();
+        assert!((850..=950).contains(&sarah), "{}", sarah);
+        assert!((850..=950).contains(&dog), "{}", dog);
+        assert_eq!(grammar.language_size("<s>"), Some(4));
+        assert_eq!(grammar.weight("<pro>", 0), Some(1));
+        assert_eq!(grammar.weight("<pro>", 2), None);
+    }
+    #[test]
+    fn test_variable_scope_run() {
+        let mut grammar = Grammar::new();
+        grammar.rule_add("<sentence>", "[hero:<pro>]");
+        grammar.rule_add("<pro>", "Mike | Sarah | Will | Jacob | Rachel");
+        let mut rng = random::seeded_rng(1);
+        let mut context = GenerationContext::new(crate::context::VariableScope::Run);
+        let first = grammar.generate_sentence_with_context("<sentence>", &mut rng, &mut context);
+        for _ in 0..10 {
+            context.begin_paragraph();
+            let next = grammar.generate_sentence_with_context("<sentence>", &mut rng, &mut context);
+            assert_eq!(first, next);
+        }
+    }
+    #[test]
+    fn test_check_variables() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<sentence>");
+        grammar.rule_add("<sentence>", "[hero:<pro>] <vp> | <np> <vp>");
+        grammar.rule_add("<np>", "[hero:<pro>] | [hero:the] [villain:<pro>]");
+        grammar.rule_add("<vp>", "hugged $hero");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        grammar.validate().unwrap();
+        grammar.rule_add("<vp>", "saw $villain");
+        let error = grammar.validate().unwrap_err();
+        assert_eq!(
+            error,
+            "variables used before they are bound: $villain in '<vp>'"
+        );
+    }
+    #[test]
+    fn test_feature_agreement() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<sentence>");
+        grammar.rule_add("<sentence>", "<np[num=$n]> <verb[num=$n]>");
+        grammar.rule_add("<np[num=sg]>", "Mike | the dog");
+        grammar.rule_add("<np[num=pl]>", "the dogs");
+        grammar.rule_add("<verb[num=sg]>", "runs");
+        grammar.rule_add("<verb[num=pl]>", "run");
+        grammar.validate().unwrap();
+        let mut rng = random::seeded_rng(3);
+        for sentence in grammar.generate_sentences_with_rng("<sentence>", 30, &mut rng) {
+            let plural = sentence.starts_with("The dogs ");
+            assert_eq!(sentence.ends_with(" run."), plural, "{}", sentence);
+        }
+    }
+    #[test]
+    fn test_feature_validation() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<sentence>");
+        grammar.rule_add("<sentence>", "<np[num=$n]> <verb[num=$n]>");
+        grammar.rule_add("<np[num=sg]>", "Mike");
+        grammar.rule_add("<np[num=pl]>", "the dogs");
+        grammar.rule_add("<verb[num=sg]>", "runs");
+        assert_eq!(
+            grammar.validate().unwrap_err(),
+            "feature combinations without a valid path: <verb[num=pl]>"
+        );
+    }
+    #[test]
+    fn test_modifiers() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<sentence>");
+        grammar.rule_add(
+            "<sentence>",
+            "[hero:<pro>] <verb>.past two <noun>.plural $hero.upper",
+        );
+        grammar.rule_add("<pro>", "mike");
+        grammar.rule_add("<verb>", "see");
+        grammar.rule_add("<noun>", "wolf");
+        grammar.validate().unwrap();
+        assert_eq!(
+            grammar.generate_sentence("<sentence>"),
+            "Mike saw two wolves MIKE."
+        );
+        // non-ASCII words are split at character boundaries
+        grammar.rule_add("<s>", "Mike <v>.past");
+        grammar.rule_add("<v>", "taß");
+        assert_eq!(grammar.generate_sentence("<s>"), "Mike taßßed.");
+    }
+    #[test]
+    fn test_registered_and_unknown_modifiers() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<sentence>");
+        grammar.rule_add("<sentence>", "<noun>.shout Mr.Smith");
+        grammar.rule_add("<noun>", "dog");
+        assert_eq!(
+            grammar.validate().unwrap_err(),
+            "unknown modifiers: .shout in '<sentence>'"
+        );
+        grammar.register_modifier("shout", |text| format!("{}!", text.to_uppercase()));
+        grammar.validate().unwrap();
+        assert_eq!(grammar.generate_sentence("<sentence>"), "DOG! Mr.Smith.");
+    }
+    #[test]
+    fn test_join_without_space() {
+        let mut grammar = Grammar::new();
+        grammar.rule_add("<sentence>", "<name> ~ , the <noun> ~ s | ~ <name>");
+        grammar.rule_add("<name>", "<syll> ~ <syll>");
+        grammar.rule_add("<syll>", "ka");
+        grammar.rule_add("<noun>", "dog");
+        let mut rng = random::seeded_rng(2);
+        let (sentence, derivation) = grammar.generate_derivation_with_rng("<sentence>", &mut rng);
+        assert!(
+            sentence == "Kaka, the dogs." || sentence == "Kaka.",
+            "{}",
+            sentence
+        );
+        assert!(derivation.contains("[<name> [<syll> ka] ~ [<syll> ka]]"));
+    }
+    #[test]
+    fn test_unique_sentences_exhausted() {
+        let mut grammar = Grammar::new();
+        grammar.rule_add("<sentence>", "<pro> <verb>");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        grammar.rule_add("<verb>", "ran | sat");
+        let mut rng = random::seeded_rng(5);
+        let mut sentences = grammar.generate_unique_sentences_with_rng("<sentence>", 10, &mut rng);
+        sentences.sort();
+        assert_eq!(
+            sentences,
+            vec!["Mike ran.", "Mike sat.", "Sarah ran.", "Sarah sat."]
+        );
+    }
+    #[test]
+    fn test_unique_sentences_recursive() {
+        let mut grammar = Grammar::new();
+        grammar.rule_add("<sentence>", "<pro> ran | <pro> ran and <sentence>");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        let mut rng = random::seeded_rng(5);
+        let sentences = grammar.generate_unique_sentences_with_rng("<sentence>", 20, &mut rng);
+        assert_eq!(sentences.len(), 20);
+        let distinct: HashSet<&String> = sentences.iter().collect();
+        assert_eq!(distinct.len(), 20);
+    }
+    #[test]
+    fn test_cooldown_across_sentences() {
+        let mut grammar = Grammar::new();
+        grammar.rule_add("<sentence>", "<pro> <adj>");
+        grammar.rule_add("<pro>", "Mike | Sarah | Will");
+        grammar.rule_add("<adj>", "a | b | c | d");
+        let mut rng = random::seeded_rng(8);
+        let mut context = GenerationContext::default();
+        context.repetition.window = 2;
+        context.repetition.windows.insert(String::from("<adj>"), 3);
+        let sentences: Vec<String> = (0..30)
+            .map(|_| grammar.generate_sentence_with_context("<sentence>", &mut rng, &mut context))
+            .collect();
+        for (i, sentence) in sentences.iter().enumerate() {
+            let words: Vec<&str> = sentence.split(' ').collect();
+            for previous in &sentences[i.saturating_sub(2)..i] {
+                assert_ne!(previous.split(' ').next(), Some(words[0]));
+            }
+            for previous in &sentences[i.saturating_sub(3)..i] {
+                assert_ne!(previous.split(' ').nth(1), Some(words[1]));
+            }
+        }
+    }
+    #[test]
+    fn test_cooldown_in_one_call() {
+        let mut grammar = Grammar::new();
+        grammar.rule_add("<sentence>", "<pro>");
+        grammar.rule_add("<pro>", "Mike | Sarah | Will");
+        let mut rng = random::seeded_rng(8);
+        let mut context = GenerationContext::default();
+        context.repetition.window = 1;
+        let sentences =
+            grammar.generate_sentences_with_context("<sentence>", 50, &mut rng, &mut context);
+        assert_eq!(sentences.len(), 50);
+        for pair in sentences.windows(2) {
+            assert_ne!(pair[0], pair[1]);
+        }
+    }
+    #[test]
+    fn test_length_steering() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<sentence>");
+        grammar.rule_add("<sentence>", "<np> <vp>");
+        grammar.rule_add("<np>", "<pro> | the <adj> <noun>");
+        grammar.rule_add("<adj>", "old | <adj> , <adj>");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        grammar.rule_add("<noun>", "dog | cat");
+        grammar.rule_add("<vp>", "ran | saw <np>");
+        let constraint = LengthConstraint {
+            min: 9,
+            max: 10,
+            unit: crate::length::LengthUnit::Words,
+        };
+        grammar.set_length_constraint(constraint).unwrap();
+        let mut rng = random::seeded_rng(4);
+        for sentence in grammar.generate_sentences_with_rng("<sentence>", 20, &mut rng) {
+            assert_eq!(constraint.distance(&sentence), 0, "{}", sentence);
+        }
+    }
+    #[test]
+    fn test_length_unsatisfiable() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<sentence>");
+        grammar.rule_add("<sentence>", "<pro> ran");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        let constraint = LengthConstraint {
+            min: 0,
+            max: 8,
+            unit: crate::length::LengthUnit::Characters,
+        };
+        assert_eq!(
+            grammar.set_length_constraint(constraint).unwrap_err(),
+            "'<sentence>' has sentences of 9 to 10 characters, which can't be 0 to 8 characters"
+        );
+    }
+    #[test]
+    fn test_length_gaps() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<s>");
+        grammar.rule_add("<s>", "a b | a b c d e f g h i j");
+        let constraint = LengthConstraint {
+            min: 5,
+            max: 6,
+            unit: length::LengthUnit::Words,
+        };
+        assert_eq!(
+            grammar.set_length_constraint(constraint).unwrap_err(),
+            "'<s>' has no sentences of 5 to 6 words, the longest shorter one is 2 words"
+        );
+        // a variable is assumed to take any of its lengths in every use, so
+        // 3 words looks possible but never fits
+        grammar.rule_add("<s>", "[x:<n>] $x");
+        grammar.rule_add("<n>", "a | b c");
+        let constraint = LengthConstraint {
+            min: 3,
+            max: 3,
+            unit: length::LengthUnit::Words,
+        };
+        grammar.set_length_constraint(constraint).unwrap();
+        let mut rng = random::seeded_rng(3);
+        let mut context = GenerationContext::default();
+        assert_eq!(
+            grammar
+                .try_generate_sentence_with_context("<s>", &mut rng, &mut context)
+                .unwrap_err(),
+            "no sentence of '<s>' fit the length constraint in 100 attempts"
+        );
+        // the closest attempt is returned without the error
+        let sentence = grammar.generate_sentence_with_rng("<s>", &mut rng);
+        assert_eq!(constraint.distance(&format!(" {}", sentence)), 1);
+    }
+    #[test]
+    fn test_length_before_post_processing() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<s>");
+        grammar.rule_add("<s>", "<pro> ran , quickly !");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        grammar.default_post_processor.attach_punctuation = true;
+        let constraint = LengthConstraint {
+            min: 0,
+            max: 3,
+            unit: length::LengthUnit::Words,
+        };
+        assert!(grammar.set_length_constraint(constraint).is_err());
+        // the table and the finished attempts agree on 5 words
+        let constraint = LengthConstraint {
+            min: 5,
+            max: 5,
+            unit: length::LengthUnit::Words,
+        };
+        grammar.set_length_constraint(constraint).unwrap();
+        let mut rng = random::seeded_rng(2);
+        let mut context = GenerationContext::default();
+        let sentence = grammar
+            .try_generate_sentence_with_context("<s>", &mut rng, &mut context)
+            .unwrap();
+        assert!(sentence.ends_with(" ran, quickly!"), "{}", sentence);
+    }
+    #[test]
+    fn test_required_items() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<sentence>");
+        grammar.rule_add("<sentence>", "<np> <vp> | <np> <vp> and <vp>");
+        grammar.rule_add("<np>", "<pro> | the <noun> | the <adj> <noun>");
+        grammar.rule_add("<vp>", "saw <np> | ran");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        grammar.rule_add("<adj>", "old | happy");
+        grammar.rule_add("<noun>", "dog | wolf | cat");
+        grammar.set_required(&["wolf", "<adj>", "Sarah"]).unwrap();
+        let mut rng = random::seeded_rng(6);
+        for sentence in grammar.generate_sentences_with_rng("<sentence>", 20, &mut rng) {
+            assert!(sentence.contains("wolf"), "{}", sentence);
+            assert!(sentence.contains("Sarah"), "{}", sentence);
+            assert!(
+                sentence.contains("old") || sentence.contains("happy"),
+                "{}",
+                sentence
+            );
+        }
+        // other symbols are not constrained
+        let mut rng = random::seeded_rng(6);
+        let names = grammar.generate_sentences_with_rng("<pro>", 20, &mut rng);
+        assert!(names.iter().any(|name| name == "Mike."));
+    }
+    #[test]
+    fn test_required_unreachable() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<sentence>");
+        grammar.rule_add("<sentence>", "<pro> ran");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        grammar.rule_add("<unused>", "wolf");
+        assert_eq!(
+            grammar
+                .set_required(&["wolf", "<pro>", "<unused>"])
+                .unwrap_err(),
+            "'<sentence>' can't reach the required items: wolf, <unused>"
+        );
+    }
+    #[test]
+    fn test_failed_attempts_are_reported() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<s>");
+        grammar.rule_add("<s>", "<pro> ran | <pro> saw the big wolf");
+        grammar.rule_add("<pro>", "Mike | Sarah");
+        grammar.set_required(&["wolf"]).unwrap();
+        let constraint = LengthConstraint {
+            min: 0,
+            max: 3,
+            unit: length::LengthUnit::Words,
+        };
+        grammar.set_length_constraint(constraint).unwrap();
+        let mut rng = random::seeded_rng(1);
+        let mut context = GenerationContext::default();
+        assert_eq!(
+            grammar
+                .try_generate_derivation_with_context("<s>", &mut rng, &mut context)
+                .unwrap_err(),
+            "no sentence of '<s>' contained the required items in 100 attempts, missing: wolf"
+        );
+        // the last attempt is returned with its derivation
+        let (sentence, derivation) = grammar.generate_derivation_with_rng("<s>", &mut rng);
+        assert!(sentence.ends_with(" ran."), "{}", sentence);
+        assert!(derivation.starts_with("[<s> [<pro> "), "{}", derivation);
+
+        // features that can't agree, in a grammar that was not validated
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<s>");
+        grammar.rule_add("<s>", "<np[num=$n]> <vp[num=$n]>");
+        grammar.rule_add("<np[num=sg]>", "the dog");
+        grammar.rule_add("<vp[num=pl]>", "run");
+        assert_eq!(
+            grammar
+                .try_generate_sentence_with_context("<s>", &mut rng, &mut context)
+                .unwrap_err(),
+            "the features of '<s>' could not be agreed on in 100 attempts"
+        );
+    }
 }
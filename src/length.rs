@@ -0,0 +1,539 @@
+//! This module contains length constraints, and the table of lengths every
+//! non-terminal can expand into which is used to steer generation towards
+//! sentences of the wanted length.
+use crate::token::{self, Token};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Inclusive range of lengths. The upper bound is UNBOUNDED for recursive
+/// rules that can grow forever.
+pub type LengthRange = (usize, usize);
+
+pub const UNBOUNDED: usize = usize::MAX;
+
+/// Largest maximum length for which every possible length is computed, see
+/// `possible_lengths`. Longer constraints are only checked against the
+/// range of lengths.
+pub const MAX_EXACT_LENGTH: usize = 1024;
+
+/// What the length of a sentence is measured in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum LengthUnit {
+    #[default]
+    Words,
+    Characters,
+}
+
+impl FromStr for LengthUnit {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "words" => Ok(LengthUnit::Words),
+            "characters" | "chars" => Ok(LengthUnit::Characters),
+            _ => Err("Length unit must be one of: words, characters."),
+        }
+    }
+}
+
+impl LengthUnit {
+    /// The length of 'text' in this unit.
+    pub fn measure(&self, text: &str) -> usize {
+        match self {
+            LengthUnit::Words => text.split_whitespace().count(),
+            LengthUnit::Characters => text.chars().count(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            LengthUnit::Words => "words",
+            LengthUnit::Characters => "characters",
+        }
+    }
+}
+
+/// The inclusive range of lengths allowed for a finished sentence.
+///
+/// # Example
+/// ```
+/// use story_gen::length::{LengthConstraint, LengthUnit};
+///
+/// let constraint = LengthConstraint { min: 2, max: 3, unit: LengthUnit::Words };
+/// assert_eq!(constraint.distance(" Mike ran"), 0);
+/// assert_eq!(constraint.distance(" Mike ran home fast"), 1);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LengthConstraint {
+    pub min: usize,
+    pub max: usize,
+    pub unit: LengthUnit,
+}
+
+impl LengthConstraint {
+    /// How far the length of 'sentence' is outside of the range, 0 if it fits.
+    /// 'sentence' is the unformatted expansion, measured the same way as in
+    /// `LengthTable`, so characters count the space in front of every word
+    /// and punctuation attached or appended by the post-processor is not
+    /// counted.
+    pub fn distance(&self, sentence: &str) -> usize {
+        let length = self.unit.measure(sentence);
+        self.min.saturating_sub(length) + length.saturating_sub(self.max)
+    }
+
+    /// Errors when no length in 'range' is allowed.
+    pub fn check(&self, key: &str, range: LengthRange) -> Result<(), String> {
+        if overlaps(range, (self.min, self.max)) {
+            return Ok(());
+        }
+        let possible = match range.1 {
+            UNBOUNDED => format!("{} or more", range.0),
+            max => format!("{} to {}", range.0, max),
+        };
+        Err(format!(
+            "'{}' has sentences of {} {}, which can't be {} to {} {}",
+            key,
+            possible,
+            self.unit.name(),
+            self.min,
+            self.max,
+            self.unit.name()
+        ))
+    }
+
+    /// Errors when none of the 'possible' lengths, which are all the lengths
+    /// up to the maximum, is allowed. This catches the gaps 'check' can't
+    /// see, e.g. a rule with a 2 and a 10 word option can't be 5 words long.
+    pub fn check_possible(&self, key: &str, possible: &[usize]) -> Result<(), String> {
+        if possible.iter().any(|length| *length >= self.min) {
+            return Ok(());
+        }
+        let shorter = match possible.last() {
+            Some(length) => format!("{} {}", length, self.unit.name()),
+            None => String::from("nothing"),
+        };
+        Err(format!(
+            "'{}' has no sentences of {} to {} {}, the longest shorter one is {}",
+            key,
+            self.min,
+            self.max,
+            self.unit.name(),
+            shorter
+        ))
+    }
+}
+
+/// Whether the two ranges have a length in common.
+pub fn overlaps(a: LengthRange, b: LengthRange) -> bool {
+    a.0 <= b.1 && b.0 <= a.1
+}
+
+/// The range of lengths of a sequence of components.
+pub fn sum(ranges: &[LengthRange]) -> LengthRange {
+    ranges.iter().fold((0, 0), |(min, max), range| {
+        (min.saturating_add(range.0), max.saturating_add(range.1))
+    })
+}
+
+/// The shortest and longest expansion of every non-terminal, measured on
+/// the unformatted expansion where every word is preceded by a space.
+/// Variables are assumed to have the length of any value bound to them,
+/// and modifiers are assumed not to change the length.
+///
+/// # Example
+/// ```
+/// use story_gen::length::{LengthTable, LengthUnit, UNBOUNDED};
+/// use std::collections::HashMap;
+///
+/// let mut rules: HashMap<String, Vec<String>> = HashMap::new();
+/// rules.insert(String::from("<s>"), vec![String::from("<pro> ran"), String::from("<pro> ran and <s>")]);
+/// rules.insert(String::from("<pro>"), vec![String::from("Mike"), String::from("the dog")]);
+/// let table = LengthTable::new(&rules, LengthUnit::Words);
+/// assert_eq!(table.range("<pro>"), (1, 2));
+/// assert_eq!(table.range("<s>"), (2, UNBOUNDED));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LengthTable {
+    pub unit: LengthUnit,
+    ranges: HashMap<String, LengthRange>,
+    variables: HashMap<String, LengthRange>,
+}
+
+impl LengthTable {
+    /// Computes the lengths by updating every rule until nothing changes.
+    /// Upper bounds still growing after every rule had a chance to settle
+    /// belong to recursive rules, and are set to UNBOUNDED.
+    pub fn new(rules: &HashMap<String, Vec<String>>, unit: LengthUnit) -> LengthTable {
+        let mut table = LengthTable {
+            unit,
+            ranges: rules
+                .keys()
+                .map(|key| (key.clone(), (UNBOUNDED, 0)))
+                .collect(),
+            variables: HashMap::new(),
+        };
+        let binds = binds(rules);
+        let settle_rounds = rules.len() + binds.len() + 2;
+        for round in 0.. {
+            let mut changed = false;
+            let mut growing: Vec<String> = vec![];
+            for (key, options) in rules {
+                let range = options
+                    .iter()
+                    .map(|option| sum(&table.option_ranges(option)))
+                    .fold((UNBOUNDED, 0), |(min, max), (lo, hi)| {
+                        (min.min(lo), max.max(hi))
+                    });
+                let previous = table.ranges[key];
+                changed |= range != previous;
+                if range.1 != previous.1 {
+                    growing.push(key.clone());
+                }
+                table.ranges.insert(key.clone(), range);
+            }
+            let mut variables: HashMap<String, LengthRange> = HashMap::new();
+            for (name, value) in &binds {
+                let range = table.token_range(value);
+                let entry = variables
+                    .entry(String::from(*name))
+                    .or_insert((UNBOUNDED, 0));
+                *entry = (entry.0.min(range.0), entry.1.max(range.1));
+            }
+            changed |= variables != table.variables;
+            table.variables = variables;
+            if !changed {
+                break;
+            }
+            // variables grow with the rules they are bound from
+            if round >= settle_rounds {
+                for key in growing {
+                    if let Some(range) = table.ranges.get_mut(&key) {
+                        range.1 = UNBOUNDED;
+                    }
+                }
+            }
+        }
+        table
+    }
+
+    /// The lengths 'key' can expand into. A key without a rule is a
+    /// terminal.
+    pub fn range(&self, key: &str) -> LengthRange {
+        match self.ranges.get(key) {
+            Some(range) => *range,
+            None => {
+                let length = self.unit.measure(&format!(" {}", key));
+                (length, length)
+            }
+        }
+    }
+
+    /// The lengths a single component of an option can expand into.
+    pub fn token_range(&self, token: &str) -> LengthRange {
+        match token::classify(token) {
+            Token::Join => (0, 0),
+            Token::Variable(name) => match self.variables.get(name) {
+                Some(range) => *range,
+                None => self.range(token),
+            },
+            Token::Bind { value, .. } => self.token_range(value),
+            Token::Modified { inner, .. } => self.token_range(inner),
+            _ => match token::referenced_symbol(token) {
+                Some(symbol) => self.range(&symbol),
+                None => self.range(token),
+            },
+        }
+    }
+
+    /// The lengths of every component of 'option'. A component joined to
+    /// the previous one with `~` loses the space (or word) in front of it.
+    pub fn option_ranges(&self, option: &str) -> Vec<LengthRange> {
+        let mut ranges = vec![];
+        let mut joined = false;
+        for sub_option in option.split_whitespace() {
+            let (min, max) = self.token_range(sub_option);
+            ranges.push(match joined {
+                true => (
+                    min.saturating_sub(1),
+                    match max {
+                        UNBOUNDED => UNBOUNDED,
+                        max => max.saturating_sub(1),
+                    },
+                ),
+                false => (min, max),
+            });
+            joined = token::classify(sub_option) == Token::Join;
+        }
+        ranges
+    }
+}
+
+/// The variable bindings of every option, as (name, value).
+fn binds(rules: &HashMap<String, Vec<String>>) -> Vec<(&str, &str)> {
+    rules
+        .values()
+        .flatten()
+        .flat_map(|option| option.split_whitespace())
+        .filter_map(
+            |sub_option| match token::classify(token::unmodified(sub_option)) {
+                Token::Bind { name, value } => Some((name, value)),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+/// Every length up to 'limit' that 'key' can expand into, in increasing
+/// order, measured like `LengthTable`. Unlike the range of the table, it
+/// has the gaps between the lengths of the options.
+///
+/// # Example
+/// ```
+/// use story_gen::length::{self, LengthUnit};
+/// use std::collections::HashMap;
+///
+/// let mut rules: HashMap<String, Vec<String>> = HashMap::new();
+/// rules.insert(String::from("<s>"), vec![String::from("<pro> ran"), String::from("<pro> ran and <s>")]);
+/// rules.insert(String::from("<pro>"), vec![String::from("Mike"), String::from("the dog")]);
+/// assert_eq!(length::possible_lengths(&rules, LengthUnit::Words, "<s>", 8), vec![2, 3, 5, 6, 7, 8]);
+/// ```
+pub fn possible_lengths(
+    rules: &HashMap<String, Vec<String>>,
+    unit: LengthUnit,
+    key: &str,
+    limit: usize,
+) -> Vec<usize> {
+    let mut sets = LengthSets {
+        unit,
+        limit,
+        sets: rules
+            .keys()
+            .map(|key| (key.clone(), LengthSet::new(limit)))
+            .collect(),
+        variables: HashMap::new(),
+    };
+    let binds = binds(rules);
+    // the sets only grow, and can't grow past 'limit'
+    loop {
+        let mut changed = false;
+        for (key, options) in rules {
+            let mut set = LengthSet::new(limit);
+            for option in options {
+                set.union(&sets.option_set(option));
+            }
+            changed |= set != sets.sets[key];
+            sets.sets.insert(key.clone(), set);
+        }
+        let mut variables: HashMap<String, LengthSet> = HashMap::new();
+        for (name, value) in &binds {
+            let set = sets.token_set(value);
+            variables
+                .entry(String::from(*name))
+                .or_insert_with(|| LengthSet::new(limit))
+                .union(&set);
+        }
+        changed |= variables != sets.variables;
+        sets.variables = variables;
+        if !changed {
+            break;
+        }
+    }
+    sets.token_set(key).lengths().collect()
+}
+
+/// The lengths up to 'limit' of every non-terminal, see `possible_lengths`.
+struct LengthSets {
+    unit: LengthUnit,
+    limit: usize,
+    sets: HashMap<String, LengthSet>,
+    variables: HashMap<String, LengthSet>,
+}
+
+impl LengthSets {
+    /// Same as `LengthTable::token_range`, for sets of lengths.
+    fn token_set(&self, token: &str) -> LengthSet {
+        match token::classify(token) {
+            Token::Join => LengthSet::single(0, self.limit),
+            Token::Variable(name) => match self.variables.get(name) {
+                Some(set) => set.clone(),
+                None => self.symbol_set(token),
+            },
+            Token::Bind { value, .. } => self.token_set(value),
+            Token::Modified { inner, .. } => self.token_set(inner),
+            _ => match token::referenced_symbol(token) {
+                Some(symbol) => self.symbol_set(&symbol),
+                None => self.symbol_set(token),
+            },
+        }
+    }
+
+    fn symbol_set(&self, key: &str) -> LengthSet {
+        match self.sets.get(key) {
+            Some(set) => set.clone(),
+            None => {
+                let length = self.unit.measure(&format!(" {}", key));
+                LengthSet::single(length, self.limit)
+            }
+        }
+    }
+
+    /// Same as `LengthTable::option_ranges`, summed into one set.
+    fn option_set(&self, option: &str) -> LengthSet {
+        let mut set = LengthSet::single(0, self.limit);
+        let mut joined = false;
+        for sub_option in option.split_whitespace() {
+            let mut token_set = self.token_set(sub_option);
+            if joined {
+                token_set = token_set.shortened();
+            }
+            set = set.sum(&token_set);
+            joined = token::classify(sub_option) == Token::Join;
+        }
+        set
+    }
+}
+
+/// A set of lengths up to a limit, one bit per length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LengthSet {
+    limit: usize,
+    bits: Vec<u64>,
+}
+
+impl LengthSet {
+    fn new(limit: usize) -> LengthSet {
+        LengthSet {
+            limit,
+            bits: vec![0; limit / 64 + 1],
+        }
+    }
+
+    fn single(length: usize, limit: usize) -> LengthSet {
+        let mut set = LengthSet::new(limit);
+        if length <= limit {
+            set.bits[length / 64] |= 1 << (length % 64);
+        }
+        set
+    }
+
+    fn contains(&self, length: usize) -> bool {
+        length <= self.limit && self.bits[length / 64] & (1 << (length % 64)) != 0
+    }
+
+    fn lengths(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..=self.limit).filter(move |length| self.contains(*length))
+    }
+
+    fn union(&mut self, other: &LengthSet) {
+        for (bits, other) in self.bits.iter_mut().zip(&other.bits) {
+            *bits |= other;
+        }
+    }
+
+    /// Every sum of a length of 'self' and a length of 'other'.
+    fn sum(&self, other: &LengthSet) -> LengthSet {
+        let mut sum = LengthSet::new(self.limit);
+        for length in self.lengths() {
+            let (words, shift) = (length / 64, length % 64);
+            for i in words..sum.bits.len() {
+                let source = i - words;
+                sum.bits[i] |= other.bits[source] << shift;
+                if shift > 0 && source > 0 {
+                    sum.bits[i] |= other.bits[source - 1] >> (64 - shift);
+                }
+            }
+        }
+        sum.clear_above_limit();
+        sum
+    }
+
+    /// Every length one shorter, for a component that lost the space (or
+    /// word) in front of it. An empty length stays empty.
+    fn shortened(&self) -> LengthSet {
+        let mut shortened = LengthSet::new(self.limit);
+        for i in 0..self.bits.len() {
+            shortened.bits[i] = self.bits[i] >> 1;
+            if let Some(next) = self.bits.get(i + 1) {
+                shortened.bits[i] |= next << 63;
+            }
+        }
+        shortened.bits[0] |= self.bits[0] & 1;
+        shortened
+    }
+
+    fn clear_above_limit(&mut self) {
+        let last = self.bits.len() - 1;
+        let used = self.limit % 64 + 1;
+        if used < 64 {
+            self.bits[last] &= (1 << used) - 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::rules;
+
+    #[test]
+    fn test_character_lengths() {
+        let grammar = rules(&[("<s>", "<name> ~ s ran"), ("<name>", "Al | Bob")]);
+        let table = LengthTable::new(&grammar, LengthUnit::Characters);
+        assert_eq!(table.range("<name>"), (3, 4));
+        // " Als ran" and " Bobs ran"
+        assert_eq!(table.range("<s>"), (8, 9));
+    }
+    #[test]
+    fn test_variable_lengths() {
+        let grammar = rules(&[
+            ("<s>", "[hero:<name>] saw $hero"),
+            ("<name>", "Al | the old man"),
+        ]);
+        let table = LengthTable::new(&grammar, LengthUnit::Words);
+        assert_eq!(table.range("<s>"), (3, 7));
+    }
+    #[test]
+    fn test_check() {
+        let constraint = LengthConstraint {
+            min: 10,
+            max: 14,
+            unit: LengthUnit::Words,
+        };
+        assert_eq!(
+            constraint.check("<s>", (3, 9)).unwrap_err(),
+            "'<s>' has sentences of 3 to 9 words, which can't be 10 to 14 words"
+        );
+        assert!(constraint.check("<s>", (3, UNBOUNDED)).is_ok());
+    }
+    #[test]
+    fn test_possible_lengths() {
+        let grammar = rules(&[
+            ("<s>", "a b | a b c d e f g h i j | <long>"),
+            ("<long>", "<word> <word> <word> | <long> <long>"),
+            ("<word>", "x | [w:y] ~ $w"),
+        ]);
+        let possible = possible_lengths(&grammar, LengthUnit::Words, "<s>", 200);
+        assert_eq!(&possible[..6], &[2, 3, 6, 9, 10, 12]);
+        assert_eq!(possible.last(), Some(&198));
+        let constraint = LengthConstraint {
+            min: 4,
+            max: 5,
+            unit: LengthUnit::Words,
+        };
+        assert_eq!(
+            constraint
+                .check_possible(
+                    "<s>",
+                    &possible_lengths(&grammar, constraint.unit, "<s>", 5)
+                )
+                .unwrap_err(),
+            "'<s>' has no sentences of 4 to 5 words, the longest shorter one is 3 words"
+        );
+        // " a b" and " x y"
+        let possible = possible_lengths(&grammar, LengthUnit::Characters, "<s>", 6);
+        assert_eq!(possible, vec![4, 6]);
+    }
+}
@@ -1,28 +1,138 @@
 //! This module contains most of the business logic required to run the
 //! application and for error handling.
-use std::env;
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 // Declare modules to make them available within this crate.
+pub mod builder;
+pub mod compiled;
+pub mod context;
+pub mod document;
+pub mod edit;
+pub mod features;
+pub mod generator;
 pub mod grammar;
+pub mod length;
+pub mod modifiers;
+pub mod output;
+pub mod postprocess;
 pub mod random;
+pub mod repl;
+#[cfg(feature = "serde")]
+pub mod schema;
+pub mod source;
+pub mod template;
+#[cfg(test)]
+mod testing;
+pub mod token;
+pub mod unique;
+
+use context::{GenerationContext, RepetitionPolicy, VariableScope};
+use document::{CountRange, Part, StoryPlan};
+use length::{LengthConstraint, LengthUnit};
+use output::{Columns, OutputFormat, RecordWriter, SentenceRecord};
+use postprocess::{ArticleAgreement, Capitalization, PostProcessor};
+use rand::rngs::StdRng;
+use source::Source;
+use unique::UniqueFilter;
+
+/// How often the files are checked for changes in watch mode.
+const WATCH_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Paragraphs every thread generates before the output is written, when
+/// generating on several threads.
+const PARAGRAPHS_PER_THREAD: usize = 64;
 
 /// Main function which runs and controls the life time of the application.
 pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(config.filename)?;
-    // let lines = &contents.split("\n").collect::<Vec<&str>>();
-    let parsed_rules = parse_file(&contents);
-    if parsed_rules.len() == 0 {
-        panic!("unable to parse file or file is empty");
+    // Always generate from a seed, and report it, so any output can be reproduced.
+    let seed = config.seed.unwrap_or_else(random::random_seed);
+    if config.watch {
+        return watch(&config, seed);
+    }
+    let grammar = load_grammar(&config, &mut Source::default())?;
+    eprintln!("Using seed {}.", seed);
+    match write_output(&config, &grammar, seed) {
+        // the reader of the output, such as `head`, has all it wanted
+        Err(e)
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) =>
+        {
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Regenerates the output with the same seed every time the grammar file,
+/// or a file it includes, changes. Errors are reported without stopping.
+fn watch(config: &Config, seed: u64) -> Result<(), Box<dyn Error>> {
+    eprintln!("Using seed {}.", seed);
+    loop {
+        let mut source = Source::default();
+        let result = load_grammar(config, &mut source)
+            .and_then(|grammar| write_output(config, &grammar, seed));
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+        }
+        let files: Vec<String> = source
+            .files
+            .iter()
+            .map(|file| file.display().to_string())
+            .collect();
+        eprintln!("Watching {} for changes.", files.join(", "));
+        let before = modification_times(&source.files);
+        while modification_times(&source.files) == before {
+            thread::sleep(WATCH_INTERVAL);
+        }
+        eprintln!("Regenerating after a change.");
+    }
+}
+
+/// The time every file was last modified, or None for files that can't be
+/// read.
+fn modification_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Reads the grammar files and the files they include into 'source', then
+/// validates the grammar and applies the options of 'config' to it.
+fn load_grammar(config: &Config, source: &mut Source) -> Result<grammar::Grammar, Box<dyn Error>> {
+    for filename in &config.filenames {
+        match filename.as_str() {
+            "-" => source.read_stdin()?,
+            _ => source.read(Path::new(filename))?,
+        }
     }
+    let start = match &source.start {
+        Some(start) => start.clone(),
+        None => return Err("unable to parse file or file is empty".into()),
+    };
     let mut grammar = grammar::Grammar::new();
-    grammar.change_start_nonterminal(&parsed_rules[0].left_hand);
-    for rule in parsed_rules {
+    grammar.change_start_nonterminal(&start);
+    for rule in source.rules.drain(..) {
         grammar.rule_add_from_file(rule);
     }
-    let unreachable = grammar.get_unreachable_nonterminals();
+    grammar.instantiate_templates()?;
+    grammar.validate()?;
+    let mut unreachable = grammar.get_unreachable_nonterminals();
+    // rules only used by the titles or levels of a story are reachable too
+    if let Some(plan) = &config.story {
+        for symbol in plan.symbols() {
+            let from_title = grammar.get_unreachable_from(symbol);
+            unreachable.retain(|key| key != symbol && from_title.contains(key));
+        }
+    }
     // Use eprintln! so message does not get outputed to file if
     // client redirects stdout to file.
     match unreachable.len() {
@@ -30,70 +140,671 @@ pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
         _ => eprintln!("Warning: Unreachable non-terminals: {:#?}", unreachable),
     }
     // println!("{:#?}", grammar.rules);
-    eprintln!("Generating {} sentences.", config.quantity);
-    let generated_sentences =
-        grammar.generate_sentences(&grammar.start_nonterminal, config.quantity);
-    // let generated_paragraphs =
-    //     convert_sentences_to_paragraphs(&generated_sentences, &config.paragraph_length);
-    let generated_paragraphs =
-        convert_sentences_to_paragraphs(&generated_sentences, *&config.paragraph_length as usize);
-    for paragraph in generated_paragraphs {
-        println!("{}", paragraph);
+    if !config.start_nonterminal.is_empty() {
+        if !grammar.has_rule(&config.start_nonterminal) {
+            return Err(format!("start symbol '{}' has no rule", config.start_nonterminal).into());
+        }
+        grammar.change_start_nonterminal(&config.start_nonterminal);
+    }
+    // the constraints apply to the sentences of a story
+    if let Some(symbol) = config
+        .story
+        .as_ref()
+        .and_then(|plan| plan.sentence.as_ref())
+    {
+        if !grammar.has_rule(symbol) {
+            return Err(format!("sentence symbol '{}' has no rule", symbol).into());
+        }
+        grammar.change_start_nonterminal(symbol);
+    }
+    if let Some(length) = config.length {
+        grammar.set_length_constraint(length)?;
+    }
+    if !config.required.is_empty() {
+        grammar.set_required(&config.required)?;
+    }
+    let start_symbol = grammar.start_nonterminal.clone();
+    grammar.set_post_processor(&start_symbol, config.post_processor.clone());
+    if let Some(plan) = &config.story {
+        // titles are capitalized like headlines, without punctuation
+        let title_processor = PostProcessor {
+            capitalization: Capitalization::Title,
+            terminal_punctuation: String::new(),
+            ..config.post_processor.clone()
+        };
+        for symbol in plan.title.iter().chain(plan.chapter_title.iter()) {
+            if !grammar.has_rule(symbol) {
+                return Err(format!("title symbol '{}' has no rule", symbol).into());
+            }
+            grammar.set_post_processor(symbol, title_processor.clone());
+        }
+        for symbol in plan.chapter.iter().chain(plan.paragraph.iter()) {
+            if !grammar.has_rule(symbol) {
+                return Err(format!("story symbol '{}' has no rule", symbol).into());
+            }
+            grammar.set_post_processor(symbol, config.post_processor.clone());
+        }
+    }
+    Ok(grammar)
+}
+
+/// Generates the sentences, or the story, and writes them to the output.
+fn write_output(
+    config: &Config,
+    grammar: &grammar::Grammar,
+    seed: u64,
+) -> Result<(), Box<dyn Error>> {
+    let mut rng = random::seeded_rng(seed);
+    let start_symbol = grammar.start_nonterminal.clone();
+    let mut context = GenerationContext::new(config.variable_scope);
+    context.repetition = config.repetition.clone();
+    // the symbol of the paragraphs or chapters of a story generated whole
+    let whole = config
+        .story
+        .as_ref()
+        .and_then(|plan| plan.chapter.as_ref().or(plan.paragraph.as_ref()));
+    let mut unique = match config.unique {
+        true => Some(UniqueFilter::new(
+            grammar.language_size(whole.unwrap_or(&start_symbol)),
+        )),
+        false => None,
+    };
+    // Generates the next sentence from 'symbol', or None once there are no
+    // distinct sentences left.
+    let mut next_sentence = |rng: &mut StdRng, context: &mut GenerationContext, symbol: &str| loop {
+        // a rejected duplicate must not leave its variables behind
+        let before = unique.as_ref().map(|_| context.clone());
+        let generated = match generate(grammar, symbol, rng, context, config.derivation) {
+            Ok(generated) => generated,
+            Err(err) => return Some(Err(err)),
+        };
+        let filter = match unique.as_mut() {
+            Some(filter) => filter,
+            None => return Some(Ok(generated)),
+        };
+        if filter.accept(&generated.0) {
+            return Some(Ok(generated));
+        }
+        if let Some(before) = before {
+            *context = before;
+        }
+        if filter.is_exhausted() {
+            return None;
+        }
+    };
+    let mut handle: Box<dyn Write> = match &config.output {
+        Some(path) => {
+            let file = fs::File::create(path)
+                .map_err(|err| format!("can't write to '{}': {}", path, err))?;
+            Box::new(io::BufWriter::new(file))
+        }
+        None => Box::new(io::BufWriter::new(io::stdout().lock())),
+    };
+    match &config.story {
+        Some(plan) => {
+            eprintln!("Generating a story.");
+            let mut ended_early = false;
+            let mut failure = None;
+            let story = plan.build(&mut rng, &start_symbol, seed, |part, rng| {
+                let generated = match part {
+                    Part::Title(symbol) => {
+                        Some(generate(grammar, symbol, rng, &mut context, false))
+                    }
+                    Part::Sentence {
+                        symbol,
+                        starts_paragraph,
+                    } => {
+                        if starts_paragraph {
+                            context.begin_paragraph();
+                        }
+                        let generated = next_sentence(rng, &mut context, symbol);
+                        ended_early = generated.is_none();
+                        generated
+                    }
+                };
+                // the story ends at the first part that can't be generated
+                match generated? {
+                    Ok(generated) => Some(generated),
+                    Err(err) => {
+                        failure = Some(err);
+                        None
+                    }
+                }
+            });
+            if let Some(err) = failure {
+                return Err(err.into());
+            }
+            if ended_early {
+                eprintln!(
+                    "Warning: the grammar ran out of distinct sentences, the story ended early."
+                );
+            }
+            output::write_story(&mut handle, config.format, &story)?;
+        }
+        None => {
+            eprintln!("Generating {} sentences.", config.quantity);
+            // sentences are written as soon as they are generated
+            let columns = Columns {
+                derivation: config.derivation,
+                chapter_index: false,
+            };
+            let mut records = RecordWriter::new(&mut handle, config.format, columns)?;
+            // these make every sentence depend on the sentences before it
+            let sequential = config.unique
+                || config.repetition.is_enabled()
+                || config.variable_scope == VariableScope::Run;
+            if sequential && config.threads > 1 {
+                eprintln!(
+                    "Warning: --unique, --cooldown and --variable-scope run use a single thread."
+                );
+            }
+            if !sequential && config.threads > 1 {
+                write_in_parallel(config, grammar, seed, &context, &mut records)?;
+            }
+            while (records.len() as u64) < config.quantity {
+                let sentence_index = records.len();
+                if (sentence_index as u64).is_multiple_of(config.paragraph_length) {
+                    context.begin_paragraph();
+                }
+                let mut rng =
+                    random::seeded_rng(random::sentence_seed(seed, sentence_index as u64));
+                let (sentence, derivation) =
+                    match next_sentence(&mut rng, &mut context, &start_symbol) {
+                        Some(generated) => generated?,
+                        None => break,
+                    };
+                records.write(&SentenceRecord {
+                    sentence_index,
+                    paragraph_index: (sentence_index as u64 / config.paragraph_length) as usize,
+                    chapter_index: None,
+                    start_symbol: start_symbol.clone(),
+                    seed,
+                    sentence,
+                    derivation,
+                })?;
+            }
+            let written = records.len();
+            records.finish()?;
+            if (written as u64) < config.quantity {
+                eprintln!(
+                    "Warning: the grammar only produced {} distinct sentences, {} were requested.",
+                    written, config.quantity
+                );
+            }
+        }
+    }
+    handle.flush()?;
+    Ok(())
+}
+
+/// Generates the sentences on 'config.threads' threads and writes them in
+/// order. The sentences are generated in batches, and every thread takes a
+/// share of the paragraphs of a batch. Each paragraph starts from a copy of
+/// 'context', which is only valid when sentences share no state across
+/// paragraphs. No more threads are started than there are paragraphs or
+/// available processors.
+fn write_in_parallel<W: Write>(
+    config: &Config,
+    grammar: &grammar::Grammar,
+    seed: u64,
+    context: &GenerationContext,
+    records: &mut RecordWriter<W>,
+) -> Result<(), Box<dyn Error>> {
+    let paragraph_length = config.paragraph_length;
+    let processors = thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = config
+        .threads
+        .min(processors)
+        .min(usize::try_from(config.quantity.div_ceil(paragraph_length)).unwrap_or(usize::MAX))
+        .max(1);
+    let batch = paragraph_length.saturating_mul((threads * PARAGRAPHS_PER_THREAD) as u64);
+    let start_symbol = &grammar.start_nonterminal;
+    let mut first = 0;
+    while first < config.quantity {
+        let end = config.quantity.min(first.saturating_add(batch));
+        let paragraphs: Vec<Range<u64>> = (first..end)
+            .step_by(usize::try_from(paragraph_length).unwrap_or(usize::MAX))
+            .map(|start| start..end.min(start.saturating_add(paragraph_length)))
+            .collect();
+        let share = paragraphs.len().div_ceil(threads);
+        // the sentences of every thread, up to the first that failed
+        let generated: Vec<(Vec<SentenceRecord>, Option<String>)> = thread::scope(|scope| {
+            let workers: Vec<_> = paragraphs
+                .chunks(share)
+                .map(|paragraphs| {
+                    scope.spawn(move || {
+                        let mut context = context.clone();
+                        let mut generated = vec![];
+                        for sentences in paragraphs {
+                            context.begin_paragraph();
+                            for sentence_index in sentences.clone() {
+                                let mut rng =
+                                    random::seeded_rng(random::sentence_seed(seed, sentence_index));
+                                let (sentence, derivation) = match generate(
+                                    grammar,
+                                    start_symbol,
+                                    &mut rng,
+                                    &mut context,
+                                    config.derivation,
+                                ) {
+                                    Ok(generated) => generated,
+                                    Err(err) => return (generated, Some(err)),
+                                };
+                                generated.push(SentenceRecord {
+                                    sentence_index: sentence_index as usize,
+                                    paragraph_index: (sentence_index / paragraph_length) as usize,
+                                    chapter_index: None,
+                                    start_symbol: start_symbol.clone(),
+                                    seed,
+                                    sentence,
+                                    derivation,
+                                });
+                            }
+                        }
+                        (generated, None)
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect()
+        });
+        for (generated, failure) in generated {
+            for record in &generated {
+                records.write(record)?;
+            }
+            if let Some(err) = failure {
+                return Err(err.into());
+            }
+        }
+        first = end;
     }
     Ok(())
 }
 
+/// Generates a sentence from 'symbol', with its derivation when asked for.
+/// Errors when no sentence meets the required items, feature agreement or
+/// length constraint.
+fn generate(
+    grammar: &grammar::Grammar,
+    symbol: &str,
+    rng: &mut StdRng,
+    context: &mut GenerationContext,
+    derivation: bool,
+) -> Result<(String, Option<String>), String> {
+    match derivation {
+        true => {
+            let (sentence, derivation) =
+                grammar.try_generate_derivation_with_context(symbol, rng, context)?;
+            Ok((sentence, Some(derivation)))
+        }
+        false => Ok((
+            grammar.try_generate_sentence_with_context(symbol, rng, context)?,
+            None,
+        )),
+    }
+}
+
 /// Validates the command line arguments, and stores their values.
 #[derive(Debug)]
 pub struct Config {
-    pub filename: String,
-    pub quantity: i32,
+    /// Grammar files, merged in order. `-` is stdin.
+    pub filenames: Vec<String>,
+    /// Number of sentences, unused in story mode.
+    pub quantity: u64,
     pub start_nonterminal: String,
-    pub paragraph_length: i32,
+    pub paragraph_length: u64,
+    pub format: OutputFormat,
+    /// File the output is written to instead of stdout.
+    pub output: Option<String>,
+    pub seed: Option<u64>,
+    pub derivation: bool,
+    pub post_processor: PostProcessor,
+    pub variable_scope: VariableScope,
+    /// Only output distinct sentences.
+    pub unique: bool,
+    pub repetition: RepetitionPolicy,
+    pub length: Option<LengthConstraint>,
+    /// Terminals or non-terminals every sentence must contain.
+    pub required: Vec<String>,
+    /// Generate a story with chapters instead of a list of sentences.
+    pub story: Option<StoryPlan>,
+    /// Generate again whenever the grammar files change.
+    pub watch: bool,
+    /// Number of threads generating sentences. The output is the same for
+    /// any number of threads.
+    pub threads: usize,
 }
 
 impl Config {
     /// Given CLI arguments, parse and validate the arguments. Takes an iterator of
-    /// the env::Args to explicitly describe the info in used for creation.
-    pub fn new(mut args: env::Args) -> Result<Config, &'static str> {
+    /// the arguments (such as env::Args) to explicitly describe the info in used
+    /// for creation.
+    ///
+    /// Positional arguments are one or more grammar files (`-` reads the
+    /// grammar from stdin), the sentence count and the optional paragraph
+    /// length. Flags may appear anywhere:
+    /// `--format <text|json|jsonl|csv|markdown|html>`, `--output <file>`,
+    /// `--seed <number>`, `--watch`, `--threads <n>`,
+    /// `--derivation` and `--start <non-terminal>`. The post-processing of
+    /// sentences is changed with `--capitalize <first|none|lower|upper|title>`,
+    /// `--punctuation <text>`, `--no-punctuation`, `--attach-punctuation`,
+    /// `--keep-whitespace`, `--articles` and `--article-exception <word>`.
+    /// Variables are kept for `--variable-scope <sentence|paragraph|run>`.
+    /// `--unique` removes duplicate sentences. Recent choices are avoided with
+    /// `--cooldown <n>`, `--cooldown-for <non-terminal>=<n>` and
+    /// `--repetition <exclude|downweight>`. The length of sentences is limited
+    /// with `--min-length <n>`, `--max-length <n>` and
+    /// `--length-unit <words|characters>`. `--require <symbol>` only produces
+    /// sentences containing the terminal or non-terminal, and can be repeated.
+    ///
+    /// `--chapters <range>`, `--paragraphs <range>`, `--sentences <range>`,
+    /// `--title <symbol>` and `--chapter-title <symbol>` generate a story
+    /// instead, where a range is a count such as `4` or `3-6`. Chapters and
+    /// paragraphs are generated whole from `--chapter-symbol <symbol>` and
+    /// `--paragraph-symbol <symbol>` instead of counts, and sentences from
+    /// `--sentence-symbol <symbol>` instead of the start symbol. The sentence
+    /// count is not used in story mode.
+    pub fn new<I: Iterator<Item = String>>(mut args: I) -> Result<Config, &'static str> {
         args.next(); // first arg not needed
-        let filename = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a file name"),
+        let mut positional = vec![];
+        let mut format = OutputFormat::default();
+        let mut output = None;
+        let mut seed = None;
+        let mut derivation = false;
+        let mut start_nonterminal = String::new();
+        let mut post_processor = PostProcessor::default();
+        let mut variable_scope = VariableScope::default();
+        let mut unique = false;
+        let mut repetition = RepetitionPolicy::default();
+        let mut min_length: Option<usize> = None;
+        let mut max_length: Option<usize> = None;
+        let mut length_unit = LengthUnit::default();
+        let mut required = vec![];
+        let mut story: Option<StoryPlan> = None;
+        let mut watch = false;
+        let mut threads = 1;
+        while let Some(arg) = args.next() {
+            // allow both '--flag value' and '--flag=value'
+            let (flag, inline_value) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => {
+                    (arg[..i].to_string(), Some(arg[i + 1..].to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            match flag.as_str() {
+                "--format" => {
+                    format = flag_value(inline_value, &mut args, "Missing value for --format.")?
+                        .parse()?;
+                }
+                "--output" => {
+                    output = Some(flag_value(
+                        inline_value,
+                        &mut args,
+                        "Missing value for --output.",
+                    )?);
+                }
+                "--seed" => {
+                    let value = flag_value(inline_value, &mut args, "Missing value for --seed.")?;
+                    seed = Some(
+                        value
+                            .parse::<u64>()
+                            .map_err(|_| "Seed must be a non-negative integer.")?,
+                    );
+                }
+                "--derivation" => derivation = true,
+                "--unique" => unique = true,
+                "--watch" => watch = true,
+                "--threads" => {
+                    let value =
+                        flag_value(inline_value, &mut args, "Missing value for --threads.")?;
+                    threads = match value.parse::<usize>() {
+                        Ok(n) if n > 0 => n,
+                        _ => return Err("Thread count must be a positive integer."),
+                    };
+                }
+                "--start" => {
+                    start_nonterminal =
+                        flag_value(inline_value, &mut args, "Missing value for --start.")?;
+                }
+                "--capitalize" => {
+                    post_processor.capitalization =
+                        flag_value(inline_value, &mut args, "Missing value for --capitalize.")?
+                            .parse()?;
+                }
+                "--punctuation" => {
+                    post_processor.terminal_punctuation =
+                        flag_value(inline_value, &mut args, "Missing value for --punctuation.")?;
+                }
+                "--no-punctuation" => post_processor.terminal_punctuation = String::new(),
+                "--attach-punctuation" => post_processor.attach_punctuation = true,
+                "--keep-whitespace" => post_processor.normalize_whitespace = false,
+                "--articles" => {
+                    post_processor
+                        .articles
+                        .get_or_insert_with(ArticleAgreement::default);
+                }
+                "--article-exception" => {
+                    let word = flag_value(
+                        inline_value,
+                        &mut args,
+                        "Missing value for --article-exception.",
+                    )?;
+                    post_processor
+                        .articles
+                        .get_or_insert_with(ArticleAgreement::default)
+                        .add_exception(&word);
+                }
+                "--variable-scope" => {
+                    variable_scope = flag_value(
+                        inline_value,
+                        &mut args,
+                        "Missing value for --variable-scope.",
+                    )?
+                    .parse()?;
+                }
+                "--cooldown" => {
+                    repetition.window =
+                        flag_value(inline_value, &mut args, "Missing value for --cooldown.")?
+                            .parse()
+                            .map_err(|_| "Cooldown must be a non-negative integer.")?;
+                }
+                "--cooldown-for" => {
+                    let value =
+                        flag_value(inline_value, &mut args, "Missing value for --cooldown-for.")?;
+                    let (key, window) = value
+                        .rsplit_once('=')
+                        .ok_or("Cooldown for a non-terminal must look like <adj>=3.")?;
+                    let window = window
+                        .parse()
+                        .map_err(|_| "Cooldown must be a non-negative integer.")?;
+                    repetition.windows.insert(String::from(key), window);
+                }
+                "--repetition" => {
+                    repetition.mode =
+                        flag_value(inline_value, &mut args, "Missing value for --repetition.")?
+                            .parse()?;
+                }
+                "--min-length" => {
+                    min_length = Some(
+                        flag_value(inline_value, &mut args, "Missing value for --min-length.")?
+                            .parse()
+                            .map_err(|_| "Length must be a non-negative integer.")?,
+                    );
+                }
+                "--max-length" => {
+                    max_length = Some(
+                        flag_value(inline_value, &mut args, "Missing value for --max-length.")?
+                            .parse()
+                            .map_err(|_| "Length must be a non-negative integer.")?,
+                    );
+                }
+                "--length-unit" => {
+                    length_unit =
+                        flag_value(inline_value, &mut args, "Missing value for --length-unit.")?
+                            .parse()?;
+                }
+                "--require" => {
+                    required.push(flag_value(
+                        inline_value,
+                        &mut args,
+                        "Missing value for --require.",
+                    )?);
+                }
+                "--chapters" => {
+                    story.get_or_insert_with(StoryPlan::default).chapters =
+                        flag_value(inline_value, &mut args, "Missing value for --chapters.")?
+                            .parse()?;
+                }
+                "--paragraphs" => {
+                    story.get_or_insert_with(StoryPlan::default).paragraphs =
+                        flag_value(inline_value, &mut args, "Missing value for --paragraphs.")?
+                            .parse()?;
+                }
+                "--sentences" => {
+                    story.get_or_insert_with(StoryPlan::default).sentences =
+                        flag_value(inline_value, &mut args, "Missing value for --sentences.")?
+                            .parse::<CountRange>()?;
+                }
+                "--title" => {
+                    story.get_or_insert_with(StoryPlan::default).title = Some(flag_value(
+                        inline_value,
+                        &mut args,
+                        "Missing value for --title.",
+                    )?);
+                }
+                "--chapter-title" => {
+                    story.get_or_insert_with(StoryPlan::default).chapter_title = Some(flag_value(
+                        inline_value,
+                        &mut args,
+                        "Missing value for --chapter-title.",
+                    )?);
+                }
+                "--chapter-symbol" => {
+                    story.get_or_insert_with(StoryPlan::default).chapter = Some(flag_value(
+                        inline_value,
+                        &mut args,
+                        "Missing value for --chapter-symbol.",
+                    )?);
+                }
+                "--paragraph-symbol" => {
+                    story.get_or_insert_with(StoryPlan::default).paragraph = Some(flag_value(
+                        inline_value,
+                        &mut args,
+                        "Missing value for --paragraph-symbol.",
+                    )?);
+                }
+                "--sentence-symbol" => {
+                    story.get_or_insert_with(StoryPlan::default).sentence = Some(flag_value(
+                        inline_value,
+                        &mut args,
+                        "Missing value for --sentence-symbol.",
+                    )?);
+                }
+                _ if flag.starts_with("--") => return Err("Unknown flag."),
+                _ => positional.push(arg),
+            }
+        }
+        // every argument before the first number is a grammar file
+        let mut positional = positional.into_iter().peekable();
+        let mut filenames = vec![];
+        let is_number = |arg: &String| {
+            let digits = arg.strip_prefix('-').unwrap_or(arg);
+            !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
         };
-        let quantity = match args.next() {
+        while let Some(arg) = positional.next_if(|arg| !is_number(arg)) {
+            filenames.push(arg);
+        }
+        if filenames.is_empty() {
+            return Err("Didn't get a file name");
+        }
+        if watch && filenames.iter().any(|filename| filename == "-") {
+            return Err("--watch can't be used when the grammar is read from stdin.");
+        }
+        let quantity = match positional.next() {
+            None if story.is_some() => 0,
+            Some(_) if story.is_some() => {
+                return Err("Use --sentences and --paragraphs instead of counts in story mode.")
+            }
             None => return Err("Didn't get a sentence count"),
-            // Raise error if 3rd argument is not an integer.
-            Some(arg) => match arg.parse::<i32>() {
+            // Raise error if the count is not an integer.
+            Some(arg) => match arg.parse::<u64>() {
                 Ok(i) => match i > 0 {
                     true => i,
-                    false => return Err("Third argument must be positive integer."),
+                    false => return Err("Sentence count must be positive integer."),
                 },
-                Err(_e) => return Err("Third argument was not an integer."),
+                Err(_e) if arg.starts_with('-') => {
+                    return Err("Sentence count must be positive integer.")
+                }
+                Err(_e) => return Err("Sentence count was not an integer."),
             },
         };
-        let paragraph_length = match args.next() {
+        let paragraph_length = match positional.next() {
             None => 1, // default
             // convert to int
-            Some(arg) => match arg.parse::<i32>() {
+            Some(arg) => match arg.parse::<u64>() {
                 Ok(i) => match i > 0 {
                     true => i,
-                    false => return Err("Fourth argument must be positive integer."),
+                    false => return Err("Paragraph length must be positive integer."),
                 },
-                // Raise error if 4th argument is not an integer
-                Err(_e) => return Err("Fourth argument was not an integer."),
+                Err(_e) if arg.starts_with('-') => {
+                    return Err("Paragraph length must be positive integer.")
+                }
+                // Raise error if the paragraph length is not an integer
+                Err(_e) => return Err("Paragraph length was not an integer."),
             },
         };
 
+        let length = match (min_length, max_length) {
+            (None, None) => None,
+            (min, max) => Some(LengthConstraint {
+                min: min.unwrap_or(0),
+                max: max.unwrap_or(length::UNBOUNDED),
+                unit: length_unit,
+            }),
+        };
+        if let Some(length) = &length {
+            if length.min > length.max {
+                return Err("Minimum length can't be larger than maximum length.");
+            }
+        }
+
         Ok(Config {
-            filename,
+            filenames,
             quantity,
             paragraph_length,
-            start_nonterminal: String::new(),
+            format,
+            output,
+            seed,
+            derivation,
+            start_nonterminal,
+            post_processor,
+            variable_scope,
+            unique,
+            repetition,
+            length,
+            required,
+            story,
+            watch,
+            threads,
         })
     }
 }
 
+/// Get the value of a flag, either from '--flag=value' or from the next argument.
+fn flag_value<I: Iterator<Item = String>>(
+    inline_value: Option<String>,
+    args: &mut I,
+    missing: &'static str,
+) -> Result<String, &'static str> {
+    inline_value.or_else(|| args.next()).ok_or(missing)
+}
+
 /// Converts generated sentences into paragraphs of given sentence length
 ///
 /// # Example
@@ -113,24 +824,46 @@ pub fn convert_sentences_to_paragraphs(slice: &[String], paragraph_length: usize
 
 /// Convert lines from a file into grammar rules
 /// Program exits if any lines do not follow the rules listed in the README.md
-pub fn parse_file<'a>(content: &'a str) -> Vec<Rule> {
+pub fn parse_file(content: &str) -> Vec<Rule> {
+    try_parse_file(content).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    })
+}
+
+/// Convert lines from a file into grammar rules, or describe the first line
+/// that does not follow the rules listed in the README.md.
+///
+/// # Example
+/// ```
+/// use story_gen::try_parse_file;
+///
+/// let rules = try_parse_file("// names\n<pro> = Mike | Sarah\n").unwrap();
+/// assert_eq!(rules[0].right_hand, vec!["Mike", "Sarah"]);
+/// assert!(try_parse_file("<pro> Mike").is_err());
+/// ```
+pub fn try_parse_file(content: &str) -> Result<Vec<Rule>, String> {
     // would prefer to split up the logic somewhat, but I believe this is the most
     // performant way to reference the line number when errors arise.
     content
         .lines()
         .enumerate() // used for errors to get line number
         .filter(|(_, line)| !should_ignore_line(line))
-        .map(|(line_num, line)| {
-            Rule::new(line).unwrap_or_else(|err| {
-                eprintln!("Problem parsing line {}: {}", line_num as i32, err);
-                eprintln!("line's content is: '{}'", line);
-                process::exit(1);
-            })
-        })
+        .map(|(line_num, line)| parse_line(line_num, line))
         .collect()
 }
 
-fn should_ignore_line(line: &str) -> bool {
+/// Parse a single line of a file, describing the problem if it is not a rule.
+pub(crate) fn parse_line(line_num: usize, line: &str) -> Result<Rule, String> {
+    Rule::new(line).map_err(|err| {
+        format!(
+            "Problem parsing line {}: {}\nline's content is: '{}'",
+            line_num as i32, err, line
+        )
+    })
+}
+
+pub(crate) fn should_ignore_line(line: &str) -> bool {
     line.trim().is_empty() || line.trim().starts_with("//")
 }
 
@@ -149,6 +882,7 @@ fn should_ignore_line(line: &str) -> bool {
 /// assert_eq!(example_failure.right_hand, expected.right_hand);
 /// ```
 #[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Rule {
     pub left_hand: String,
     pub right_hand: Vec<String>,
@@ -158,10 +892,21 @@ impl Rule {
     /// and parses the line, and applies the necessary transformations
     /// to convert it into a rule.
     pub fn new(line: &str) -> Result<Rule, &str> {
-        let parsed = line.split("=").collect::<Vec<&str>>();
-        if parsed.len() < 2 {
-            return Err("line doesn't contain '=");
-        }
+        // the delimiter is the first '=' that is not part of features such as
+        // `<np[num=pl]>`
+        let mut depth = 0;
+        let delimiter = line.char_indices().find(|(_, c)| {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                _ => (),
+            }
+            *c == '=' && depth == 0
+        });
+        let parsed = match delimiter {
+            Some((i, _)) => [&line[..i], &line[i + 1..]],
+            None => return Err("line doesn't contain '='"),
+        };
         let left_hand = String::from(parsed[0].trim());
         let right_unparsed = String::from(parsed[1].trim());
         let temp_right_hand = parse_right_hand_side(&right_unparsed);
@@ -210,6 +955,11 @@ pub fn process_rhs_optional_combinations(right_hand: &[String]) -> Vec<String> {
 pub struct Combinations {
     pub options: Vec<String>,
 }
+impl Default for Combinations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Combinations {
     pub fn new() -> Combinations {
         Combinations {
@@ -259,6 +1009,8 @@ pub fn parse_right_hand_side(rhs: &str) -> Vec<String> {
     parsed
 }
 #[cfg(test)]
+// the original tests are kept as they were written
+#[allow(clippy::unwrap_or_default)]
 mod tests {
     use super::*;
     #[test]
@@ -279,6 +1031,12 @@ mod tests {
         assert_eq!(example.right_hand, vec!["cat", "dog"]);
     }
     #[test]
+    fn test_rule_with_features() {
+        let example = Rule::new("<np[num=$n]> = the <noun[num=$n]>").unwrap();
+        assert_eq!(example.left_hand, "<np[num=$n]>");
+        assert_eq!(example.right_hand, vec!["the <noun[num=$n]>"]);
+    }
+    #[test]
     fn test_rule_fails() {
         let example_failure = Rule::new("noun cat | dog").unwrap_or(Rule::default());
         let expected = Rule::default();
@@ -302,4 +1060,297 @@ mod tests {
         let expected: Vec<String> = vec![String::from("1 2 3")];
         assert_eq!(convert_sentences_to_paragraphs(&tester, 3), expected);
     }
+    fn args(values: &[&str]) -> impl Iterator<Item = String> {
+        let mut all = vec![String::from("story_gen")];
+        all.extend(values.iter().map(|v| v.to_string()));
+        all.into_iter()
+    }
+    #[test]
+    fn test_config_positional() {
+        let config = Config::new(args(&["grammar.txt", "4", "2"])).unwrap();
+        assert_eq!(config.filenames, vec!["grammar.txt"]);
+        assert_eq!(config.quantity, 4);
+        assert_eq!(config.paragraph_length, 2);
+        assert_eq!(config.format, OutputFormat::Text);
+        assert_eq!(config.output, None);
+        assert_eq!(config.seed, None);
+    }
+    #[test]
+    fn test_config_several_files() {
+        let config = Config::new(args(&["base.txt", "-", "overrides.txt", "4", "2"])).unwrap();
+        assert_eq!(config.filenames, vec!["base.txt", "-", "overrides.txt"]);
+        assert_eq!(config.quantity, 4);
+        assert_eq!(config.paragraph_length, 2);
+        let config = Config::new(args(&["base.txt", "more.txt", "--chapters", "2"])).unwrap();
+        assert_eq!(config.filenames.len(), 2);
+        assert!(Config::new(args(&["4"])).is_err());
+        let config = Config::new(args(&["grammar.txt", "5000000000"])).unwrap();
+        assert_eq!(config.quantity, 5_000_000_000);
+        let config = Config::new(args(&["grammar.txt", "4", "5000000000"])).unwrap();
+        assert_eq!(config.paragraph_length, 5_000_000_000);
+        assert!(Config::new(args(&["grammar.txt", "-2"])).is_err());
+        assert!(Config::new(args(&["grammar.txt", "4", "-2"])).is_err());
+        assert!(Config::new(args(&["-", "4", "--watch"])).is_err());
+    }
+    #[test]
+    fn test_unmet_requirements_are_errors() {
+        let temp = testing::TempDir::new("unmet");
+        let grammar_file = temp.path().join("grammar.txt");
+        fs::write(
+            &grammar_file,
+            "<s> = <pro> ran | <pro> saw the big wolf\n<pro> = Mike | Sarah",
+        )
+        .unwrap();
+        let output = temp.path().join("out.jsonl");
+        for threads in ["1", "2"] {
+            let config = Config::new(args(&[
+                grammar_file.to_str().unwrap(),
+                "5",
+                "--require",
+                "wolf",
+                "--max-length",
+                "3",
+                "--derivation",
+                "--format=jsonl",
+                "--threads",
+                threads,
+                "--output",
+                output.to_str().unwrap(),
+            ]))
+            .unwrap();
+            let grammar = load_grammar(&config, &mut Source::default()).unwrap();
+            let err = write_output(&config, &grammar, 42).unwrap_err();
+            assert!(err.to_string().contains("missing: wolf"), "{}", err);
+            assert_eq!(fs::read_to_string(&output).unwrap(), "");
+        }
+    }
+    #[test]
+    fn test_gapped_lengths_are_errors() {
+        let temp = testing::TempDir::new("gapped");
+        let grammar_file = temp.path().join("grammar.txt");
+        fs::write(&grammar_file, "<s> = a b | a b c d e f g h i j").unwrap();
+        let config = Config::new(args(&[
+            grammar_file.to_str().unwrap(),
+            "3",
+            "--min-length",
+            "5",
+            "--max-length",
+            "6",
+        ]))
+        .unwrap();
+        let err = load_grammar(&config, &mut Source::default()).unwrap_err();
+        assert!(
+            err.to_string().contains("no sentences of 5 to 6 words"),
+            "{}",
+            err
+        );
+    }
+    #[test]
+    fn test_story_levels_from_symbols() {
+        let temp = testing::TempDir::new("levels");
+        let grammar_file = temp.path().join("grammar.txt");
+        fs::write(
+            &grammar_file,
+            "<s> = <pro> ran\n<paragraph> = <s> and <pro> sat\n<pro> = Mike | Sarah",
+        )
+        .unwrap();
+        let output = temp.path().join("story.jsonl");
+        let story = |extra: &[&str]| {
+            let mut values = vec![grammar_file.to_str().unwrap(), "--chapters", "2"];
+            values.extend_from_slice(&["--format=jsonl", "--output", output.to_str().unwrap()]);
+            values.extend_from_slice(extra);
+            let config = Config::new(args(&values)).unwrap();
+            let grammar = load_grammar(&config, &mut Source::default()).unwrap();
+            write_output(&config, &grammar, 7).map(|_| fs::read_to_string(&output).unwrap())
+        };
+        let records = story(&["--paragraphs", "3", "--paragraph-symbol", "<paragraph>"]).unwrap();
+        assert_eq!(records.lines().count(), 6);
+        for record in records.lines() {
+            assert!(
+                record.contains(r#""start_symbol":"<paragraph>""#),
+                "{}",
+                record
+            );
+            assert!(record.contains(" ran and "), "{}", record);
+        }
+        let records = story(&["--chapter-symbol", "<paragraph>"]).unwrap();
+        assert_eq!(records.lines().count(), 2);
+    }
+    #[test]
+    fn test_output_is_the_same_on_every_thread_count() {
+        let temp = testing::TempDir::new("threads");
+        let directory = temp.path();
+        let grammar_file = directory.join("grammar.txt");
+        fs::write(
+            &grammar_file,
+            "<s> = <pro> <vp>\n<pro> = Mike | Sarah | Will\n<vp> = ran | sat | <vp> and <vp>",
+        )
+        .unwrap();
+        let mut outputs = vec![];
+        // any thread count is clamped to the paragraphs and processors
+        for threads in ["1", "3", "8", "18446744073709551615"] {
+            let output = directory.join(format!("out{}.jsonl", threads));
+            let config = Config::new(args(&[
+                grammar_file.to_str().unwrap(),
+                "1000",
+                "3",
+                "--format=jsonl",
+                "--threads",
+                threads,
+                "--output",
+                output.to_str().unwrap(),
+            ]))
+            .unwrap();
+            let grammar = load_grammar(&config, &mut Source::default()).unwrap();
+            write_output(&config, &grammar, 42).unwrap();
+            outputs.push(fs::read_to_string(output).unwrap());
+        }
+        assert_eq!(outputs[0].lines().count(), 1000);
+        assert!(outputs[0]
+            .lines()
+            .last()
+            .unwrap()
+            .contains("\"sentence_index\":999"));
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0], outputs[2]);
+        assert_eq!(outputs[0], outputs[3]);
+        assert!(Config::new(args(&["grammar.txt", "4", "--threads", "0"])).is_err());
+    }
+    #[test]
+    fn test_config_flags() {
+        let config = Config::new(args(&[
+            "--format=jsonl",
+            "grammar.txt",
+            "4",
+            "--seed",
+            "12",
+            "--derivation",
+            "--unique",
+            "--output",
+            "story.jsonl",
+            "--watch",
+        ]))
+        .unwrap();
+        assert_eq!(config.format, OutputFormat::Jsonl);
+        assert_eq!(config.output.as_deref(), Some("story.jsonl"));
+        assert!(config.watch);
+        assert_eq!(config.seed, Some(12));
+        assert!(config.derivation);
+        assert!(config.unique);
+        assert!(config.required.is_empty());
+        assert_eq!(config.paragraph_length, 1);
+    }
+    #[test]
+    fn test_config_repetition() {
+        let config = Config::new(args(&[
+            "grammar.txt",
+            "4",
+            "--cooldown",
+            "2",
+            "--cooldown-for=<adj>=5",
+            "--repetition",
+            "downweight",
+        ]))
+        .unwrap();
+        assert_eq!(config.repetition.window("<noun>"), 2);
+        assert_eq!(config.repetition.window("<adj>"), 5);
+        assert_eq!(config.repetition.mode, context::RepetitionMode::Downweight);
+        assert!(Config::new(args(&["grammar.txt", "4", "--cooldown-for", "<adj>"])).is_err());
+    }
+    #[test]
+    fn test_config_length() {
+        let config = Config::new(args(&[
+            "grammar.txt",
+            "4",
+            "--max-length=40",
+            "--length-unit",
+            "characters",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.length,
+            Some(LengthConstraint {
+                min: 0,
+                max: 40,
+                unit: LengthUnit::Characters
+            })
+        );
+        let config = Config::new(args(&[
+            "grammar.txt",
+            "4",
+            "--require",
+            "wolf",
+            "--require=<adj>",
+        ]))
+        .unwrap();
+        assert_eq!(config.required, vec!["wolf", "<adj>"]);
+        let min_above_max = args(&["grammar.txt", "4", "--min-length", "5", "--max-length", "4"]);
+        assert!(Config::new(min_above_max).is_err());
+    }
+    #[test]
+    fn test_config_story() {
+        let config = Config::new(args(&[
+            "grammar.txt",
+            "--chapters",
+            "2-3",
+            "--sentences=4",
+            "--title",
+            "<title>",
+        ]))
+        .unwrap();
+        let story = config.story.unwrap();
+        assert_eq!(story.chapters, CountRange { min: 2, max: 3 });
+        assert_eq!(story.paragraphs, CountRange::default());
+        assert_eq!(story.sentences, CountRange { min: 4, max: 4 });
+        assert_eq!(story.title.as_deref(), Some("<title>"));
+        assert_eq!(story.chapter_title, None);
+        assert_eq!(story.paragraph, None);
+        let config = Config::new(args(&[
+            "grammar.txt",
+            "--chapter-symbol",
+            "<chapter>",
+            "--paragraph-symbol=<paragraph>",
+            "--sentence-symbol",
+            "<line>",
+        ]))
+        .unwrap();
+        let story = config.story.unwrap();
+        assert_eq!(story.chapter.as_deref(), Some("<chapter>"));
+        assert_eq!(story.paragraph.as_deref(), Some("<paragraph>"));
+        assert_eq!(story.sentence.as_deref(), Some("<line>"));
+        assert!(Config::new(args(&["grammar.txt", "4", "--chapters", "2"])).is_err());
+        assert!(Config::new(args(&["grammar.txt", "--paragraphs", "0"])).is_err());
+    }
+    #[test]
+    fn test_config_bad_format() {
+        assert!(Config::new(args(&["grammar.txt", "4", "--format", "yaml"])).is_err());
+    }
+    #[test]
+    fn test_config_post_processing() {
+        let config = Config::new(args(&[
+            "grammar.txt",
+            "4",
+            "--start",
+            "<question>",
+            "--capitalize",
+            "none",
+            "--punctuation=?",
+            "--attach-punctuation",
+        ]))
+        .unwrap();
+        assert_eq!(config.start_nonterminal, "<question>");
+        assert_eq!(config.post_processor.capitalization, Capitalization::None);
+        assert_eq!(config.post_processor.terminal_punctuation, "?");
+        assert!(config.post_processor.attach_punctuation);
+        assert_eq!(config.post_processor.articles, None);
+        assert_eq!(config.variable_scope, VariableScope::Sentence);
+    }
+    #[test]
+    fn test_config_articles() {
+        let config =
+            Config::new(args(&["grammar.txt", "4", "--article-exception", "herb"])).unwrap();
+        let articles = config.post_processor.articles.unwrap();
+        assert!(articles.takes_an("herb"));
+        assert!(articles.takes_an("hour"));
+    }
 }
@@ -2,12 +2,22 @@
 //! intiates the application.
 
 use std::env;
+use std::io;
 use std::process;
+use story_gen::repl::{self, Session};
 use story_gen::Config;
 
 fn main() {
-    // let args: Vec<String> = env::args().collect();
-    let config = Config::new(env::args()).unwrap_or_else(|err| {
+    let mut args: Vec<String> = env::args().collect();
+    // 'generate' is the default subcommand, and can be left out
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("repl") => return run_repl(args.get(2)),
+        Some("generate") => {
+            args.remove(1);
+        }
+        _ => (),
+    }
+    let config = Config::new(args.into_iter()).unwrap_or_else(|err| {
         eprintln!("Problem parsing arguments: {}", err);
         process::exit(1);
     });
@@ -16,3 +26,20 @@ fn main() {
         process::exit(1);
     }
 }
+
+/// Starts the interactive console, with the rules of 'filename' if given.
+fn run_repl(filename: Option<&String>) {
+    let mut session = Session::new();
+    if let Some(filename) = filename {
+        match session.load(filename) {
+            Ok(loaded) => println!("{}", loaded),
+            Err(err) => println!("Error: {}", err),
+        }
+    }
+    println!("Type 'help' for the list of commands.");
+    let stdin = io::stdin();
+    if let Err(e) = repl::run(&mut session, stdin.lock(), &mut io::stdout()) {
+        eprintln!("Application error: {}", e);
+        process::exit(1);
+    }
+}
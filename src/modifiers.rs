@@ -0,0 +1,359 @@
+//! This module contains modifiers, which are named transformations applied
+//! to the expansion of a token inside an option, e.g. `<noun>.plural` or
+//! `<pro>.capitalize`. Modifiers can be chained, e.g. `<verb>.past.upper`.
+use crate::postprocess::ArticleAgreement;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A transformation of the text a token expanded into.
+pub type Modifier = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Nouns whose plural does not follow the spelling rules in `pluralize`.
+const IRREGULAR_PLURALS: &[(&str, &str)] = &[
+    ("calf", "calves"),
+    ("child", "children"),
+    ("deer", "deer"),
+    ("elf", "elves"),
+    ("fish", "fish"),
+    ("foot", "feet"),
+    ("goose", "geese"),
+    ("half", "halves"),
+    ("hero", "heroes"),
+    ("knife", "knives"),
+    ("leaf", "leaves"),
+    ("life", "lives"),
+    ("loaf", "loaves"),
+    ("man", "men"),
+    ("moose", "moose"),
+    ("mouse", "mice"),
+    ("ox", "oxen"),
+    ("person", "people"),
+    ("potato", "potatoes"),
+    ("sheep", "sheep"),
+    ("shelf", "shelves"),
+    ("thief", "thieves"),
+    ("tomato", "tomatoes"),
+    ("tooth", "teeth"),
+    ("wife", "wives"),
+    ("wolf", "wolves"),
+    ("woman", "women"),
+];
+
+/// Verbs whose past tense does not follow the spelling rules in `past_tense`.
+const IRREGULAR_PAST: &[(&str, &str)] = &[
+    ("be", "was"),
+    ("begin", "began"),
+    ("bite", "bit"),
+    ("break", "broke"),
+    ("bring", "brought"),
+    ("build", "built"),
+    ("buy", "bought"),
+    ("catch", "caught"),
+    ("choose", "chose"),
+    ("come", "came"),
+    ("cut", "cut"),
+    ("do", "did"),
+    ("drink", "drank"),
+    ("drive", "drove"),
+    ("eat", "ate"),
+    ("fall", "fell"),
+    ("feed", "fed"),
+    ("feel", "felt"),
+    ("fight", "fought"),
+    ("find", "found"),
+    ("fly", "flew"),
+    ("forget", "forgot"),
+    ("get", "got"),
+    ("give", "gave"),
+    ("go", "went"),
+    ("grow", "grew"),
+    ("have", "had"),
+    ("hear", "heard"),
+    ("hide", "hid"),
+    ("hit", "hit"),
+    ("hold", "held"),
+    ("hurt", "hurt"),
+    ("keep", "kept"),
+    ("know", "knew"),
+    ("lead", "led"),
+    ("leave", "left"),
+    ("lend", "lent"),
+    ("let", "let"),
+    ("lose", "lost"),
+    ("make", "made"),
+    ("meet", "met"),
+    ("pay", "paid"),
+    ("put", "put"),
+    ("read", "read"),
+    ("ride", "rode"),
+    ("rise", "rose"),
+    ("run", "ran"),
+    ("say", "said"),
+    ("see", "saw"),
+    ("sell", "sold"),
+    ("send", "sent"),
+    ("shake", "shook"),
+    ("sing", "sang"),
+    ("sit", "sat"),
+    ("sleep", "slept"),
+    ("speak", "spoke"),
+    ("spend", "spent"),
+    ("stand", "stood"),
+    ("steal", "stole"),
+    ("swim", "swam"),
+    ("take", "took"),
+    ("teach", "taught"),
+    ("tell", "told"),
+    ("think", "thought"),
+    ("throw", "threw"),
+    ("understand", "understood"),
+    ("wake", "woke"),
+    ("wear", "wore"),
+    ("win", "won"),
+    ("write", "wrote"),
+];
+
+/// The registry of modifiers that can be used in a grammar. Starts with
+/// the built-in modifiers: capitalize, upper, lower, title, plural, past
+/// and a (prefixes 'a' or 'an').
+///
+/// # Example
+/// ```
+/// use story_gen::modifiers::Modifiers;
+///
+/// let mut modifiers = Modifiers::new();
+/// modifiers.register("shout", |text| format!("{}!", text.to_uppercase()));
+/// assert_eq!(modifiers.apply("plural", "wolf"), Some(String::from("wolves")));
+/// assert_eq!(modifiers.apply("shout", "hey"), Some(String::from("HEY!")));
+/// assert_eq!(modifiers.apply("missing", "hey"), None);
+/// ```
+pub struct Modifiers {
+    registry: HashMap<String, Modifier>,
+}
+
+impl Modifiers {
+    pub fn new() -> Modifiers {
+        let mut modifiers = Modifiers {
+            registry: HashMap::new(),
+        };
+        modifiers.register("capitalize", capitalize);
+        modifiers.register("upper", |text| text.to_uppercase());
+        modifiers.register("lower", |text| text.to_lowercase());
+        modifiers.register("title", |text| {
+            text.split(' ')
+                .map(capitalize)
+                .collect::<Vec<String>>()
+                .join(" ")
+        });
+        modifiers.register("plural", |text| map_last_word(text, pluralize));
+        modifiers.register("past", |text| map_first_word(text, past_tense));
+        let articles = ArticleAgreement::default();
+        modifiers.register("a", move |text| match articles.takes_an(text) {
+            true => format!("an {}", text),
+            false => format!("a {}", text),
+        });
+        modifiers
+    }
+
+    /// Add a modifier, replacing any existing modifier with the same name.
+    pub fn register<F>(&mut self, name: &str, modifier: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.registry.insert(String::from(name), Box::new(modifier));
+    }
+
+    /// Whether a modifier with the name exists.
+    pub fn contains(&self, name: &str) -> bool {
+        self.registry.contains_key(name)
+    }
+
+    /// Applies the named modifier to 'text'. Returns None if there is no
+    /// modifier with the name.
+    pub fn apply(&self, name: &str, text: &str) -> Option<String> {
+        self.registry.get(name).map(|modifier| modifier(text))
+    }
+
+    /// The names of every registered modifier, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.registry.keys().map(|name| name.as_str()).collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+impl Default for Modifiers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Modifiers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Modifiers")
+            .field("registry", &self.names())
+            .finish()
+    }
+}
+
+/// Uppercase the first letter.
+fn capitalize(text: &str) -> String {
+    let mut c = text.chars();
+    match c.next() {
+        None => String::new(),
+        Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
+    }
+}
+
+fn map_last_word(text: &str, map: fn(&str) -> String) -> String {
+    match text.rsplit_once(' ') {
+        Some((start, last)) => format!("{} {}", start, map(last)),
+        None => map(text),
+    }
+}
+
+fn map_first_word(text: &str, map: fn(&str) -> String) -> String {
+    match text.split_once(' ') {
+        Some((first, rest)) => format!("{} {}", map(first), rest),
+        None => map(text),
+    }
+}
+
+/// Looks the word up in a list of irregular forms, keeping the case of the
+/// first letter.
+fn irregular(word: &str, forms: &[(&str, &str)]) -> Option<String> {
+    let lower = word.to_lowercase();
+    let (_, form) = forms.iter().find(|(base, _)| *base == lower)?;
+    match word.starts_with(char::is_uppercase) {
+        true => Some(capitalize(form)),
+        false => Some(String::from(*form)),
+    }
+}
+
+fn is_vowel(c: char) -> bool {
+    matches!(c.to_ascii_lowercase(), 'a' | 'e' | 'i' | 'o' | 'u')
+}
+
+/// The plural of an English noun.
+///
+/// # Example
+/// ```
+/// use story_gen::modifiers::pluralize;
+/// assert_eq!(pluralize("dog"), "dogs");
+/// assert_eq!(pluralize("fox"), "foxes");
+/// assert_eq!(pluralize("puppy"), "puppies");
+/// assert_eq!(pluralize("child"), "children");
+/// ```
+pub fn pluralize(word: &str) -> String {
+    if let Some(form) = irregular(word, IRREGULAR_PLURALS) {
+        return form;
+    }
+    let lower = word.to_lowercase();
+    let chars: Vec<char> = lower.chars().collect();
+    if ["s", "x", "z", "ch", "sh"]
+        .iter()
+        .any(|ending| lower.ends_with(ending))
+    {
+        return format!("{}es", word);
+    }
+    if chars.len() > 1 && chars[chars.len() - 1] == 'y' && !is_vowel(chars[chars.len() - 2]) {
+        return format!("{}ies", without_last_char(word));
+    }
+    format!("{}s", word)
+}
+
+/// The past tense of an English verb.
+///
+/// # Example
+/// ```
+/// use story_gen::modifiers::past_tense;
+/// assert_eq!(past_tense("walk"), "walked");
+/// assert_eq!(past_tense("love"), "loved");
+/// assert_eq!(past_tense("hug"), "hugged");
+/// assert_eq!(past_tense("carry"), "carried");
+/// assert_eq!(past_tense("see"), "saw");
+/// ```
+pub fn past_tense(word: &str) -> String {
+    if let Some(form) = irregular(word, IRREGULAR_PAST) {
+        return form;
+    }
+    let chars: Vec<char> = word.to_lowercase().chars().collect();
+    let n = chars.len();
+    if n == 0 {
+        return String::new();
+    }
+    if chars[n - 1] == 'e' {
+        return format!("{}d", word);
+    }
+    if n > 1 && chars[n - 1] == 'y' && !is_vowel(chars[n - 2]) {
+        return format!("{}ied", without_last_char(word));
+    }
+    // short words ending consonant-vowel-consonant double the last letter
+    let vowels = chars.iter().filter(|c| is_vowel(**c)).count();
+    if n >= 3
+        && vowels == 1
+        && !is_vowel(chars[n - 1])
+        && !matches!(chars[n - 1], 'w' | 'x' | 'y')
+        && is_vowel(chars[n - 2])
+        && !is_vowel(chars[n - 3])
+    {
+        if let Some(last) = word.chars().last() {
+            return format!("{}{}ed", word, last);
+        }
+    }
+    format!("{}ed", word)
+}
+
+/// 'word' without its last character, which may take several bytes.
+fn without_last_char(word: &str) -> &str {
+    match word.char_indices().last() {
+        Some((last, _)) => &word[..last],
+        None => word,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plural_rules() {
+        assert_eq!(pluralize("bus"), "buses");
+        assert_eq!(pluralize("church"), "churches");
+        assert_eq!(pluralize("day"), "days");
+        assert_eq!(pluralize("Wolf"), "Wolves");
+        assert_eq!(pluralize("sheep"), "sheep");
+    }
+    #[test]
+    fn test_past_rules() {
+        assert_eq!(past_tense("stop"), "stopped");
+        assert_eq!(past_tense("play"), "played");
+        assert_eq!(past_tense("visit"), "visited");
+        assert_eq!(past_tense("fix"), "fixed");
+        assert_eq!(past_tense("go"), "went");
+        assert_eq!(past_tense("Bite"), "Bit");
+        assert_eq!(past_tense("taß"), "taßßed");
+        assert_eq!(past_tense("kař"), "kařřed");
+        assert_eq!(past_tense("cárry"), "cárried");
+        assert_eq!(pluralize("bódy"), "bódies");
+    }
+    #[test]
+    fn test_builtin_modifiers() {
+        let modifiers = Modifiers::new();
+        let apply = |name, text| modifiers.apply(name, text).unwrap();
+        assert_eq!(apply("capitalize", "mike saw"), "Mike saw");
+        assert_eq!(apply("upper", "mike"), "MIKE");
+        assert_eq!(apply("title", "the old dog"), "The Old Dog");
+        assert_eq!(apply("plural", "the old dog"), "the old dogs");
+        assert_eq!(apply("past", "go home"), "went home");
+        assert_eq!(apply("a", "owl"), "an owl");
+    }
+    #[test]
+    fn test_register_replaces() {
+        let mut modifiers = Modifiers::new();
+        modifiers.register("upper", |text| format!("<{}>", text));
+        assert_eq!(modifiers.apply("upper", "a"), Some(String::from("<a>")));
+        assert!(modifiers.contains("plural"));
+        assert!(!modifiers.contains("shout"));
+    }
+}
@@ -0,0 +1,663 @@
+//! This module contains the output formats that generated sentences can be
+//! written in, so other programs can consume them without parsing text.
+use crate::document::Story;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// The supported output formats. `Text` is the original paragraph output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Jsonl,
+    Csv,
+    Markdown,
+    /// A standalone HTML document.
+    Html,
+}
+
+impl FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "csv" => Ok(OutputFormat::Csv),
+            "markdown" | "md" => Ok(OutputFormat::Markdown),
+            "html" => Ok(OutputFormat::Html),
+            _ => Err("Format must be one of: text, json, jsonl, csv, markdown, html."),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Markdown => "markdown",
+            OutputFormat::Html => "html",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A single generated sentence together with the information needed to
+/// locate and reproduce it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SentenceRecord {
+    pub sentence_index: usize,
+    pub paragraph_index: usize,
+    /// Only set for sentences of a structured story.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub chapter_index: Option<usize>,
+    pub start_symbol: String,
+    pub seed: u64,
+    pub sentence: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub derivation: Option<String>,
+}
+
+/// Writes the records in the requested format. Records are expected to be
+/// ordered by sentence index.
+pub fn write_records<W: Write>(
+    writer: &mut W,
+    format: OutputFormat,
+    records: &[SentenceRecord],
+) -> io::Result<()> {
+    let columns = Columns {
+        derivation: records.iter().any(|r| r.derivation.is_some()),
+        chapter_index: records.iter().any(|r| r.chapter_index.is_some()),
+    };
+    let mut record_writer = RecordWriter::new(writer, format, columns)?;
+    for record in records {
+        record_writer.write(record)?;
+    }
+    record_writer.finish()?;
+    Ok(())
+}
+
+/// The optional fields written as CSV columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Columns {
+    pub derivation: bool,
+    pub chapter_index: bool,
+}
+
+/// Writes records one at a time as they are generated, so the output of a
+/// large run is never held in memory. `finish` must be called after the
+/// last record to close the document.
+///
+/// # Example
+/// ```
+/// use story_gen::output::{Columns, OutputFormat, RecordWriter, SentenceRecord};
+///
+/// let mut buffer = vec![];
+/// let mut writer = RecordWriter::new(&mut buffer, OutputFormat::Json, Columns::default()).unwrap();
+/// for sentence_index in 0..2 {
+///     writer.write(&SentenceRecord {
+///         sentence_index,
+///         paragraph_index: 0,
+///         chapter_index: None,
+///         start_symbol: String::from("<s>"),
+///         seed: 1,
+///         sentence: String::from("Hi."),
+///         derivation: None,
+///     }).unwrap();
+/// }
+/// writer.finish().unwrap();
+/// assert_eq!(String::from_utf8(buffer).unwrap().lines().count(), 4);
+/// ```
+#[derive(Debug)]
+pub struct RecordWriter<W: Write> {
+    writer: W,
+    format: OutputFormat,
+    columns: Columns,
+    written: usize,
+    /// The paragraph index of the last record.
+    paragraph: Option<usize>,
+}
+
+impl<W: Write> RecordWriter<W> {
+    /// Starts the output, writing what comes before the first record, such
+    /// as the CSV header. CSV only has the optional 'columns' that are set.
+    pub fn new(mut writer: W, format: OutputFormat, columns: Columns) -> io::Result<Self> {
+        match format {
+            OutputFormat::Json => writeln!(writer, "[")?,
+            OutputFormat::Html => {
+                write_html_start(&mut writer, None)?;
+                writeln!(writer, "<section>")?;
+            }
+            OutputFormat::Csv => {
+                write!(writer, "sentence_index,paragraph_index")?;
+                if columns.chapter_index {
+                    write!(writer, ",chapter_index")?;
+                }
+                write!(writer, ",start_symbol,seed,sentence")?;
+                if columns.derivation {
+                    write!(writer, ",derivation")?;
+                }
+                writeln!(writer)?;
+            }
+            _ => (),
+        }
+        Ok(RecordWriter {
+            writer,
+            format,
+            columns,
+            written: 0,
+            paragraph: None,
+        })
+    }
+
+    /// Writes the next record.
+    pub fn write(&mut self, record: &SentenceRecord) -> io::Result<()> {
+        let new_paragraph = self.paragraph != Some(record.paragraph_index);
+        match self.format {
+            OutputFormat::Text | OutputFormat::Markdown | OutputFormat::Html => {
+                let sentence = match self.format {
+                    OutputFormat::Markdown => escape_markdown(&record.sentence),
+                    OutputFormat::Html => escape_html(&record.sentence),
+                    _ => record.sentence.clone(),
+                };
+                if new_paragraph {
+                    if self.paragraph.is_some() {
+                        self.end_paragraph()?;
+                    }
+                    if self.format == OutputFormat::Html {
+                        write!(self.writer, "<p>")?;
+                    }
+                } else {
+                    write!(self.writer, " ")?;
+                }
+                write!(self.writer, "{}", sentence)?;
+            }
+            OutputFormat::Json => {
+                if self.written > 0 {
+                    writeln!(self.writer, ",")?;
+                }
+                write!(self.writer, "  {}", record_to_json(record))?;
+            }
+            OutputFormat::Jsonl => writeln!(self.writer, "{}", record_to_json(record))?,
+            OutputFormat::Csv => {
+                write!(
+                    self.writer,
+                    "{},{}",
+                    record.sentence_index, record.paragraph_index
+                )?;
+                if self.columns.chapter_index {
+                    match record.chapter_index {
+                        Some(chapter_index) => write!(self.writer, ",{}", chapter_index)?,
+                        None => write!(self.writer, ",")?,
+                    }
+                }
+                write!(
+                    self.writer,
+                    ",{},{},{}",
+                    escape_csv(&record.start_symbol),
+                    record.seed,
+                    escape_csv(&record.sentence)
+                )?;
+                if self.columns.derivation {
+                    let derivation = record.derivation.as_deref().unwrap_or("");
+                    write!(self.writer, ",{}", escape_csv(derivation))?;
+                }
+                writeln!(self.writer)?;
+            }
+        }
+        self.paragraph = Some(record.paragraph_index);
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Finishes the paragraph of the last record.
+    fn end_paragraph(&mut self) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Markdown => write!(self.writer, "\n\n"),
+            OutputFormat::Html => writeln!(self.writer, "</p>"),
+            _ => writeln!(self.writer),
+        }
+    }
+
+    /// Number of records written.
+    pub fn len(&self) -> usize {
+        self.written
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.written == 0
+    }
+
+    /// Closes the document and flushes the output, returning the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.format {
+            OutputFormat::Text | OutputFormat::Markdown => {
+                if self.paragraph.is_some() {
+                    writeln!(self.writer)?;
+                }
+            }
+            OutputFormat::Html => {
+                if self.paragraph.is_some() {
+                    self.end_paragraph()?;
+                }
+                writeln!(self.writer, "</section>")?;
+                write_html_end(&mut self.writer)?;
+            }
+            OutputFormat::Json => {
+                if self.written > 0 {
+                    writeln!(self.writer)?;
+                }
+                writeln!(self.writer, "]")?;
+            }
+            OutputFormat::Jsonl | OutputFormat::Csv => (),
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Writes a structured story in the requested format. Text and Markdown
+/// put the titles on their own lines (as headings in Markdown), HTML
+/// writes a document with headings and paragraphs, and JSON nests the
+/// records inside their chapters and paragraphs. JSON lines and
+/// CSV only contain the sentence records, with their chapter index.
+pub fn write_story<W: Write>(
+    writer: &mut W,
+    format: OutputFormat,
+    story: &Story,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text | OutputFormat::Markdown => {
+            let markdown = format == OutputFormat::Markdown;
+            let escape = |value: &str| match markdown {
+                true => escape_markdown(value),
+                false => String::from(value),
+            };
+            // blocks are separated by a blank line in Markdown, and in text
+            // only around titles
+            let mut blocks: Vec<(bool, String)> = vec![];
+            if let Some(title) = &story.title {
+                let heading = if markdown { "# " } else { "" };
+                blocks.push((true, format!("{}{}", heading, escape(title))));
+            }
+            for chapter in &story.chapters {
+                if let Some(title) = &chapter.title {
+                    let heading = if markdown { "## " } else { "" };
+                    blocks.push((true, format!("{}{}", heading, escape(title))));
+                }
+                for paragraph in &chapter.paragraphs {
+                    let sentences: Vec<String> =
+                        paragraph.iter().map(|r| escape(&r.sentence)).collect();
+                    blocks.push((false, sentences.join(" ")));
+                }
+            }
+            for (i, (is_title, block)) in blocks.iter().enumerate() {
+                let after_title = i > 0 && blocks[i - 1].0;
+                if i > 0 && (markdown || *is_title || after_title) {
+                    writeln!(writer)?;
+                }
+                writeln!(writer, "{}", block)?;
+            }
+        }
+        OutputFormat::Html => write_html(writer, story)?,
+        OutputFormat::Json => {
+            let title = |title: &Option<String>| match title {
+                Some(title) => escape_json(title),
+                None => String::from("null"),
+            };
+            writeln!(writer, "{{")?;
+            writeln!(writer, "  \"title\": {},", title(&story.title))?;
+            writeln!(writer, "  \"chapters\": [")?;
+            for (c, chapter) in story.chapters.iter().enumerate() {
+                writeln!(writer, "    {{")?;
+                writeln!(writer, "      \"title\": {},", title(&chapter.title))?;
+                writeln!(writer, "      \"paragraphs\": [")?;
+                for (p, paragraph) in chapter.paragraphs.iter().enumerate() {
+                    writeln!(writer, "        [")?;
+                    for (i, record) in paragraph.iter().enumerate() {
+                        let separator = if i + 1 < paragraph.len() { "," } else { "" };
+                        writeln!(writer, "          {}{}", record_to_json(record), separator)?;
+                    }
+                    let separator = if p + 1 < chapter.paragraphs.len() {
+                        ","
+                    } else {
+                        ""
+                    };
+                    writeln!(writer, "        ]{}", separator)?;
+                }
+                writeln!(writer, "      ]")?;
+                let separator = if c + 1 < story.chapters.len() {
+                    ","
+                } else {
+                    ""
+                };
+                writeln!(writer, "    }}{}", separator)?;
+            }
+            writeln!(writer, "  ]")?;
+            writeln!(writer, "}}")?;
+        }
+        OutputFormat::Jsonl | OutputFormat::Csv => {
+            let records: Vec<SentenceRecord> = story.records().cloned().collect();
+            write_records(writer, format, &records)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the story as a standalone HTML document. Every chapter is a
+/// section, and every paragraph a `<p>`.
+fn write_html<W: Write>(writer: &mut W, story: &Story) -> io::Result<()> {
+    write_html_start(writer, story.title.as_deref())?;
+    for chapter in &story.chapters {
+        writeln!(writer, "<section>")?;
+        if let Some(title) = &chapter.title {
+            writeln!(writer, "<h2>{}</h2>", escape_html(title))?;
+        }
+        for paragraph in &chapter.paragraphs {
+            let sentences: Vec<String> =
+                paragraph.iter().map(|r| escape_html(&r.sentence)).collect();
+            writeln!(writer, "<p>{}</p>", sentences.join(" "))?;
+        }
+        writeln!(writer, "</section>")?;
+    }
+    write_html_end(writer)
+}
+
+/// Writes the head of an HTML document, and opens the article.
+fn write_html_start<W: Write>(writer: &mut W, title: Option<&str>) -> io::Result<()> {
+    let title = title.map(escape_html);
+    writeln!(writer, "<!DOCTYPE html>")?;
+    writeln!(writer, "<html lang=\"en\">")?;
+    writeln!(writer, "<head>")?;
+    writeln!(writer, "<meta charset=\"utf-8\">")?;
+    writeln!(
+        writer,
+        "<title>{}</title>",
+        title.as_deref().unwrap_or("Story")
+    )?;
+    writeln!(
+        writer,
+        "<style>body {{ max-width: 40em; margin: auto; padding: 1em; font-family: Georgia, serif; line-height: 1.5; }}</style>"
+    )?;
+    writeln!(writer, "</head>")?;
+    writeln!(writer, "<body>")?;
+    writeln!(writer, "<article>")?;
+    if let Some(title) = &title {
+        writeln!(writer, "<h1>{}</h1>", title)?;
+    }
+    Ok(())
+}
+
+fn write_html_end<W: Write>(writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "</article>")?;
+    writeln!(writer, "</body>")?;
+    writeln!(writer, "</html>")?;
+    Ok(())
+}
+
+/// Serializes a record as a single line JSON object.
+fn record_to_json(record: &SentenceRecord) -> String {
+    let mut json = format!(
+        "{{\"sentence_index\":{},\"paragraph_index\":{},",
+        record.sentence_index, record.paragraph_index
+    );
+    if let Some(chapter_index) = record.chapter_index {
+        json.push_str(&format!("\"chapter_index\":{},", chapter_index));
+    }
+    json.push_str(&format!(
+        "\"start_symbol\":{},\"seed\":{},\"sentence\":{}",
+        escape_json(&record.start_symbol),
+        record.seed,
+        escape_json(&record.sentence)
+    ));
+    if let Some(derivation) = &record.derivation {
+        json.push_str(&format!(",\"derivation\":{}", escape_json(derivation)));
+    }
+    json.push('}');
+    json
+}
+
+/// Quotes and escapes a string so it is a valid JSON string literal.
+///
+/// # Example
+/// ```
+/// use story_gen::output::escape_json;
+/// assert_eq!(escape_json("say \"hi\""), "\"say \\\"hi\\\"\"");
+/// ```
+pub fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Quotes a CSV field when it contains a delimiter, quote or line break.
+pub fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        String::from(value)
+    }
+}
+
+/// Escapes the characters that Markdown would otherwise interpret as
+/// formatting, including markers of lists and headings at the start.
+///
+/// # Example
+/// ```
+/// use story_gen::output::escape_markdown;
+/// assert_eq!(escape_markdown("a *bold* dog"), "a \\*bold\\* dog");
+/// assert_eq!(escape_markdown("- 1. dogs"), "\\- 1. dogs");
+/// assert_eq!(escape_markdown("1984. The end"), "1984\\. The end");
+/// ```
+pub fn escape_markdown(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    // the position of a character that would start a list or heading: a
+    // leading '-', or the '.' after a leading number
+    let digits = value.chars().take_while(char::is_ascii_digit).count();
+    let block_marker = match value.starts_with(['-', '+', '=']) {
+        true => Some(0),
+        false if digits > 0 && value[digits..].starts_with(['.', ')']) => Some(digits),
+        false => None,
+    };
+    for (i, c) in value.char_indices() {
+        if Some(i) == block_marker
+            || matches!(
+                c,
+                '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '|'
+            )
+        {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes the characters that have a meaning in HTML text and attributes.
+///
+/// # Example
+/// ```
+/// use story_gen::output::escape_html;
+/// assert_eq!(escape_html("<b>Tom & \"Jerry\"</b>"), "&lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;");
+/// ```
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(sentence_index: usize, paragraph_index: usize, sentence: &str) -> SentenceRecord {
+        SentenceRecord {
+            sentence_index,
+            paragraph_index,
+            chapter_index: None,
+            start_symbol: String::from("<sentence>"),
+            seed: 7,
+            sentence: String::from(sentence),
+            derivation: None,
+        }
+    }
+
+    fn render(format: OutputFormat, records: &[SentenceRecord]) -> String {
+        let mut buffer = vec![];
+        write_records(&mut buffer, format, records).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("jsonl".parse::<OutputFormat>(), Ok(OutputFormat::Jsonl));
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+    #[test]
+    fn test_text_groups_paragraphs() {
+        let records = vec![record(0, 0, "A."), record(1, 0, "B."), record(2, 1, "C.")];
+        assert_eq!(render(OutputFormat::Text, &records), "A. B.\nC.\n");
+    }
+    #[test]
+    fn test_jsonl() {
+        let mut with_derivation = record(0, 0, "Mike \"ran\".");
+        with_derivation.derivation = Some(String::from("[<s> Mike]"));
+        assert_eq!(
+            render(OutputFormat::Jsonl, &[with_derivation]),
+            "{\"sentence_index\":0,\"paragraph_index\":0,\"start_symbol\":\"<sentence>\",\"seed\":7,\"sentence\":\"Mike \\\"ran\\\".\",\"derivation\":\"[<s> Mike]\"}\n"
+        );
+    }
+    #[test]
+    fn test_json_array() {
+        let records = vec![record(0, 0, "A."), record(1, 0, "B.")];
+        let output = render(OutputFormat::Json, &records);
+        assert!(output.starts_with("[\n"));
+        assert_eq!(output.matches("},\n").count(), 1);
+        assert!(output.ends_with("}\n]\n"));
+    }
+    #[test]
+    fn test_csv_escaping() {
+        let records = vec![record(0, 0, "Yes, \"no\".")];
+        assert_eq!(
+            render(OutputFormat::Csv, &records),
+            "sentence_index,paragraph_index,start_symbol,seed,sentence\n0,0,<sentence>,7,\"Yes, \"\"no\"\".\"\n"
+        );
+    }
+    #[test]
+    fn test_markdown() {
+        let records = vec![record(0, 0, "A *b*."), record(1, 1, "C.")];
+        assert_eq!(
+            render(OutputFormat::Markdown, &records),
+            "A \\*b\\*.\n\nC.\n"
+        );
+    }
+    fn story() -> Story {
+        let mut first = record(0, 0, "A *b*.");
+        first.chapter_index = Some(0);
+        let mut second = record(1, 1, "C.");
+        second.chapter_index = Some(0);
+        Story {
+            title: Some(String::from("The Dog")),
+            chapters: vec![crate::document::Chapter {
+                title: Some(String::from("One")),
+                paragraphs: vec![vec![first], vec![second]],
+            }],
+        }
+    }
+    #[test]
+    fn test_story_text_and_markdown() {
+        let mut buffer = vec![];
+        write_story(&mut buffer, OutputFormat::Text, &story()).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "The Dog\n\nOne\n\nA *b*.\nC.\n"
+        );
+        let mut buffer = vec![];
+        write_story(&mut buffer, OutputFormat::Markdown, &story()).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "# The Dog\n\n## One\n\nA \\*b\\*.\n\nC.\n"
+        );
+    }
+    #[test]
+    fn test_records_are_written_before_finish() {
+        let mut buffer = vec![];
+        let mut writer =
+            RecordWriter::new(&mut buffer, OutputFormat::Text, Columns::default()).unwrap();
+        writer.write(&record(0, 0, "A.")).unwrap();
+        writer.write(&record(1, 1, "B.")).unwrap();
+        assert_eq!(writer.len(), 2);
+        writer.finish().unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "A.\nB.\n");
+        let mut buffer = vec![];
+        let mut writer =
+            RecordWriter::new(&mut buffer, OutputFormat::Jsonl, Columns::default()).unwrap();
+        writer.write(&record(0, 0, "A.")).unwrap();
+        assert!(String::from_utf8(buffer)
+            .unwrap()
+            .contains("\"sentence\":\"A.\""));
+    }
+    #[test]
+    fn test_html_escapes() {
+        let records = vec![record(0, 0, "Tom & <Jerry>."), record(1, 0, "C.")];
+        let output = render(OutputFormat::Html, &records);
+        assert!(output.starts_with("<!DOCTYPE html>\n"));
+        assert!(output.contains("<title>Story</title>"));
+        assert!(output.contains("<p>Tom &amp; &lt;Jerry&gt;. C.</p>\n"));
+        let mut buffer = vec![];
+        write_story(&mut buffer, OutputFormat::Html, &story()).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output
+            .contains("<h1>The Dog</h1>\n<section>\n<h2>One</h2>\n<p>A *b*.</p>\n<p>C.</p>\n"));
+        assert!(output.ends_with("</html>\n"));
+    }
+    #[test]
+    fn test_story_records() {
+        let mut buffer = vec![];
+        write_story(&mut buffer, OutputFormat::Csv, &story()).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.starts_with("sentence_index,paragraph_index,chapter_index,start_symbol"));
+        assert!(output.ends_with("1,1,0,<sentence>,7,C.\n"));
+        let mut buffer = vec![];
+        write_story(&mut buffer, OutputFormat::Json, &story()).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("\"title\": \"The Dog\""));
+        assert!(output.contains("\"paragraph_index\":1,\"chapter_index\":0,"));
+    }
+}
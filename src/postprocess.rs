@@ -0,0 +1,326 @@
+//! This module contains the post-processing pipeline that turns the raw
+//! expansion of a non-terminal into a finished sentence.
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Words where the first letter does not predict the article, because they
+/// are pronounced with a vowel sound while starting with a consonant, or the
+/// other way around.
+const DEFAULT_ARTICLE_EXCEPTIONS: &[&str] = &[
+    "heir",
+    "heirs",
+    "honest",
+    "honestly",
+    "honor",
+    "honorable",
+    "honour",
+    "honourable",
+    "hour",
+    "hours",
+    "hourly",
+    "euro",
+    "european",
+    "once",
+    "one",
+    "ubiquitous",
+    "unicorn",
+    "uniform",
+    "union",
+    "unique",
+    "unit",
+    "united",
+    "universal",
+    "university",
+    "usage",
+    "use",
+    "useful",
+    "user",
+    "usual",
+    "utensil",
+];
+
+/// How the letters of a finished sentence are capitalized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Capitalization {
+    /// Uppercase the first letter of the sentence.
+    #[default]
+    First,
+    /// Leave the letters exactly as the grammar produced them.
+    None,
+    Lower,
+    Upper,
+    /// Uppercase the first letter of every word, as in headlines.
+    Title,
+}
+
+impl FromStr for Capitalization {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "first" => Ok(Capitalization::First),
+            "none" => Ok(Capitalization::None),
+            "lower" => Ok(Capitalization::Lower),
+            "upper" => Ok(Capitalization::Upper),
+            "title" => Ok(Capitalization::Title),
+            _ => Err("Capitalization must be one of: first, none, lower, upper, title."),
+        }
+    }
+}
+
+/// The configurable steps applied to every generated sentence. The default
+/// trims the sentence, appends a period and uppercases the first letter.
+///
+/// # Example
+/// ```
+/// use story_gen::postprocess::{Capitalization, PostProcessor};
+///
+/// let question = PostProcessor {
+///     terminal_punctuation: String::from("?"),
+///     attach_punctuation: true,
+///     ..PostProcessor::default()
+/// };
+/// assert_eq!(question.apply(" is it , really"), "Is it, really?");
+///
+/// let headline = PostProcessor {
+///     capitalization: Capitalization::Title,
+///     terminal_punctuation: String::new(),
+///     ..PostProcessor::default()
+/// };
+/// assert_eq!(headline.apply(" dog bites man"), "Dog Bites Man");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PostProcessor {
+    pub capitalization: Capitalization,
+    /// Appended to the sentence unless it already ends with '.', '!' or '?'.
+    /// An empty string disables it.
+    pub terminal_punctuation: String,
+    /// Collapse runs of whitespace into a single space.
+    pub normalize_whitespace: bool,
+    /// Remove the space in front of the characters in 'attached_punctuation'
+    /// so they attach to the previous word.
+    pub attach_punctuation: bool,
+    pub attached_punctuation: String,
+    /// When set, every 'a' or 'an' is corrected to agree with the next word.
+    pub articles: Option<ArticleAgreement>,
+}
+
+impl Default for PostProcessor {
+    fn default() -> Self {
+        PostProcessor {
+            capitalization: Capitalization::First,
+            terminal_punctuation: String::from("."),
+            normalize_whitespace: true,
+            attach_punctuation: false,
+            attached_punctuation: String::from(",.!?;:%"),
+            articles: None,
+        }
+    }
+}
+
+impl PostProcessor {
+    /// Runs every step of the pipeline on a raw expansion.
+    pub fn apply(&self, raw: &str) -> String {
+        let mut sentence = if self.normalize_whitespace {
+            raw.split_whitespace().collect::<Vec<&str>>().join(" ")
+        } else {
+            String::from(raw.trim())
+        };
+        if self.attach_punctuation {
+            sentence = attach_punctuation(&sentence, &self.attached_punctuation);
+        }
+        if let Some(articles) = &self.articles {
+            sentence = articles.apply(&sentence);
+        }
+        if !self.terminal_punctuation.is_empty()
+            && !sentence.is_empty()
+            && !sentence.ends_with(['.', '!', '?'])
+        {
+            sentence.push_str(&self.terminal_punctuation);
+        }
+        capitalize(&sentence, self.capitalization)
+    }
+}
+
+/// Chooses between 'a' and 'an' based on the sound of the following word.
+/// The sound is guessed from the first letter, except for the words in
+/// 'exceptions' where the guess is reversed (e.g. "an hour", "a university").
+///
+/// # Example
+/// ```
+/// use story_gen::postprocess::ArticleAgreement;
+///
+/// let articles = ArticleAgreement::default();
+/// assert_eq!(articles.apply("a owl saw an dog"), "an owl saw a dog");
+/// assert_eq!(articles.apply("A hour with an university"), "An hour with a university");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ArticleAgreement {
+    pub exceptions: HashSet<String>,
+}
+
+impl Default for ArticleAgreement {
+    fn default() -> Self {
+        ArticleAgreement::with_exceptions(DEFAULT_ARTICLE_EXCEPTIONS.iter().copied())
+    }
+}
+
+impl ArticleAgreement {
+    /// Create the agreement pass with only the provided exceptions.
+    pub fn with_exceptions<'a, I: IntoIterator<Item = &'a str>>(exceptions: I) -> Self {
+        ArticleAgreement {
+            exceptions: exceptions.into_iter().map(|w| w.to_lowercase()).collect(),
+        }
+    }
+
+    /// Add a word whose article does not follow its first letter.
+    pub fn add_exception(&mut self, word: &str) {
+        self.exceptions.insert(word.to_lowercase());
+    }
+
+    /// Whether the word takes 'an' instead of 'a'.
+    pub fn takes_an(&self, word: &str) -> bool {
+        let cleaned: String = word
+            .trim_matches(|c: char| !c.is_alphanumeric())
+            .to_lowercase();
+        let starts_with_vowel = cleaned.starts_with(['a', 'e', 'i', 'o', 'u']);
+        if self.exceptions.contains(&cleaned) {
+            !starts_with_vowel
+        } else {
+            starts_with_vowel
+        }
+    }
+
+    /// Corrects every article in the sentence, keeping the case of its first letter.
+    pub fn apply(&self, sentence: &str) -> String {
+        let words: Vec<&str> = sentence.split(' ').collect();
+        let mut corrected: Vec<String> = Vec::with_capacity(words.len());
+        for (i, word) in words.iter().enumerate() {
+            let lower = word.to_lowercase();
+            let next = words[i + 1..].iter().find(|w| !w.is_empty());
+            match next {
+                Some(next) if lower == "a" || lower == "an" => {
+                    let article = if self.takes_an(next) { "an" } else { "a" };
+                    if word.starts_with(char::is_uppercase) {
+                        corrected.push(uppercase_first(article));
+                    } else {
+                        corrected.push(String::from(article));
+                    }
+                }
+                _ => corrected.push(String::from(*word)),
+            }
+        }
+        corrected.join(" ")
+    }
+}
+
+/// Removes the whitespace directly in front of any of the 'punctuation'
+/// characters.
+fn attach_punctuation(sentence: &str, punctuation: &str) -> String {
+    let mut attached = String::with_capacity(sentence.len());
+    let mut pending_whitespace = String::new();
+    for c in sentence.chars() {
+        if c.is_whitespace() {
+            pending_whitespace.push(c);
+            continue;
+        }
+        if !punctuation.contains(c) {
+            attached.push_str(&pending_whitespace);
+        }
+        pending_whitespace.clear();
+        attached.push(c);
+    }
+    attached
+}
+
+fn capitalize(sentence: &str, capitalization: Capitalization) -> String {
+    match capitalization {
+        Capitalization::None => String::from(sentence),
+        Capitalization::Lower => sentence.to_lowercase(),
+        Capitalization::Upper => sentence.to_uppercase(),
+        Capitalization::First => uppercase_first(sentence),
+        Capitalization::Title => sentence
+            .split(' ')
+            .map(uppercase_first)
+            .collect::<Vec<String>>()
+            .join(" "),
+    }
+}
+
+/// Uppercase the first letter of the provided string.
+fn uppercase_first(value: &str) -> String {
+    let mut c = value.chars();
+    match c.next() {
+        None => String::new(),
+        Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_matches_original_formatting() {
+        let processor = PostProcessor::default();
+        assert_eq!(processor.apply(" the dog bit a cat"), "The dog bit a cat.");
+        assert_eq!(processor.apply(""), "");
+    }
+    #[test]
+    fn test_terminal_punctuation_not_doubled() {
+        let processor = PostProcessor::default();
+        assert_eq!(processor.apply(" who saw the dog ?"), "Who saw the dog ?");
+        let attaching = PostProcessor {
+            attach_punctuation: true,
+            ..PostProcessor::default()
+        };
+        assert_eq!(attaching.apply(" who saw the dog ?"), "Who saw the dog?");
+    }
+    #[test]
+    fn test_attach_punctuation() {
+        assert_eq!(
+            attach_punctuation("wow , a dog ! yes", ",!"),
+            "wow, a dog! yes"
+        );
+    }
+    #[test]
+    fn test_capitalization_modes() {
+        assert_eq!(capitalize("mike saw", Capitalization::None), "mike saw");
+        assert_eq!(capitalize("Mike saw", Capitalization::Lower), "mike saw");
+        assert_eq!(capitalize("mike saw", Capitalization::Upper), "MIKE SAW");
+        assert_eq!(capitalize("mike saw", Capitalization::Title), "Mike Saw");
+    }
+    #[test]
+    fn test_whitespace() {
+        let keep = PostProcessor {
+            normalize_whitespace: false,
+            ..PostProcessor::default()
+        };
+        assert_eq!(keep.apply("  a  b "), "A  b.");
+        assert_eq!(PostProcessor::default().apply("  a  b "), "A b.");
+    }
+    #[test]
+    fn test_articles_opt_in() {
+        let processor = PostProcessor {
+            articles: Some(ArticleAgreement::default()),
+            ..PostProcessor::default()
+        };
+        assert_eq!(processor.apply(" a apple"), "An apple.");
+        assert_eq!(PostProcessor::default().apply(" a apple"), "A apple.");
+    }
+    #[test]
+    fn test_article_exceptions() {
+        let mut articles = ArticleAgreement::with_exceptions(vec![]);
+        assert_eq!(articles.apply("an honest owl"), "a honest owl");
+        articles.add_exception("Honest");
+        assert_eq!(articles.apply("a honest owl"), "an honest owl");
+        assert_eq!(articles.apply("a \"owl\""), "an \"owl\"");
+        assert_eq!(articles.apply("ends with a"), "ends with a");
+    }
+}
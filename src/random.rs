@@ -1,14 +1,51 @@
 //! Module that provides random helper functions that are used in
 //! the application.
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 
 /// Uses uniform distribution to select a random element from the
 /// provided string slice, and return a copy of the String value.
 pub fn get_random_from_vector(slice: &[String]) -> String {
     let mut rng = rand::thread_rng();
-    if let Some(randomly_picked) = slice.choose(&mut rng) {
+    choose_from_vector(slice, &mut rng)
+}
+
+/// Same as `get_random_from_vector`, but draws from the provided random
+/// number generator so results can be reproduced from a seed.
+pub fn choose_from_vector<R: Rng + ?Sized>(slice: &[String], rng: &mut R) -> String {
+    if let Some(randomly_picked) = slice.choose(rng) {
         String::from(randomly_picked)
     } else {
         String::new()
     }
 }
+
+/// Creates a deterministic random number generator from a seed.
+pub fn seeded_rng(seed: u64) -> StdRng {
+    StdRng::seed_from_u64(seed)
+}
+
+/// The seed of the sentence at 'index' in a run started from 'seed'. It is
+/// the index-th output of a SplitMix64 generator started at 'seed', so every
+/// sentence has its own generator and sentences can be generated in any
+/// order, or on several threads, with the same result.
+///
+/// # Example
+/// ```
+/// use story_gen::random::sentence_seed;
+/// assert_eq!(sentence_seed(7, 3), sentence_seed(7, 3));
+/// assert_ne!(sentence_seed(7, 3), sentence_seed(7, 4));
+/// assert_ne!(sentence_seed(7, 3), sentence_seed(8, 3));
+/// ```
+pub fn sentence_seed(seed: u64, index: u64) -> u64 {
+    let mut z = seed.wrapping_add(index.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Picks a fresh seed when the client did not provide one.
+pub fn random_seed() -> u64 {
+    rand::thread_rng().gen()
+}
@@ -0,0 +1,402 @@
+//! This module contains the interactive console used to edit and test a
+//! grammar. Rules can be added, removed and listed, and the grammar can be
+//! validated, sampled and saved without restarting the program.
+use crate::context::GenerationContext;
+use crate::features;
+use crate::grammar::Grammar;
+use crate::source::Source;
+use crate::{random, Rule};
+use rand::rngs::StdRng;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+const HELP: &str = "\
+Commands:
+  load <file>        replace the grammar with the rules in the file
+  add <rule>         add a rule, or options to a rule, e.g. add <noun> = fox | owl
+  remove <symbol>    remove the rule of a non-terminal
+  list [symbol]      print every rule, or the rule of one non-terminal
+  start <symbol>     generate from another non-terminal
+  validate           check the grammar and list unreachable non-terminals
+  generate [count]   print sample sentences, 5 by default
+  seed <number>      make the samples reproducible
+  save [file]        write the rules to the file, or to the file of the last save
+  help               print this message
+  quit               leave the console";
+
+/// Number of sentences printed by `generate` without a count.
+const DEFAULT_SAMPLES: usize = 5;
+
+/// The result of a command that succeeded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Text to show, which may be empty.
+    Output(String),
+    Quit,
+}
+
+/// The state of an interactive session. The rules are kept as they were
+/// written, so templates are only instantiated in the copy used to validate
+/// and generate, and saving writes them back unchanged.
+///
+/// # Example
+/// ```
+/// use story_gen::repl::{Reply, Session};
+///
+/// let mut session = Session::new();
+/// session.execute("add <s> = <pro> ran").unwrap();
+/// session.execute("add <pro> = Mike").unwrap();
+/// assert_eq!(
+///     session.execute("generate 1"),
+///     Ok(Reply::Output(String::from("Mike ran.")))
+/// );
+/// assert!(session.execute("remove <noun>").is_err());
+/// ```
+#[derive(Debug)]
+pub struct Session {
+    grammar: Grammar,
+    /// The file the rules were last saved to. A loaded file is not saved to
+    /// unless it is named, since saving rewrites its rules.
+    saved: Option<String>,
+    rng: StdRng,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    /// Start a session with an empty grammar.
+    pub fn new() -> Session {
+        Session {
+            grammar: Grammar::new(),
+            saved: None,
+            rng: random::seeded_rng(random::random_seed()),
+        }
+    }
+
+    /// Replace the grammar with the rules in the file at 'path', and the
+    /// files it includes. `save` needs to be given a file after a load, so
+    /// the loaded file is only rewritten when it is named.
+    pub fn load(&mut self, path: &str) -> Result<String, String> {
+        let mut source = Source::default();
+        source.read(Path::new(path))?;
+        let mut grammar = Grammar::new();
+        if let Some(start) = &source.start {
+            grammar.change_start_nonterminal(start);
+        }
+        let count = source.rules.len();
+        for rule in source.rules {
+            grammar.rule_add_from_file(rule);
+        }
+        self.grammar = grammar;
+        self.saved = None;
+        Ok(format!("Loaded {} rules from '{}'.", count, path))
+    }
+
+    /// Runs a single command. Errors are messages meant for the user, and
+    /// leave the session unchanged.
+    pub fn execute(&mut self, line: &str) -> Result<Reply, String> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        let output = match command {
+            "" => String::new(),
+            "load" => self.load(required(argument, "load <file>")?)?,
+            "add" => self.add(required(argument, "add <rule>")?)?,
+            "remove" => self.remove(required(argument, "remove <symbol>")?)?,
+            "list" => self.list(argument)?,
+            "start" => {
+                let symbol = required(argument, "start <symbol>")?;
+                if !self.grammar.has_rule(symbol) {
+                    return Err(format!("no rule for '{}'", symbol));
+                }
+                self.grammar.change_start_nonterminal(symbol);
+                format!("Generating from {}.", symbol)
+            }
+            "validate" => self.validate()?,
+            "generate" => self.generate(argument)?,
+            "seed" => {
+                let seed = required(argument, "seed <number>")?
+                    .parse()
+                    .map_err(|_| "the seed must be a non-negative integer")?;
+                self.rng = random::seeded_rng(seed);
+                format!("Using seed {}.", seed)
+            }
+            "save" => self.save(argument)?,
+            "help" => String::from(HELP),
+            "quit" | "exit" => return Ok(Reply::Quit),
+            _ => {
+                return Err(format!(
+                    "unknown command '{}', type 'help' for the list of commands",
+                    command
+                ))
+            }
+        };
+        Ok(Reply::Output(output))
+    }
+
+    fn add(&mut self, line: &str) -> Result<String, String> {
+        let rule = Rule::new(line).map_err(|err| format!("{}: '{}'", err, line))?;
+        if rule.left_hand.is_empty() {
+            return Err(format!(
+                "a rule needs a non-terminal before '=': '{}'",
+                line
+            ));
+        }
+        if self.grammar.start_nonterminal.is_empty() {
+            self.grammar.change_start_nonterminal(&rule.left_hand);
+        }
+        let reply = format!("Added {} = {}", rule.left_hand, rule.right_hand.join(" | "));
+        self.grammar.rule_add_from_file(rule);
+        Ok(reply)
+    }
+
+    fn remove(&mut self, symbol: &str) -> Result<String, String> {
+        self.grammar
+            .remove_rule(symbol)
+            .map_err(|err| err.to_string())?;
+        match symbol == self.grammar.start_nonterminal {
+            true => Ok(format!(
+                "Removed {}, which is the start symbol. Choose another with 'start <symbol>'.",
+                symbol
+            )),
+            false => Ok(format!("Removed {}.", symbol)),
+        }
+    }
+
+    /// Every rule, or the rules of 'symbol' including the ones with features.
+    fn list(&self, symbol: &str) -> Result<String, String> {
+        let rules = self.grammar.to_string();
+        if symbol.is_empty() {
+            return match rules.is_empty() {
+                true => Ok(String::from("The grammar has no rules.")),
+                false => Ok(String::from(rules.trim_end())),
+            };
+        }
+        let matching: Vec<&str> = rules
+            .lines()
+            .filter(|line| {
+                let key = line.split(" = ").next().unwrap_or_default();
+                key == symbol
+                    || features::parse_featured(key)
+                        .is_some_and(|featured| featured.symbol == symbol)
+            })
+            .collect();
+        match matching.is_empty() {
+            true => Err(format!("no rule for '{}'", symbol)),
+            false => Ok(matching.join("\n")),
+        }
+    }
+
+    fn validate(&self) -> Result<String, String> {
+        let grammar = self.prepared()?;
+        let mut unreachable = grammar.get_unreachable_nonterminals();
+        unreachable.sort_unstable();
+        match unreachable.is_empty() {
+            true => Ok(String::from("The grammar is valid.")),
+            false => Ok(format!(
+                "The grammar is valid. Unreachable non-terminals: {}",
+                unreachable.join(", ")
+            )),
+        }
+    }
+
+    fn generate(&mut self, count: &str) -> Result<String, String> {
+        let count = match count {
+            "" => DEFAULT_SAMPLES,
+            count => match count.parse() {
+                Ok(count) if count > 0 => count,
+                _ => return Err(String::from("the count must be a positive integer")),
+            },
+        };
+        let grammar = self.prepared()?;
+        let start = &grammar.start_nonterminal;
+        let mut context = GenerationContext::default();
+        let sentences: Vec<String> = (0..count)
+            .map(|_| grammar.generate_sentence_with_context(start, &mut self.rng, &mut context))
+            .collect();
+        Ok(sentences.join("\n"))
+    }
+
+    /// Writes every rule, with included rules, in the form of `list`: the
+    /// options of `(x)` written out, without comments, and with the start
+    /// symbol first and the other rules sorted.
+    fn save(&mut self, path: &str) -> Result<String, String> {
+        let path = match (path, &self.saved) {
+            ("", Some(saved)) => saved.clone(),
+            ("", None) => {
+                return Err(String::from(
+                    "no file to save to, use 'save <file>'. Saving rewrites the rules without \
+                     comments or optional items, so name the loaded file to replace it",
+                ))
+            }
+            (path, _) => String::from(path),
+        };
+        let rules = self.grammar.to_string();
+        fs::write(&path, &rules).map_err(|err| format!("can't write to '{}': {}", path, err))?;
+        let reply = format!("Saved {} rules to '{}'.", rules.lines().count(), path);
+        self.saved = Some(path);
+        Ok(reply)
+    }
+
+    /// A copy of the grammar with the templates instantiated, which is
+    /// validated before it is used.
+    fn prepared(&self) -> Result<Grammar, String> {
+        let start = &self.grammar.start_nonterminal;
+        if start.is_empty() {
+            return Err(String::from(
+                "the grammar has no rules, add one with 'add <rule>'",
+            ));
+        }
+        if !self.grammar.has_rule(start) {
+            return Err(format!("start symbol '{}' has no rule", start));
+        }
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal(start);
+        grammar.rules = self.grammar.rules.clone();
+        grammar.featured_rules = self.grammar.featured_rules.clone();
+        grammar.weights = self.grammar.weights.clone();
+        grammar.instantiate_templates()?;
+        grammar.validate()?;
+        Ok(grammar)
+    }
+}
+
+/// The argument of a command, or how to use the command when it is missing.
+fn required<'a>(argument: &'a str, usage: &str) -> Result<&'a str, String> {
+    match argument.is_empty() {
+        true => Err(format!("usage: {}", usage)),
+        false => Ok(argument),
+    }
+}
+
+/// Runs commands from 'input' until `quit` or the end of the input. Replies
+/// and errors are written to 'output', so a mistake never ends the session.
+pub fn run<R: BufRead, W: Write>(
+    session: &mut Session,
+    input: R,
+    output: &mut W,
+) -> io::Result<()> {
+    write!(output, "> ")?;
+    output.flush()?;
+    for line in input.lines() {
+        match session.execute(&line?) {
+            Ok(Reply::Quit) => return Ok(()),
+            Ok(Reply::Output(text)) if text.is_empty() => (),
+            Ok(Reply::Output(text)) => writeln!(output, "{}", text)?,
+            Err(err) => writeln!(output, "Error: {}", err)?,
+        }
+        write!(output, "> ")?;
+        output.flush()?;
+    }
+    writeln!(output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(reply: Result<Reply, String>) -> String {
+        match reply.unwrap() {
+            Reply::Output(text) => text,
+            Reply::Quit => panic!("unexpected quit"),
+        }
+    }
+
+    #[test]
+    fn test_edit_and_list() {
+        let mut session = Session::new();
+        output(session.execute("add <s> = <pro> saw (the) <noun>"));
+        output(session.execute("add <pro> = Mike | Sarah"));
+        output(session.execute("add <noun> = dog"));
+        output(session.execute("add <noun> = cat"));
+        assert_eq!(
+            output(session.execute("list")),
+            "<s> = <pro> saw <noun> | <pro> saw the <noun>\n<noun> = dog | cat\n<pro> = Mike | Sarah"
+        );
+        assert_eq!(output(session.execute("list <noun>")), "<noun> = dog | cat");
+        assert_eq!(output(session.execute("remove <pro>")), "Removed <pro>.");
+        assert_eq!(
+            session.execute("list <pro>"),
+            Err(String::from("no rule for '<pro>'"))
+        );
+        assert_eq!(output(session.execute("validate")), "The grammar is valid.");
+        assert!(output(session.execute("remove <s>")).contains("start symbol"));
+        assert_eq!(
+            session.execute("validate"),
+            Err(String::from("start symbol '<s>' has no rule"))
+        );
+    }
+    #[test]
+    fn test_generate_with_seed() {
+        let mut session = Session::new();
+        output(session.execute("add <s> = <pro> ran"));
+        output(session.execute("add <pro> = Mike | Sarah | Will"));
+        output(session.execute("add <noun> = dog"));
+        output(session.execute("seed 4"));
+        let first = output(session.execute("generate 3"));
+        assert_eq!(first.lines().count(), 3);
+        output(session.execute("seed 4"));
+        assert_eq!(output(session.execute("generate 3")), first);
+        assert!(session.execute("generate none").is_err());
+        assert_eq!(
+            output(session.execute("validate")),
+            "The grammar is valid. Unreachable non-terminals: <noun>"
+        );
+        output(session.execute("start <pro>"));
+        assert!(!output(session.execute("generate 1")).contains("ran"));
+        assert!(session.execute("start <verb>").is_err());
+    }
+    #[test]
+    fn test_templates_are_saved_unchanged() {
+        let temp = crate::testing::TempDir::new("repl");
+        let path = temp.path().join("grammar.txt");
+        let path = path.to_str().unwrap();
+        let mut session = Session::new();
+        assert!(session.execute("save").is_err());
+        output(session.execute("add <s> = <list(<pro>)> ran"));
+        output(session.execute("add <list(X)> = X | X and <list(X)>"));
+        output(session.execute("add <pro> = Mike | Sarah"));
+        assert_eq!(output(session.execute("validate")), "The grammar is valid.");
+        output(session.execute(&format!("save {}", path)));
+        let mut loaded = Session::new();
+        output(loaded.execute(&format!("load {}", path)));
+        assert_eq!(
+            output(loaded.execute("list")),
+            output(session.execute("list"))
+        );
+        // the loaded file is only replaced when it is named
+        fs::write(path, "// comment\n<s> = (a) b").unwrap();
+        output(loaded.execute(&format!("load {}", path)));
+        assert!(loaded.execute("save").is_err());
+        assert_eq!(fs::read_to_string(path).unwrap(), "// comment\n<s> = (a) b");
+        output(loaded.execute(&format!("save {}", path)));
+        output(loaded.execute("add <s> = c"));
+        output(loaded.execute("save"));
+        assert_eq!(fs::read_to_string(path).unwrap(), "<s> = b | a b | c\n");
+        fs::remove_file(path).unwrap();
+        assert!(loaded.execute(&format!("load {}", path)).is_err());
+    }
+    #[test]
+    fn test_run_reports_errors() {
+        let mut session = Session::new();
+        let input = "add <s> = Mike ran\nadd <pro> Mike\njump\ngenerate 1\nquit\ngenerate 1\n";
+        let mut buffer = vec![];
+        run(&mut session, input.as_bytes(), &mut buffer).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "> Added <s> = Mike ran\n\
+             > Error: line doesn't contain '=': '<pro> Mike'\n\
+             > Error: unknown command 'jump', type 'help' for the list of commands\n\
+             > Mike ran.\n\
+             > "
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! This module contains the serialized form of a grammar, available with the
+//! `serde` feature. The rules, weights of options, start symbol,
+//! post-processing and sentence constraints are stored together with a schema version, so stored
+//! grammars can still be read after the library changes. Caches such as the
+//! compiled rules and the validation results are not stored, and neither
+//! are modifiers registered with `Grammar::register_modifier`.
+//!
+//! The other model types, such as `Rule`, `Validator`, `PostProcessor` and
+//! `SentenceRecord`, derive `Serialize` and `Deserialize` directly.
+use crate::grammar::Grammar;
+use crate::length::LengthConstraint;
+use crate::postprocess::PostProcessor;
+use crate::Rule;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+/// The version of the serialized grammar written by this library. Grammars
+/// with a newer version are rejected.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A grammar as it is serialized.
+#[derive(Debug, Serialize, Deserialize)]
+struct GrammarSchema {
+    version: u32,
+    start: String,
+    /// Rules without features sorted by their LHS, then the rules with
+    /// features in the order they were added.
+    rules: Vec<Rule>,
+    /// The weights of the options of the rules that have them, see
+    /// `Grammar::set_weight`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    weights: BTreeMap<String, Vec<u32>>,
+    #[serde(default)]
+    default_post_processor: PostProcessor,
+    #[serde(default)]
+    post_processors: BTreeMap<String, PostProcessor>,
+    #[serde(default)]
+    length_constraint: Option<LengthConstraint>,
+    #[serde(default)]
+    required: Vec<String>,
+}
+
+/// Writes the grammar in the versioned schema.
+///
+/// # Example
+/// ```
+/// use story_gen::grammar::Grammar;
+///
+/// let mut grammar = Grammar::new();
+/// grammar.change_start_nonterminal("<s>");
+/// grammar.rule_add("<s>", "<pro> ran");
+/// grammar.rule_add("<pro>", "Mike | Sarah");
+/// let json = serde_json::to_string(&grammar).unwrap();
+/// assert!(json.starts_with(r#"{"version":1,"start":"<s>","rules":[{"left_hand":"<pro>""#));
+///
+/// let copy: Grammar = serde_json::from_str(&json).unwrap();
+/// assert_eq!(copy.to_string(), grammar.to_string());
+/// ```
+impl Serialize for Grammar {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut keys: Vec<&String> = self.rules.keys().collect();
+        keys.sort();
+        let mut rules: Vec<Rule> = keys
+            .into_iter()
+            .map(|key| Rule {
+                left_hand: key.clone(),
+                right_hand: self.rules[key].clone(),
+            })
+            .collect();
+        let mut symbols: Vec<&String> = self.featured_rules.keys().collect();
+        symbols.sort();
+        for symbol in symbols {
+            rules.extend(self.featured_rules[symbol].iter().map(|rule| Rule {
+                left_hand: rule.head.clone(),
+                right_hand: rule.options.clone(),
+            }));
+        }
+        GrammarSchema {
+            version: SCHEMA_VERSION,
+            start: self.start_nonterminal.clone(),
+            rules,
+            weights: self
+                .weights
+                .iter()
+                .map(|(key, weights)| (key.clone(), weights.clone()))
+                .collect(),
+            default_post_processor: self.default_post_processor.clone(),
+            post_processors: self
+                .post_processors
+                .iter()
+                .map(|(key, processor)| (key.clone(), processor.clone()))
+                .collect(),
+            length_constraint: self.length_constraint,
+            required: self.required.clone(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Reads a grammar written in any version of the schema up to
+/// SCHEMA_VERSION. The length constraint and required items are applied
+/// again, so they fail like `set_length_constraint` and `set_required` when
+/// the rules can't satisfy them, and weights are checked like
+/// `set_weight`. The rules are not validated.
+impl<'de> Deserialize<'de> for Grammar {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let schema = GrammarSchema::deserialize(deserializer)?;
+        if schema.version > SCHEMA_VERSION {
+            return Err(D::Error::custom(format!(
+                "grammar schema version {} is newer than the supported version {}",
+                schema.version, SCHEMA_VERSION
+            )));
+        }
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal(&schema.start);
+        for rule in schema.rules {
+            grammar.rule_add_from_file(rule);
+        }
+        for (key, weights) in schema.weights {
+            for (index, weight) in weights.into_iter().enumerate() {
+                grammar
+                    .set_weight(&key, index, weight)
+                    .map_err(D::Error::custom)?;
+            }
+        }
+        grammar.default_post_processor = schema.default_post_processor;
+        grammar.post_processors = schema.post_processors.into_iter().collect();
+        if let Some(constraint) = schema.length_constraint {
+            grammar
+                .set_length_constraint(constraint)
+                .map_err(D::Error::custom)?;
+        }
+        if !schema.required.is_empty() {
+            grammar
+                .set_required(&schema.required)
+                .map_err(D::Error::custom)?;
+        }
+        Ok(grammar)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::length::LengthUnit;
+    use crate::postprocess::Capitalization;
+    use crate::random;
+
+    #[test]
+    fn test_grammar_round_trip() {
+        let mut grammar = Grammar::new();
+        grammar.change_start_nonterminal("<s>");
+        grammar.rule_add("<s>", "<np[num=$n]> <vp[num=$n]> | <title>");
+        grammar.rule_add("<np[num=sg]>", "the dog | the cat");
+        grammar.rule_add("<np[num=pl]>", "the dogs");
+        grammar.rule_add("<vp[num=sg]>", "runs | sleeps");
+        grammar.rule_add("<vp[num=pl]>", "run");
+        grammar.rule_add("<title>", "a day");
+        let title = PostProcessor {
+            capitalization: Capitalization::Title,
+            ..PostProcessor::default()
+        };
+        grammar.set_post_processor("<title>", title);
+        let constraint = LengthConstraint {
+            min: 2,
+            max: 3,
+            unit: LengthUnit::Words,
+        };
+        grammar.set_length_constraint(constraint).unwrap();
+        grammar.set_required(&["dog"]).unwrap();
+        grammar.set_weight("<np[num=sg]>", 1, 4).unwrap();
+
+        let json = serde_json::to_string(&grammar).unwrap();
+        let copy: Grammar = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.to_string(), grammar.to_string());
+        assert_eq!(serde_json::to_string(&copy).unwrap(), json);
+        assert_eq!(copy.required, vec!["dog"]);
+        assert_eq!(copy.weight("<np[num=sg]>", 1), Some(4));
+        assert_eq!(
+            copy.post_processor("<title>").capitalization,
+            Capitalization::Title
+        );
+        for seed in 0..20 {
+            assert_eq!(
+                copy.generate_sentence_with_rng("<s>", &mut random::seeded_rng(seed)),
+                grammar.generate_sentence_with_rng("<s>", &mut random::seeded_rng(seed))
+            );
+        }
+    }
+    #[test]
+    fn test_schema_versions_and_defaults() {
+        let json =
+            r#"{"version":1,"start":"<s>","rules":[{"left_hand":"<s>","right_hand":["hi"]}]}"#;
+        let grammar: Grammar = serde_json::from_str(json).unwrap();
+        assert_eq!(grammar.generate_sentence("<s>"), "Hi.");
+        let newer = json.replace("\"version\":1", "\"version\":2");
+        let err = serde_json::from_str::<Grammar>(&newer).unwrap_err();
+        assert!(err.to_string().contains("version 2 is newer"), "{}", err);
+        let impossible = json.replace(
+            "}]}",
+            r#"}],"length_constraint":{"min":3,"max":4,"unit":"words"}}"#,
+        );
+        assert!(serde_json::from_str::<Grammar>(&impossible).is_err());
+        let heavy = json.replace("}]}", r#"}],"weights":{"<s>":[2000000]}}"#);
+        let err = serde_json::from_str::<Grammar>(&heavy).unwrap_err();
+        assert!(err.to_string().contains("not 2000000"), "{}", err);
+    }
+    #[test]
+    fn test_model_types_use_lowercase_names() {
+        let processor: PostProcessor =
+            serde_json::from_str(r#"{"capitalization":"upper","terminal_punctuation":"!"}"#)
+                .unwrap();
+        assert_eq!(processor.apply("mike ran"), "MIKE RAN!");
+        let record = crate::output::SentenceRecord {
+            sentence_index: 0,
+            paragraph_index: 0,
+            chapter_index: None,
+            start_symbol: String::from("<s>"),
+            seed: 1,
+            sentence: String::from("Hi."),
+            derivation: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"sentence_index":0,"paragraph_index":0,"start_symbol":"<s>","seed":1,"sentence":"Hi."}"#
+        );
+    }
+}
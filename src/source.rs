@@ -0,0 +1,222 @@
+//! This module reads grammar files. A file can include the rules of other
+//! files with a line such as `@include words.txt`, where the path is
+//! relative to the including file.
+use crate::{parse_line, should_ignore_line, Rule};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// The rules read from one or more grammar files, in the order they were
+/// written with included rules in place of the `@include` line.
+///
+/// # Example
+/// ```
+/// use story_gen::source::Source;
+/// use std::fs;
+///
+/// let dir = std::env::temp_dir().join(format!("story_gen_source_doc_{}", std::process::id()));
+/// fs::create_dir_all(&dir).unwrap();
+/// fs::write(dir.join("words.txt"), "<pro> = Mike | Sarah").unwrap();
+/// fs::write(dir.join("main.txt"), "@include words.txt\n<s> = <pro> ran").unwrap();
+///
+/// let mut source = Source::default();
+/// source.read(&dir.join("main.txt")).unwrap();
+/// assert_eq!(source.rules.len(), 2);
+/// assert_eq!(source.start.as_deref(), Some("<s>"));
+/// assert_eq!(source.files.len(), 2);
+/// fs::remove_dir_all(&dir).unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct Source {
+    pub rules: Vec<Rule>,
+    /// The LHS of the first rule written in a file that was read directly,
+    /// or of the first included rule when those files have no rules.
+    pub start: Option<String>,
+    /// Every file that was read or attempted, including the ones that
+    /// failed, so they can be watched for changes.
+    pub files: Vec<PathBuf>,
+    /// Whether 'start' comes from a file that was read directly.
+    start_is_direct: bool,
+    /// Set while the files named by `@include` lines are read.
+    including: bool,
+    /// Files being read, to detect files including themselves.
+    reading: Vec<PathBuf>,
+}
+
+impl Source {
+    /// Adds the rules of the file at 'path' and of every file it includes.
+    /// A file that was already read is skipped. See `add` for how the rules
+    /// are combined with the rules read before.
+    pub fn read(&mut self, path: &Path) -> Result<(), String> {
+        let identity = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if self.reading.contains(&identity) {
+            return Err(format!("'{}' includes itself", path.display()));
+        }
+        if self
+            .files
+            .iter()
+            .any(|file| fs::canonicalize(file).unwrap_or_else(|_| file.clone()) == identity)
+        {
+            return Ok(());
+        }
+        self.files.push(path.to_path_buf());
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("can't read '{}': {}", path.display(), err))?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        let name = path.display().to_string();
+        self.reading.push(identity);
+        let result = self.add(directory, &name, &contents);
+        self.reading.pop();
+        result
+    }
+
+    /// Adds the rules read from stdin. Included files are relative to the
+    /// current directory.
+    pub fn read_stdin(&mut self) -> Result<(), String> {
+        let mut contents = String::new();
+        io::stdin()
+            .read_to_string(&mut contents)
+            .map_err(|err| format!("can't read stdin: {}", err))?;
+        self.add(Path::new(""), "stdin", &contents)
+    }
+
+    /// Adds the rules in 'contents', which is called 'name' in errors.
+    /// Rules of a file read directly, rather than included, replace the
+    /// rules with the same LHS from the files read before, so a file can
+    /// override a base grammar. Rules with the same LHS in one file and the
+    /// files it includes are merged.
+    ///
+    /// # Example
+    /// ```
+    /// use story_gen::source::Source;
+    /// use std::path::Path;
+    ///
+    /// let mut source = Source::default();
+    /// source.add(Path::new(""), "base", "<s> = <pro> ran\n<pro> = Mike\n<pro> = Sarah").unwrap();
+    /// source.add(Path::new(""), "overrides", "<pro> = Will").unwrap();
+    /// let rules: Vec<String> = source.rules.iter().map(|rule| rule.right_hand.join(" | ")).collect();
+    /// assert_eq!(rules, vec!["<pro> ran", "Will"]);
+    /// assert_eq!(source.start.as_deref(), Some("<s>"));
+    /// ```
+    pub fn add(&mut self, directory: &Path, name: &str, contents: &str) -> Result<(), String> {
+        let directly = !self.including;
+        let first_new = self.rules.len();
+        for (line_num, line) in contents.lines().enumerate() {
+            if let Some(included) = include_target(line) {
+                let including = std::mem::replace(&mut self.including, true);
+                let result = self.read(&directory.join(included));
+                self.including = including;
+                result?;
+                continue;
+            }
+            if should_ignore_line(line) {
+                continue;
+            }
+            let rule =
+                parse_line(line_num, line).map_err(|err| format!("In '{}': {}", name, err))?;
+            if directly && !self.start_is_direct {
+                self.start = Some(rule.left_hand.clone());
+                self.start_is_direct = true;
+            }
+            self.rules.push(rule);
+        }
+        if directly {
+            let replaced: HashSet<String> = self.rules[first_new..]
+                .iter()
+                .map(|rule| rule.left_hand.clone())
+                .collect();
+            let mut index = 0;
+            self.rules.retain(|rule| {
+                index += 1;
+                index > first_new || !replaced.contains(&rule.left_hand)
+            });
+        }
+        if self.start.is_none() {
+            self.start = self.rules.first().map(|rule| rule.left_hand.clone());
+        }
+        Ok(())
+    }
+}
+
+/// The file named by an `@include` line.
+///
+/// # Example
+/// ```
+/// use story_gen::source::include_target;
+/// assert_eq!(include_target("@include words/nouns.txt"), Some("words/nouns.txt"));
+/// assert_eq!(include_target("<s> = <np> <vp>"), None);
+/// ```
+pub fn include_target(line: &str) -> Option<&str> {
+    let target = line.trim().strip_prefix("@include")?.trim();
+    match target.trim_matches('"') {
+        "" => None,
+        target => Some(target),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// A new directory with the files, named after the test.
+    fn directory(name: &str, files: &[(&str, &str)]) -> TempDir {
+        let directory = TempDir::new(name);
+        for (file, contents) in files {
+            let path = directory.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn test_nested_includes_are_read_once() {
+        let directory = directory(
+            "nested_includes",
+            &[
+                (
+                    "main.txt",
+                    "@include words/all.txt\n<s> = <pro> saw <noun>\n@include words/nouns.txt",
+                ),
+                ("words/all.txt", "@include nouns.txt\n<pro> = Mike"),
+                ("words/nouns.txt", "<noun> = dog | cat"),
+            ],
+        );
+        let mut source = Source::default();
+        source.read(&directory.path().join("main.txt")).unwrap();
+        let keys: Vec<&str> = source
+            .rules
+            .iter()
+            .map(|rule| rule.left_hand.as_str())
+            .collect();
+        assert_eq!(keys, vec!["<noun>", "<pro>", "<s>"]);
+        assert_eq!(source.start.as_deref(), Some("<s>"));
+        assert_eq!(source.files.len(), 3);
+    }
+    #[test]
+    fn test_include_errors() {
+        let directory = directory(
+            "include_errors",
+            &[
+                ("a.txt", "<s> = <b>\n@include b.txt"),
+                ("b.txt", "@include a.txt\n<b> = dog"),
+                ("bad.txt", "<s> = dog\n@include missing.txt"),
+                ("typo.txt", "<s> dog"),
+            ],
+        );
+        let mut source = Source::default();
+        let err = source.read(&directory.path().join("a.txt")).unwrap_err();
+        assert!(err.ends_with("a.txt' includes itself"), "{}", err);
+        let mut source = Source::default();
+        let err = source.read(&directory.path().join("bad.txt")).unwrap_err();
+        assert!(err.starts_with("can't read '"), "{}", err);
+        // the missing file is still watched
+        assert_eq!(source.files.len(), 2);
+        let err = Source::default()
+            .read(&directory.path().join("typo.txt"))
+            .unwrap_err();
+        assert!(err.contains("typo.txt': Problem parsing line 0"), "{}", err);
+    }
+}
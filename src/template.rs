@@ -0,0 +1,313 @@
+//! This module contains parameterized rules (templates), which are rules
+//! whose left hand side takes parameters, e.g. `<list(X)> = X | X , <list(X)>`.
+//! Every use of a template, such as `<list(<noun>)>`, is instantiated into
+//! a regular rule when the grammar is loaded.
+use crate::features::FeaturedRule;
+use crate::token;
+use std::collections::HashMap;
+
+/// Templates whose instantiations nest deeper than this are assumed to
+/// instantiate forever, e.g. `<f(X)> = <f(<g(X)>)>`.
+const MAX_NESTING: usize = 8;
+/// Upper bound on the number of rules created from templates.
+const MAX_INSTANTIATIONS: usize = 10_000;
+
+/// A rule which takes parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Template {
+    head: String,
+    params: Vec<String>,
+    options: Vec<String>,
+}
+
+/// Splits a template head or use into its name and arguments. The name is
+/// the token with the parenthesized arguments removed, so `<list(<noun>)>`
+/// has the name `<list>` and the single argument `<noun>`. Returns None if
+/// the token does not have arguments.
+///
+/// # Example
+/// ```
+/// use story_gen::template::parse_call;
+/// assert_eq!(
+///     parse_call("<pair(<noun>,<list(a)>)>"),
+///     Some((String::from("<pair>"), vec!["<noun>", "<list(a)>"]))
+/// );
+/// assert_eq!(parse_call("<noun>"), None);
+/// ```
+pub fn parse_call(token: &str) -> Option<(String, Vec<&str>)> {
+    let open = token.find('(')?;
+    if open == 0 {
+        return None;
+    }
+    let mut depth = 0;
+    let mut close = None;
+    let mut args = vec![];
+    let mut arg_start = open + 1;
+    for (i, c) in token.char_indices().skip_while(|(i, _)| *i < open) {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    args.push(&token[arg_start..i]);
+                    close = Some(i);
+                    break;
+                }
+            }
+            ',' if depth == 1 => {
+                args.push(&token[arg_start..i]);
+                arg_start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    let close = close?;
+    let suffix = &token[close + 1..];
+    if suffix.contains(['(', ')']) || args.iter().any(|arg| arg.is_empty()) {
+        return None;
+    }
+    Some((format!("{}{}", &token[..open], suffix), args))
+}
+
+/// Whether the character can be part of a parameter name.
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Replaces every whole word occurrence of the parameters in 'token'.
+fn substitute(token: &str, params: &[String], args: &[&str]) -> String {
+    let mut substituted = String::with_capacity(token.len());
+    let mut rest = token;
+    while !rest.is_empty() {
+        let word_len = rest
+            .char_indices()
+            .find(|(_, c)| !is_name_char(*c))
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        if word_len == 0 {
+            let c = rest.chars().next().unwrap_or_default();
+            substituted.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let word = &rest[..word_len];
+        match params.iter().position(|param| param == word) {
+            Some(i) => substituted.push_str(args[i]),
+            None => substituted.push_str(word),
+        }
+        rest = &rest[word_len..];
+    }
+    substituted
+}
+
+/// How deeply template uses are nested inside the token.
+fn nesting(token: &str) -> usize {
+    let mut depth: usize = 0;
+    let mut deepest = 0;
+    for c in token.chars() {
+        match c {
+            '(' => {
+                depth += 1;
+                deepest = deepest.max(depth);
+            }
+            ')' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    deepest
+}
+
+/// Removes every template from 'rules' and adds a regular rule for every
+/// template use that is found, directly or through other templates, in the
+/// remaining rules and in 'featured_rules'. Errors when a template is used with the wrong number of
+/// arguments, is defined twice with different parameters, or would be
+/// instantiated forever.
+pub fn instantiate(
+    rules: &mut HashMap<String, Vec<String>>,
+    featured_rules: &HashMap<String, Vec<FeaturedRule>>,
+) -> Result<(), String> {
+    let mut templates: HashMap<String, Template> = HashMap::new();
+    let mut errors: Vec<String> = vec![];
+    let heads: Vec<String> = rules
+        .keys()
+        .filter(|key| is_template_head(key))
+        .cloned()
+        .collect();
+    for head in heads {
+        let options = rules.remove(&head).unwrap_or_default();
+        let (name, params) = match parse_call(&head) {
+            Some(call) => call,
+            None => continue,
+        };
+        let params: Vec<String> = params.into_iter().map(String::from).collect();
+        if let Some(existing) = templates.get(&name) {
+            errors.push(format!(
+                "template '{}' is defined as both '{}' and '{}'",
+                name, existing.head, head
+            ));
+            continue;
+        }
+        templates.insert(
+            name,
+            Template {
+                head,
+                params,
+                options,
+            },
+        );
+    }
+    if templates.is_empty() {
+        return Ok(());
+    }
+    let mut pending: Vec<String> = rules
+        .values()
+        .chain(featured_rules.values().flatten().map(|rule| &rule.options))
+        .flatten()
+        .flat_map(|option| option.split_whitespace())
+        .filter_map(token::referenced_symbol)
+        .map(|symbol| symbol.into_owned())
+        .collect();
+    let mut instantiated = 0;
+    while let Some(symbol) = pending.pop() {
+        if rules.contains_key(&symbol) {
+            continue;
+        }
+        let (name, args) = match parse_call(&symbol) {
+            Some(call) => call,
+            None => continue,
+        };
+        let template = match templates.get(&name) {
+            Some(template) => template,
+            None => continue,
+        };
+        if template.params.len() != args.len() {
+            errors.push(format!(
+                "'{}' has {} arguments but '{}' takes {}",
+                symbol,
+                args.len(),
+                template.head,
+                template.params.len()
+            ));
+            continue;
+        }
+        instantiated += 1;
+        if nesting(&symbol) > MAX_NESTING || instantiated > MAX_INSTANTIATIONS {
+            errors.push(format!(
+                "template '{}' is instantiated forever, last use was '{}'",
+                template.head, symbol
+            ));
+            break;
+        }
+        let options: Vec<String> = template
+            .options
+            .iter()
+            .map(|option| {
+                option
+                    .split_whitespace()
+                    .map(|sub_option| substitute(sub_option, &template.params, &args))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            })
+            .collect();
+        pending.extend(
+            options
+                .iter()
+                .flat_map(|option| option.split_whitespace())
+                .filter_map(token::referenced_symbol)
+                .map(|symbol| symbol.into_owned()),
+        );
+        rules.insert(symbol, options);
+    }
+    errors.sort();
+    match errors.len() {
+        0 => Ok(()),
+        _ => Err(errors.join("; ")),
+    }
+}
+
+/// A template head has arguments which are all plain parameter names.
+fn is_template_head(key: &str) -> bool {
+    match parse_call(key) {
+        Some((_, params)) => params.iter().all(|param| param.chars().all(is_name_char)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::rules;
+
+    #[test]
+    fn test_substitute() {
+        let params = vec![String::from("X")];
+        assert_eq!(substitute("<list(X)>", &params, &["<a>"]), "<list(<a>)>");
+        assert_eq!(substitute("[v:X]", &params, &["<a>"]), "[v:<a>]");
+        assert_eq!(substitute("X-ray", &params, &["<a>"]), "X-ray");
+    }
+    #[test]
+    fn test_instantiate_recursive_template() {
+        let mut grammar = rules(&[
+            ("<sentence>", "I saw <list(<noun>)>"),
+            ("<list(X)>", "X | X and <list(X)>"),
+            ("<noun>", "dogs | cats"),
+        ]);
+        instantiate(&mut grammar, &HashMap::new()).unwrap();
+        assert!(!grammar.contains_key("<list(X)>"));
+        assert_eq!(
+            grammar["<list(<noun>)>"],
+            vec!["<noun>", "<noun> and <list(<noun>)>"]
+        );
+    }
+    #[test]
+    fn test_instantiate_uses_in_featured_rules() {
+        let mut grammar = rules(&[("<list(X)>", "X | X and <list(X)>"), ("<noun>", "dogs")]);
+        let mut featured_rules = HashMap::new();
+        featured_rules.insert(
+            String::from("<np>"),
+            vec![FeaturedRule {
+                head: String::from("<np[num=pl]>"),
+                features: crate::features::parse_featured("<np[num=pl]>")
+                    .unwrap()
+                    .features,
+                options: vec![String::from("<list(<noun>)>")],
+            }],
+        );
+        instantiate(&mut grammar, &featured_rules).unwrap();
+        assert_eq!(
+            grammar["<list(<noun>)>"],
+            vec!["<noun>", "<noun> and <list(<noun>)>"]
+        );
+    }
+    #[test]
+    fn test_instantiate_nested_arguments() {
+        let mut grammar = rules(&[
+            ("<sentence>", "<pair(<list(a)>,b)>"),
+            ("<pair(X,Y)>", "X Y"),
+            ("<list(X)>", "X"),
+        ]);
+        instantiate(&mut grammar, &HashMap::new()).unwrap();
+        assert_eq!(grammar["<pair(<list(a)>,b)>"], vec!["<list(a)> b"]);
+        assert_eq!(grammar["<list(a)>"], vec!["a"]);
+    }
+    #[test]
+    fn test_instantiate_arity_mismatch() {
+        let mut grammar = rules(&[("<sentence>", "<pair(a)>"), ("<pair(X,Y)>", "X Y")]);
+        let error = instantiate(&mut grammar, &HashMap::new()).unwrap_err();
+        assert_eq!(
+            error,
+            "'<pair(a)>' has 1 arguments but '<pair(X,Y)>' takes 2"
+        );
+    }
+    #[test]
+    fn test_instantiate_forever() {
+        let mut grammar = rules(&[("<sentence>", "<f(a)>"), ("<f(X)>", "X | <f(<g(X)>)>")]);
+        let error = instantiate(&mut grammar, &HashMap::new()).unwrap_err();
+        assert!(error.starts_with("template '<f(X)>' is instantiated forever"));
+    }
+    #[test]
+    fn test_conflicting_definitions() {
+        let mut grammar = rules(&[("<s>", "<f(a)>"), ("<f(X)>", "X"), ("<f(X,Y)>", "X Y")]);
+        assert!(instantiate(&mut grammar, &HashMap::new()).is_err());
+    }
+}
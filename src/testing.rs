@@ -0,0 +1,42 @@
+//! This module contains helpers shared by the unit tests of several
+//! modules.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+/// Rules from (LHS, RHS) pairs, with the options of the RHS separated by '|'.
+pub fn rules(lines: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+    lines
+        .iter()
+        .map(|(key, value)| {
+            (
+                key.to_string(),
+                value.split('|').map(|x| String::from(x.trim())).collect(),
+            )
+        })
+        .collect()
+}
+
+/// A new directory in the system's temporary directory, removed when it is
+/// dropped. The name includes the process id, so concurrent test runs don't
+/// share it.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("story_gen_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,182 @@
+//! This module classifies the space delimited tokens of a right-hand-side
+//! option into the special syntax understood by the generator.
+use crate::features;
+use std::borrow::Cow;
+
+/// A single component of a right-hand-side option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    /// A non-terminal when a rule exists for it, otherwise a terminal.
+    Symbol(&'a str),
+    /// `[name:value]` expands 'value' and remembers the result as 'name'.
+    /// If 'name' is already bound, the remembered value is reused instead.
+    Bind { name: &'a str, value: &'a str },
+    /// `$name` repeats the value bound to 'name'.
+    Variable(&'a str),
+    /// A non-terminal with features, e.g. `<np[num=$n]>`. See the features
+    /// module.
+    Featured(&'a str),
+    /// A token followed by modifiers, e.g. `<noun>.plural.capitalize`.
+    /// 'modifiers' are the dot separated names without the leading dot.
+    /// See the modifiers module.
+    Modified { inner: &'a str, modifiers: &'a str },
+    /// A standalone `~` joins the expansions on either side without a space,
+    /// e.g. `<prefix> ~ <suffix>`.
+    Join,
+}
+
+/// Classify a single token of an option.
+///
+/// # Example
+/// ```
+/// use story_gen::token::{classify, Token};
+/// assert_eq!(classify("<pro>"), Token::Symbol("<pro>"));
+/// assert_eq!(classify("[hero:<pro>]"), Token::Bind { name: "hero", value: "<pro>" });
+/// assert_eq!(classify("$hero"), Token::Variable("hero"));
+/// assert_eq!(
+///     classify("<noun>.plural"),
+///     Token::Modified { inner: "<noun>", modifiers: "plural" }
+/// );
+/// ```
+pub fn classify(token: &str) -> Token<'_> {
+    if token == "~" {
+        return Token::Join;
+    }
+    if let Some(start) = modifiers_start(token) {
+        return Token::Modified {
+            inner: &token[..start],
+            modifiers: &token[start + 1..],
+        };
+    }
+    if token.len() > 1 && token.starts_with('$') {
+        return Token::Variable(&token[1..]);
+    }
+    if token.starts_with('[') && token.ends_with(']') {
+        let inner = &token[1..token.len() - 1];
+        if let Some((name, value)) = inner.split_once(':') {
+            if !name.is_empty() && !value.is_empty() {
+                return Token::Bind { name, value };
+            }
+        }
+    }
+    if features::parse_featured(token).is_some() {
+        return Token::Featured(token);
+    }
+    Token::Symbol(token)
+}
+
+/// Whether the character can be part of a modifier name.
+fn is_modifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Finds the '.' that starts a chain of modifiers at the end of the token.
+/// Every modifier must start with a letter, so "etc." and "3.14" are not
+/// modified.
+fn modifiers_start(token: &str) -> Option<usize> {
+    let mut start = None;
+    let mut rest = token;
+    while let Some(dot) = rest.rfind('.') {
+        let name = &rest[dot + 1..];
+        if dot == 0
+            || !name.starts_with(|c: char| c.is_ascii_alphabetic())
+            || !name.chars().all(is_modifier_char)
+        {
+            break;
+        }
+        start = Some(dot);
+        rest = &rest[..dot];
+    }
+    start
+}
+
+/// The token without any modifiers, e.g. `[hero:<pro>]` for
+/// `[hero:<pro>].capitalize`.
+pub fn unmodified(token: &str) -> &str {
+    match modifiers_start(token) {
+        Some(start) => &token[..start],
+        None => token,
+    }
+}
+
+/// The symbol a token expands, if any. Used by the validation traversals
+/// so symbols that only appear inside a binding, or with features, are
+/// still visited.
+pub fn referenced_symbol(token: &str) -> Option<Cow<'_, str>> {
+    match classify(token) {
+        Token::Symbol(symbol) => Some(Cow::Borrowed(symbol)),
+        Token::Bind { value, .. } => referenced_symbol(value),
+        Token::Variable(_) | Token::Join => None,
+        Token::Featured(featured) => {
+            features::parse_featured(featured).map(|parsed| Cow::Owned(parsed.symbol))
+        }
+        Token::Modified { inner, .. } => referenced_symbol(inner),
+    }
+}
+
+/// Whether 'name' is read as a single plain symbol, without special syntax
+/// such as `$name`, `[name:value]`, features or modifiers.
+pub fn is_plain_symbol(name: &str) -> bool {
+    !name.is_empty()
+        && !name.contains(char::is_whitespace)
+        && !name.contains('|')
+        && classify(name) == Token::Symbol(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_plain() {
+        assert_eq!(classify("cat"), Token::Symbol("cat"));
+        assert_eq!(classify("$"), Token::Symbol("$"));
+        assert_eq!(classify("[note]"), Token::Symbol("[note]"));
+        assert_eq!(classify("[:x]"), Token::Symbol("[:x]"));
+        assert_eq!(classify("~"), Token::Join);
+        assert_eq!(classify("~s"), Token::Symbol("~s"));
+        assert_eq!(referenced_symbol("~"), None);
+    }
+    #[test]
+    fn test_classify_variables() {
+        assert_eq!(
+            classify("[villain:bob]"),
+            Token::Bind {
+                name: "villain",
+                value: "bob"
+            }
+        );
+        assert_eq!(classify("$villain"), Token::Variable("villain"));
+        assert_eq!(
+            referenced_symbol("[villain:<pro>]"),
+            Some(Cow::Borrowed("<pro>"))
+        );
+        assert_eq!(referenced_symbol("$villain"), None);
+    }
+    #[test]
+    fn test_classify_featured() {
+        assert_eq!(classify("<np[num=pl]>"), Token::Featured("<np[num=pl]>"));
+        assert_eq!(
+            referenced_symbol("[hero:<np[num=$n]>]"),
+            Some(Cow::Owned(String::from("<np>")))
+        );
+    }
+    #[test]
+    fn test_classify_modified() {
+        assert_eq!(
+            classify("[hero:<pro>].capitalize.upper"),
+            Token::Modified {
+                inner: "[hero:<pro>]",
+                modifiers: "capitalize.upper"
+            }
+        );
+        assert_eq!(classify("etc."), Token::Symbol("etc."));
+        assert_eq!(classify("3.14"), Token::Symbol("3.14"));
+        assert_eq!(classify(".plural"), Token::Symbol(".plural"));
+        assert_eq!(unmodified("$hero.upper"), "$hero");
+        assert_eq!(
+            referenced_symbol("<np[num=pl]>.plural"),
+            Some(Cow::Owned(String::from("<np>")))
+        );
+    }
+}
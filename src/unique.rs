@@ -0,0 +1,87 @@
+//! This module contains the filter used to generate distinct sentences.
+use std::collections::HashSet;
+
+/// Number of duplicate sentences in a row after which the grammar is
+/// assumed to have no new sentences left.
+pub const MAX_UNIQUE_ATTEMPTS: usize = 1_000;
+
+/// Remembers the generated sentences to reject duplicates. It is exhausted
+/// once 'limit' distinct sentences were accepted, or after
+/// MAX_UNIQUE_ATTEMPTS duplicates in a row.
+///
+/// # Example
+/// ```
+/// use story_gen::unique::UniqueFilter;
+///
+/// let mut filter = UniqueFilter::new(Some(2));
+/// assert!(filter.accept("A dog."));
+/// assert!(!filter.accept("A dog."));
+/// assert!(!filter.is_exhausted());
+/// assert!(filter.accept("A cat."));
+/// assert!(filter.is_exhausted());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct UniqueFilter {
+    seen: HashSet<String>,
+    limit: Option<u64>,
+    duplicates: usize,
+}
+
+impl UniqueFilter {
+    /// Create a filter for a language with at most 'limit' sentences, if known.
+    pub fn new(limit: Option<u64>) -> Self {
+        UniqueFilter {
+            seen: HashSet::new(),
+            limit,
+            duplicates: 0,
+        }
+    }
+
+    /// Whether the sentence was not generated before. Accepted sentences are
+    /// remembered.
+    pub fn accept(&mut self, sentence: &str) -> bool {
+        if self.seen.contains(sentence) {
+            self.duplicates += 1;
+            return false;
+        }
+        self.duplicates = 0;
+        self.seen.insert(String::from(sentence));
+        true
+    }
+
+    /// Whether no further distinct sentence is expected.
+    pub fn is_exhausted(&self) -> bool {
+        let complete = match self.limit {
+            Some(limit) => self.seen.len() as u64 >= limit,
+            None => false,
+        };
+        complete || self.duplicates >= MAX_UNIQUE_ATTEMPTS
+    }
+
+    /// Number of distinct sentences accepted.
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exhausted_after_duplicates() {
+        let mut filter = UniqueFilter::new(None);
+        assert!(filter.accept("a"));
+        for _ in 0..MAX_UNIQUE_ATTEMPTS - 1 {
+            assert!(!filter.accept("a"));
+        }
+        assert!(!filter.is_exhausted());
+        filter.accept("a");
+        assert!(filter.is_exhausted());
+        assert_eq!(filter.len(), 1);
+    }
+}